@@ -0,0 +1,14 @@
+//! Feeds arbitrary bytes to the partition table and filesystem parsers as a disk image.
+//!
+//! Seed it with the malformed samples used by the tests:
+//!
+//!     cargo fuzz run parse_image ../tests/corpus
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../tests/common/mod.rs"]
+mod common;
+
+fuzz_target!(|data: &[u8]| common::exercise(data));
@@ -0,0 +1,104 @@
+use std::{fs::OpenOptions, path::PathBuf};
+
+use humansize::BINARY;
+
+use crate::image::{
+    BlockDevice, FileDevice, Image, ImageError, ImageFormat, Qcow2Device, WritableBlockDevice,
+};
+
+use super::Action;
+
+/// Amount of the image copied at once. Chunks that are all zeros are skipped, and it matches
+/// the cluster size of new qcow2 images so that each chunk can be compressed as a cluster.
+const CONVERT_CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct ConvertArgs {
+    pub path: PathBuf,
+    pub format: ImageFormat,
+    /// Compress the clusters of a qcow2 image.
+    pub compress: bool,
+    pub overwrite: bool,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ConvertError {
+    /// The output file already exists, and force overwrite was not specified
+    FileAlreadyExistsError,
+    /// Unable to create the output file
+    CreateError,
+    /// Only qcow2 images can be compressed
+    CompressionUnsupported,
+    /// The output file is the image being converted
+    SameFile,
+    /// {0}
+    ImageError(#[from] ImageError),
+}
+
+pub struct ConvertAction {}
+
+/// Copies the contents of `src` to `dst`, which must be at least as large and read as zeros,
+/// leaving out the chunks that are all zeros. Returns the number of bytes copied.
+fn copy_contents(
+    src: &Image<impl BlockDevice>,
+    dst: &mut Image<impl WritableBlockDevice>,
+) -> Result<u64, ImageError> {
+    let mut copied = 0;
+
+    for offset in (0..src.len()).step_by(CONVERT_CHUNK_SIZE) {
+        let len = (CONVERT_CHUNK_SIZE as u64).min(src.len() - offset);
+        let data = src.get_bytes(offset, len as usize)?;
+
+        if data.iter().any(|&b| b != 0) {
+            dst.write_bytes(offset, &data)?;
+            copied += len;
+        }
+    }
+
+    Ok(copied)
+}
+
+impl<D: BlockDevice> Action<D, ConvertArgs, ConvertError> for ConvertAction {
+    fn invoke(image: &mut Image<D>, args: ConvertArgs) -> Result<(), ConvertError> {
+        if args.compress && args.format != ImageFormat::Qcow2 {
+            return Err(ConvertError::CompressionUnsupported);
+        }
+        // Opening the output would truncate the image before it is read.
+        if image.is_stored_at(&args.path) {
+            return Err(ConvertError::SameFile);
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(args.overwrite)
+            .create_new(!args.overwrite)
+            .truncate(true)
+            .open(&args.path)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => ConvertError::FileAlreadyExistsError,
+                _ => ConvertError::CreateError,
+            })?;
+
+        let copied = match args.format {
+            ImageFormat::Raw => {
+                file.set_len(image.len())
+                    .map_err(|_| ConvertError::CreateError)?;
+                copy_contents(image, &mut Image::new(FileDevice::from_file(file)?))?
+            }
+            ImageFormat::Qcow2 => {
+                let mut device = Qcow2Device::create(file, image.len())?;
+                device.set_compression(args.compress);
+                copy_contents(image, &mut Image::new(device))?
+            }
+        };
+
+        println!(
+            "Converted {} image to {}, {} of data",
+            humansize::format_size(image.len(), BINARY),
+            args.path.display(),
+            humansize::format_size(copied, BINARY)
+        );
+
+        Ok(())
+    }
+}
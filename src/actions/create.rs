@@ -1,17 +1,42 @@
-use std::path::Path;
+use std::{fs::OpenOptions, os::unix::io::RawFd, path::Path};
 
 use nix::{
+    errno::Errno,
     fcntl::{FallocateFlags, OFlag},
-    sys::stat::Mode,
+    sys::{stat::Mode, uio::pwrite},
 };
 
-use crate::pt::{gpt::GPT, PartitionTableType};
-use crate::{image::Image, pt::mbr::MBR};
+use crate::pt::{
+    gpt::{Layout, GPT, MIN_ENTRIES},
+    ids::IdSource,
+    PartitionTableType,
+};
+use crate::{
+    image::{Image, ImageFormat, Qcow2Device, WritableBlockDevice},
+    pt::mbr::MBR,
+};
+
+const BLOCK_SIZE: u64 = 512;
+
+/// How the space for a new image is allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// Set the size without allocating anything, so that blocks are only allocated once written.
+    Sparse,
+    /// Reserve all blocks with `fallocate`, writing zeros if the filesystem does not support it.
+    Full,
+    /// Write zeros over the whole image.
+    Zero,
+}
 
 pub struct CreateActionArgs {
     pub size: i64,
+    pub format: ImageFormat,
+    pub allocation: Allocation,
     pub overwrite: bool,
     pub initial_pt_type: Option<PartitionTableType>,
+    pub ids: IdSource,
+    pub gpt_layout: Layout,
 }
 
 /// Error during creation of disk image.
@@ -23,8 +48,47 @@ pub enum CreateError {
     MapError,
     /// Unable to allocate space for output file.
     AllocationFailedError,
+    /// Unable to write to output file.
+    WriteError,
     /// The image file already exists, and force overwrite was not specified.
     FileAlreadyExistsError,
+    /// The GPT entry array does not fit in the image, or has fewer than 128 entries.
+    InvalidLayoutError,
+    /// Only sparse allocation is supported for qcow2 images.
+    UnsupportedAllocationError,
+}
+
+fn write_zeros(fd: RawFd, size: i64) -> Result<(), CreateError> {
+    let zeros = vec![0; 1 << 20];
+    let mut offset = 0;
+
+    while offset < size {
+        let len = (size - offset).min(zeros.len() as i64) as usize;
+        match pwrite(fd, &zeros[..len], offset) {
+            Err(Errno::EINTR) => {}
+            Ok(0) | Err(_) => return Err(CreateError::AllocationFailedError),
+            Ok(n) => offset += n as i64,
+        }
+    }
+
+    Ok(())
+}
+
+fn allocate(fd: RawFd, size: i64, allocation: Allocation) -> Result<(), CreateError> {
+    match allocation {
+        Allocation::Sparse => {
+            nix::unistd::ftruncate(fd, size).map_err(|_| CreateError::AllocationFailedError)
+        }
+        Allocation::Full => match nix::fcntl::fallocate(fd, FallocateFlags::empty(), 0, size) {
+            Ok(()) => Ok(()),
+            Err(Errno::EOPNOTSUPP) => {
+                println!("Warning: fallocate is not supported here, writing zeros instead");
+                write_zeros(fd, size)
+            }
+            Err(_) => Err(CreateError::AllocationFailedError),
+        },
+        Allocation::Zero => write_zeros(fd, size),
+    }
 }
 
 pub fn invoke(image_file: &String, ca: CreateActionArgs) -> Result<(), CreateError> {
@@ -41,38 +105,70 @@ pub fn invoke(image_file: &String, ca: CreateActionArgs) -> Result<(), CreateErr
         println!("Warning: overwrite was specified, but the image file does not already exist!");
     }
 
-    println!("Creating a disk image of size {}", ca.size);
-
-    // We need to use the *nix APIs to create a sparse file.
-    let fd = nix::fcntl::open(
-        p,
-        OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_RDWR,
-        Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IROTH,
-    )
-    .map_err(|_| CreateError::OpenError)?;
+    // Clusters of a qcow2 image are only allocated once written.
+    if ca.format == ImageFormat::Qcow2 && ca.allocation != Allocation::Sparse {
+        return Err(CreateError::UnsupportedAllocationError);
+    }
+    if matches!(ca.initial_pt_type, Some(PartitionTableType::GPT))
+        && ca.gpt_layout.nr_entries < MIN_ENTRIES
+    {
+        return Err(CreateError::InvalidLayoutError);
+    }
 
-    nix::fcntl::fallocate(fd, FallocateFlags::empty(), 0, ca.size)
-        .map_err(|_| CreateError::AllocationFailedError)?;
+    println!("Creating a disk image of size {}", ca.size);
 
-    // A bit hacky, but this will clear any existing MBR.
-    nix::unistd::write(fd, &[0; 512]);
+    match ca.format {
+        ImageFormat::Raw => {
+            // We need to use the *nix APIs to create a sparse file.
+            let fd = nix::fcntl::open(
+                p,
+                OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_RDWR,
+                Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IROTH,
+            )
+            .map_err(|_| CreateError::OpenError)?;
 
-    nix::unistd::close(fd).unwrap();
+            let allocated = allocate(fd, ca.size, ca.allocation);
+            nix::unistd::close(fd).unwrap();
+            allocated?;
 
-    // OK - and now, see if we're also creating an initial partition table.
+            let mut image = Image::open(p).map_err(|_| CreateError::OpenError)?;
+            write_initial_table(&mut image, &ca)
+        }
+        ImageFormat::Qcow2 => {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(p)
+                .map_err(|_| CreateError::OpenError)?;
+            let device =
+                Qcow2Device::create(file, ca.size as u64).map_err(|_| CreateError::WriteError)?;
 
-    let mut image = Image::open(p).map_err(|_| CreateError::OpenError)?;
+            write_initial_table(&mut Image::new(device), &ca)
+        }
+    }
+}
 
+/// Writes the partition table, if any, that a new image was asked to start with.
+fn write_initial_table(
+    image: &mut Image<impl WritableBlockDevice>,
+    ca: &CreateActionArgs,
+) -> Result<(), CreateError> {
     match ca.initial_pt_type {
         None => Ok(()),
         Some(PartitionTableType::MBR) => {
-            let mbr = MBR::new();
-            mbr.write(&mut image);
+            let mbr = MBR::with_ids(&ca.ids);
+            mbr.write(image).map_err(|_| CreateError::WriteError)?;
             Ok(())
         }
         Some(PartitionTableType::GPT) => {
-            let gpt = GPT::new();
-            gpt.write(&mut image);
+            let gpt = GPT::with_layout(&ca.ids, ca.gpt_layout);
+            if !gpt.fits(image.len() / BLOCK_SIZE) {
+                return Err(CreateError::InvalidLayoutError);
+            }
+
+            gpt.write(image).map_err(|_| CreateError::WriteError)?;
             Ok(())
         }
     }
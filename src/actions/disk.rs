@@ -0,0 +1,41 @@
+use nuuid::Uuid;
+
+use crate::{
+    image::{Image, ImageError, WritableBlockDevice},
+    pt::{read_partition_table, PartitionTable},
+};
+
+use super::Action;
+
+pub struct SetDiskArgs {
+    pub uuid: Option<String>,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum SetDiskError {
+    /// No GPT partition table found
+    NoGPT,
+    /// Invalid GUID `{0}`
+    InvalidGuid(String),
+    /// Unable to write GPT: {0}
+    ImageError(#[from] ImageError),
+}
+
+pub struct SetDiskAction {}
+
+impl<D: WritableBlockDevice> Action<D, SetDiskArgs, SetDiskError> for SetDiskAction {
+    fn invoke(image: &mut Image<D>, args: SetDiskArgs) -> Result<(), SetDiskError> {
+        let mut gpt = match read_partition_table(image) {
+            Some(PartitionTable::GPT(gpt)) => gpt,
+            _ => return Err(SetDiskError::NoGPT),
+        };
+
+        if let Some(uuid) = args.uuid {
+            gpt.set_disk_guid(Uuid::parse(&uuid).map_err(|_| SetDiskError::InvalidGuid(uuid))?);
+        }
+
+        gpt.write_tables(image)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use humansize::BINARY;
+
+use crate::{
+    image::{
+        vhd::{self, DynamicHeader, Footer, VhdType},
+        BlockDevice, Image, ImageError,
+    },
+    pt::{
+        backup::{BackupError, TableBackup},
+        ids::IdSource,
+        raw::GPT_SIGNATURE,
+        read_partition_table, PartitionTable,
+    },
+};
+
+use super::Action;
+
+/// Amount of the image copied at once. Chunks that are all zeros are left as holes in the
+/// output.
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+const BLOCK_SIZE: usize = 512;
+
+pub struct ExportArgs {
+    pub path: PathBuf,
+    pub format: VhdType,
+    pub overwrite: bool,
+    /// Source of the UUID of the VHD.
+    pub ids: IdSource,
+    /// Creation time recorded in the footer.
+    pub created: SystemTime,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ExportError {
+    /// The output file already exists, and force overwrite was not specified
+    FileAlreadyExistsError,
+    /// Unable to create the output file
+    CreateError,
+    /// The image is {0} bytes, but a VHD can hold at most 2040 GiB
+    TooLarge(u64),
+    /// The output file is the image being exported
+    SameFile,
+    /// Unable to write the output file: {0}
+    WriteError(#[from] io::Error),
+    /// {0}
+    ImageError(#[from] ImageError),
+}
+
+pub struct ExportAction {}
+
+/// The disk as exported: the image padded with zeros to the size of the VHD, with some blocks
+/// replaced, such as the partition tables moved to fit the new size.
+struct ExportedDisk<'a, D: BlockDevice> {
+    image: &'a Image<D>,
+    size: u64,
+    /// Replacement contents, as `(block, data)`.
+    patches: Vec<(u64, Vec<u8>)>,
+}
+
+impl<D: BlockDevice> ExportedDisk<'_, D> {
+    fn read(&self, offset: u64, len: usize) -> Result<Vec<u8>, ImageError> {
+        let end = offset + len as u64;
+        let mut data = vec![0; len];
+
+        if offset < self.image.len() {
+            let n = (len as u64).min(self.image.len() - offset) as usize;
+            data[..n].copy_from_slice(&self.image.get_bytes(offset, n)?);
+        }
+
+        for (block, patch) in &self.patches {
+            let start = block * BLOCK_SIZE as u64;
+            let patch_end = start + patch.len() as u64;
+            if start < end && offset < patch_end {
+                let from = start.max(offset);
+                let to = patch_end.min(end);
+                data[(from - offset) as usize..(to - offset) as usize]
+                    .copy_from_slice(&patch[(from - start) as usize..(to - start) as usize]);
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+/// Returns the blocks that move the backup GPT of `image` to the end of a disk of `size`
+/// bytes, and clear the backup header left behind at the old end.
+fn relocated_gpt(
+    image: &Image<impl BlockDevice>,
+    size: u64,
+) -> Result<Vec<(u64, Vec<u8>)>, BackupError> {
+    let mut backup = TableBackup::read(image)?;
+    backup.relocate(size / BLOCK_SIZE as u64)?;
+
+    let mut patches = Vec::new();
+    let last_block = image.len() / BLOCK_SIZE as u64 - 1;
+    if image
+        .get_blocks(last_block, 1)
+        .is_ok_and(|b| b[..8] == GPT_SIGNATURE)
+    {
+        patches.push((last_block, vec![0; BLOCK_SIZE]));
+    }
+    patches.extend(backup.blocks());
+
+    Ok(patches)
+}
+
+/// Calls `write` with the offset within the disk of each chunk of `len` bytes at `offset` that
+/// is not all zeros, and the chunk itself. Returns the number of bytes passed to `write`.
+fn for_each_data_chunk(
+    disk: &ExportedDisk<'_, impl BlockDevice>,
+    offset: u64,
+    len: u64,
+    mut write: impl FnMut(u64, &[u8]) -> io::Result<()>,
+) -> Result<u64, ExportError> {
+    let mut written = 0;
+
+    for chunk_offset in (offset..offset + len).step_by(EXPORT_CHUNK_SIZE) {
+        let n = (EXPORT_CHUNK_SIZE as u64).min(offset + len - chunk_offset);
+        let data = disk.read(chunk_offset, n as usize)?;
+
+        if data.iter().any(|&b| b != 0) {
+            write(chunk_offset, &data)?;
+            written += n;
+        }
+    }
+
+    Ok(written)
+}
+
+/// Writes the contents of the disk followed by the footer.
+fn write_fixed(
+    disk: &ExportedDisk<'_, impl BlockDevice>,
+    file: &File,
+    footer: &Footer,
+) -> Result<u64, ExportError> {
+    file.set_len(footer.size + vhd::FOOTER_SIZE as u64)?;
+
+    let written = for_each_data_chunk(disk, 0, disk.size, |offset, data| {
+        file.write_all_at(data, offset)
+    })?;
+    file.write_all_at(&footer.encode(), footer.size)?;
+
+    Ok(written)
+}
+
+/// Writes a copy of the footer, the dynamic header and the BAT, followed by the blocks of the
+/// disk that are not all zeros and finally the footer.
+fn write_dynamic(
+    disk: &ExportedDisk<'_, impl BlockDevice>,
+    file: &File,
+    footer: &Footer,
+) -> Result<u64, ExportError> {
+    let block_size = vhd::DYNAMIC_BLOCK_SIZE;
+    let header = DynamicHeader {
+        table_offset: (vhd::FOOTER_SIZE + vhd::DYNAMIC_HEADER_SIZE) as u64,
+        max_table_entries: footer.size.div_ceil(vhd::DYNAMIC_BLOCK_SIZE) as u32,
+    };
+
+    let mut table = vec![vhd::UNALLOCATED; header.max_table_entries as usize];
+    let mut end = header.table_offset + vhd::table_size(header.max_table_entries);
+    let mut written = 0;
+
+    // Every sector of a stored block is marked as holding data, the parts that were left out
+    // because they are zeros read as zeros from the holes in the file.
+    let bitmap = vec![0xff; vhd::BLOCK_BITMAP_SIZE as usize];
+
+    for (index, entry) in table.iter_mut().enumerate() {
+        let block_offset = index as u64 * block_size;
+        let len = block_size.min(disk.size.saturating_sub(block_offset));
+        let data_offset = end + vhd::BLOCK_BITMAP_SIZE;
+
+        let n = for_each_data_chunk(disk, block_offset, len, |offset, data| {
+            file.write_all_at(data, data_offset + offset - block_offset)
+        })?;
+        if n == 0 {
+            continue;
+        }
+
+        file.write_all_at(&bitmap, end)?;
+        *entry = (end / BLOCK_SIZE as u64) as u32;
+        end = data_offset + vhd::DYNAMIC_BLOCK_SIZE;
+        written += n;
+    }
+
+    let table: Vec<u8> = table.iter().flat_map(|entry| entry.to_be_bytes()).collect();
+    file.write_all_at(&table, header.table_offset)?;
+    file.write_all_at(&footer.encode(), 0)?;
+    file.write_all_at(&header.encode(), vhd::FOOTER_SIZE as u64)?;
+    file.write_all_at(&footer.encode(), end)?;
+
+    Ok(written)
+}
+
+impl<D: BlockDevice> Action<D, ExportArgs, ExportError> for ExportAction {
+    fn invoke(image: &mut Image<D>, args: ExportArgs) -> Result<(), ExportError> {
+        // Azure only accepts whole MiB, Hyper-V does not mind.
+        let size = image.len().next_multiple_of(vhd::SIZE_ALIGNMENT);
+        if size > vhd::MAX_SIZE {
+            return Err(ExportError::TooLarge(image.len()));
+        }
+        // Opening the output would truncate the image before it is read.
+        if image.is_stored_at(&args.path) {
+            return Err(ExportError::SameFile);
+        }
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(args.overwrite)
+            .create_new(!args.overwrite)
+            .truncate(true)
+            .open(&args.path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => ExportError::FileAlreadyExistsError,
+                _ => ExportError::CreateError,
+            })?;
+
+        let mut disk = ExportedDisk {
+            image,
+            size,
+            patches: Vec::new(),
+        };
+        if size != image.len() {
+            println!("Rounded the size up from {} to {} bytes", image.len(), size);
+
+            if let Some(PartitionTable::GPT(_)) = read_partition_table(image) {
+                match relocated_gpt(image, size) {
+                    Ok(patches) => disk.patches = patches,
+                    Err(e) => println!(
+                        "Warning: the backup GPT is no longer at the end of the disk: {}",
+                        e
+                    ),
+                }
+            }
+        }
+
+        let footer = Footer {
+            vhd_type: args.format,
+            size,
+            uuid: args.ids.image_uuid(),
+            created: args.created,
+        };
+
+        let written = match args.format {
+            VhdType::Fixed => write_fixed(&disk, &file, &footer)?,
+            VhdType::Dynamic => write_dynamic(&disk, &file, &footer)?,
+        };
+
+        println!(
+            "Exported {} image to {} as a {} VHD, {} of data",
+            humansize::format_size(size, BINARY),
+            args.path.display(),
+            match args.format {
+                VhdType::Fixed => "fixed",
+                VhdType::Dynamic => "dynamic",
+            },
+            humansize::format_size(written, BINARY)
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nuuid::Uuid;
+
+    use super::*;
+    use crate::{
+        image::MemoryDevice,
+        pt::{
+            gpt::GPT,
+            raw::{RawGPTHeader, GPT_PTYPE_LINUX_FS},
+        },
+    };
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// Exports `image` to a file in the temporary directory and returns the contents of the
+    /// file.
+    fn export(mut image: Image<MemoryDevice>, format: VhdType, name: &str) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("fisic-{}-{}", std::process::id(), name));
+        let result = ExportAction::invoke(
+            &mut image,
+            ExportArgs {
+                path: path.clone(),
+                format,
+                overwrite: true,
+                ids: IdSource::Seeded(String::from("test")),
+                created: SystemTime::UNIX_EPOCH,
+            },
+        );
+        let data = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+
+        result.unwrap();
+        data.unwrap()
+    }
+
+    fn be_u32(b: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(b[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn dynamic_export_stores_only_written_blocks() {
+        let block_size = vhd::DYNAMIC_BLOCK_SIZE;
+        let mut image = Image::new(MemoryDevice::new(vec![0; 5 * MIB as usize]));
+        image.write_bytes(100, b"first block").unwrap();
+        image
+            .write_bytes(2 * block_size + 7, b"third block")
+            .unwrap();
+
+        let vhd = export(image, VhdType::Dynamic, "dynamic.vhd");
+
+        let table_offset = (vhd::FOOTER_SIZE + vhd::DYNAMIC_HEADER_SIZE) as u64;
+        let table_end = table_offset + vhd::table_size(3);
+        let entry = |index: usize| be_u32(&vhd, table_offset as usize + index * 4);
+        let stored_block = table_end + vhd::BLOCK_BITMAP_SIZE + block_size;
+
+        assert_eq!(be_u32(&vhd, vhd::FOOTER_SIZE + 28), 3);
+        assert_eq!(entry(0) as u64 * 512, table_end);
+        assert_eq!(entry(1), vhd::UNALLOCATED);
+        assert_eq!(entry(2) as u64 * 512, stored_block);
+
+        let data = |sector: u32, offset: u64, len: usize| {
+            let start = (sector as u64 * 512 + vhd::BLOCK_BITMAP_SIZE + offset) as usize;
+            vhd[start..start + len].to_vec()
+        };
+        assert_eq!(data(entry(0), 100, 11), b"first block");
+        assert_eq!(data(entry(2), 7, 11), b"third block");
+
+        // The footer follows the last block, and is repeated at the start.
+        let footer_offset = stored_block + vhd::BLOCK_BITMAP_SIZE + block_size;
+        assert_eq!(vhd.len() as u64, footer_offset + vhd::FOOTER_SIZE as u64);
+        assert_eq!(vhd[footer_offset as usize..], vhd[..vhd::FOOTER_SIZE],);
+        assert_eq!(&vhd[..8], b"conectix");
+    }
+
+    #[test]
+    fn rounded_export_moves_backup_gpt_to_end() {
+        // 4 MiB and 7 sectors, rounded up to 5 MiB.
+        let nr_blocks = 8 * 1024 + 7;
+        let mut image = Image::new(MemoryDevice::new(vec![0; nr_blocks * BLOCK_SIZE]));
+        let mut gpt = GPT::with_ids(&IdSource::Seeded(String::from("test")));
+        let linux = Uuid::parse(GPT_PTYPE_LINUX_FS).unwrap();
+        gpt.add_partition(linux, String::from("root"), 2048, 4095);
+        gpt.write(&mut image).unwrap();
+
+        let vhd = export(image, VhdType::Fixed, "fixed.vhd");
+        let size = 5 * MIB;
+        assert_eq!(vhd.len() as u64, size + vhd::FOOTER_SIZE as u64);
+
+        let disk = Image::new(MemoryDevice::new(vhd[..size as usize].to_vec()));
+        let last_lba = size / BLOCK_SIZE as u64 - 1;
+        let primary = disk.read::<RawGPTHeader>(BLOCK_SIZE as u64).unwrap();
+        let backup = disk
+            .read::<RawGPTHeader>(last_lba * BLOCK_SIZE as u64)
+            .unwrap();
+
+        assert_eq!(primary.other_header_lba, last_lba);
+        assert_eq!(backup.signature, GPT_SIGNATURE);
+        assert_eq!(backup.this_header_lba, last_lba);
+        assert_eq!(backup.other_header_lba, 1);
+        assert_eq!(
+            backup.partition_entries_checksum,
+            primary.partition_entries_checksum
+        );
+        // Reading a backup checks the CRCs of both headers and both entry arrays.
+        assert!(TableBackup::read(&disk).is_ok());
+        assert_eq!(GPT::read(&disk).unwrap().partitions()[0].end(), 4095);
+
+        // The backup header at the old end of the disk is gone.
+        let old_backup = disk.get_blocks(nr_blocks as u64 - 1, 1).unwrap();
+        assert!(old_backup.iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,133 @@
+use humansize::BINARY;
+use serde::Serialize;
+
+use crate::{
+    image::{BlockDevice, Image},
+    pt::{
+        json::JsonListing,
+        mbr::PartitionType,
+        read_partition_table_verbose,
+        regions::{raw_data_regions, table_regions},
+        PartitionTable,
+    },
+};
+
+use super::{Action, OutputFormat};
+
+const BLOCK_SIZE: u64 = 512;
+
+pub struct InfoArgs {
+    pub output: OutputFormat,
+    /// Device name reported for the image.
+    pub device: String,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum InfoError {
+    /// Generic Error
+    GenericError,
+}
+
+#[derive(Serialize)]
+struct JsonRegion {
+    name: String,
+    start: u64,
+    end: u64,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct JsonInfo {
+    device: String,
+    size: u64,
+    sectorsize: u64,
+    sectors: u64,
+    #[serde(flatten)]
+    table: JsonListing,
+    regions: Vec<JsonRegion>,
+}
+
+pub struct InfoAction {}
+
+impl<D: BlockDevice> Action<D, InfoArgs, InfoError> for InfoAction {
+    fn invoke(image: &mut Image<D>, args: InfoArgs) -> Result<(), InfoError> {
+        let nr_blocks = image.len() / BLOCK_SIZE;
+        let (pt, gpt_error) = read_partition_table_verbose(image);
+
+        let mut regions = table_regions(image);
+        regions.extend(raw_data_regions(image, &regions));
+        regions.sort_by_key(|r| r.start);
+
+        if args.output == OutputFormat::Json {
+            let info = JsonInfo {
+                device: args.device.clone(),
+                size: image.len(),
+                sectorsize: BLOCK_SIZE,
+                sectors: nr_blocks,
+                table: JsonListing::new(pt, &args.device, image),
+                regions: regions
+                    .iter()
+                    .map(|r| JsonRegion {
+                        name: r.name.clone(),
+                        start: r.start,
+                        end: r.end - 1,
+                        size: r.len(),
+                    })
+                    .collect(),
+            };
+
+            println!("{}", serde_json::to_string_pretty(&info).unwrap());
+            return Ok(());
+        }
+
+        println!("Image: {}", args.device);
+        println!(
+            "Size: {} ({} bytes, {} sectors)",
+            humansize::format_size(image.len(), BINARY),
+            image.len(),
+            nr_blocks
+        );
+        println!("Sector size: {}", BLOCK_SIZE);
+        if let Some(e) = gpt_error {
+            println!("Warning: ignoring the GPT: {}", e);
+        }
+
+        match pt {
+            Some(PartitionTable::MBR(mbr)) => {
+                let count = mbr
+                    .partition_table
+                    .iter()
+                    .filter(|pte| pte.ptype != PartitionType::Empty)
+                    .count();
+
+                println!("Partition table: mbr");
+                println!("Disk signature: 0x{:08x}", mbr.disk_signature);
+                println!("Partitions: {}", count);
+            }
+            Some(PartitionTable::GPT(gpt)) => {
+                let (first, last) = gpt.usable_range(nr_blocks);
+                let count = gpt.partitions().iter().filter(|p| !p.is_empty()).count();
+
+                println!("Partition table: gpt");
+                println!("Disk GUID: {}", gpt.disk_guid());
+                println!("First usable LBA: {}", first);
+                println!("Last usable LBA: {}", last);
+                println!("Partitions: {}", count);
+            }
+            Some(PartitionTable::Superfloppy(fs)) => {
+                println!("Partition table: none");
+                println!("{}", fs);
+            }
+            None => println!("Partition table: none"),
+        }
+
+        if !regions.is_empty() {
+            println!("Regions:");
+            for r in regions {
+                println!("  {}", r);
+            }
+        }
+
+        Ok(())
+    }
+}
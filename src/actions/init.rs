@@ -1,33 +1,55 @@
 use crate::{
-    image::Image,
-    pt::{gpt::GPT, mbr::MBR, PartitionTableType},
+    image::{Image, ImageError, WritableBlockDevice},
+    pt::{
+        gpt::{Layout, GPT},
+        ids::IdSource,
+        mbr::{MBRError, MBR},
+        PartitionTableType,
+    },
 };
 
 use super::Action;
 
+const BLOCK_SIZE: u64 = 512;
+
 pub struct InitActionArgs {
     pub pt_type: PartitionTableType,
+    pub ids: IdSource,
+    pub gpt_layout: Layout,
 }
 
 #[derive(Debug, displaydoc::Display, thiserror::Error)]
 pub enum InitActionError {
     /// Generic Error
     GenericError,
+    /// The GPT entry array does not fit on the image, or has fewer than 128 entries
+    InvalidLayout,
+    /// Unable to write MBR: {0}
+    MBRError(#[from] MBRError),
+    /// Unable to write GPT: {0}
+    ImageError(#[from] ImageError),
 }
 
 pub struct InitAction {}
 
-impl Action<InitActionArgs, InitActionError> for InitAction {
-    fn invoke(image: &mut Image, args: InitActionArgs) -> Result<(), InitActionError> {
+impl<D: WritableBlockDevice> Action<D, InitActionArgs, InitActionError> for InitAction {
+    fn invoke(
+        image: &mut Image<D>,
+        args: InitActionArgs,
+    ) -> Result<(), InitActionError> {
         match args.pt_type {
             PartitionTableType::MBR => {
-                let mbr = MBR::new();
-                mbr.write(image);
+                let mbr = MBR::with_ids(&args.ids);
+                mbr.write(image)?;
                 Ok(())
             }
             PartitionTableType::GPT => {
-                let gpt = GPT::new();
-                gpt.write(image);
+                let gpt = GPT::with_layout(&args.ids, args.gpt_layout);
+                if !gpt.fits(image.len() / BLOCK_SIZE) {
+                    return Err(InitActionError::InvalidLayout);
+                }
+
+                gpt.write(image)?;
                 Ok(())
             }
         }
@@ -1,9 +1,24 @@
-use crate::image::Image;
+use crate::image::{BlockDevice, Image};
 
+pub mod convert;
 pub mod create;
+pub mod disk;
+pub mod export;
+pub mod info;
 pub mod init;
 pub mod partitions;
+pub mod raw;
+pub mod regenerate;
+pub mod sparsify;
+pub mod wipe;
 
-pub trait Action<T, E> {
-    fn invoke(image: &mut Image, args: T) -> Result<(), E>;
+/// Format of the report printed by inspection actions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+pub trait Action<D: BlockDevice, T, E> {
+    fn invoke(image: &mut Image<D>, args: T) -> Result<(), E>;
 }
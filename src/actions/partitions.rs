@@ -1,8 +1,43 @@
-use crate::pt::{read_partition_table, PartitionTable};
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
 
-use super::Action;
+use nuuid::Uuid;
 
-pub struct ListPartitionsArgs {}
+use crate::{
+    fs::{
+        probe::{probe_partition, FsInfo},
+        size::filesystem_size,
+        PROBE_SIZE,
+    },
+    image::{BlockDevice, Image, ImageError, WritableBlockDevice},
+    pt::{
+        backup::{clear_gpt_headers, BackupError, TableBackup},
+        gpt::Layout,
+        ids::IdSource,
+        json::JsonListing,
+        mbr::{MBRError, PartitionType},
+        read_partition_table,
+        sfdisk::{self, Script, ScriptError},
+        PartitionTable,
+    },
+};
+
+use super::{Action, OutputFormat};
+
+const BLOCK_SIZE: u64 = 512;
+
+/// Amount of a file read into memory at once when copying it into a partition.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+pub struct ListPartitionsArgs {
+    pub output: OutputFormat,
+    /// Device name used as the prefix of each partition node in JSON output.
+    pub device: String,
+}
 
 #[derive(Debug, displaydoc::Display, thiserror::Error)]
 pub enum ListPartitionsError {
@@ -12,22 +47,52 @@ pub enum ListPartitionsError {
 
 pub struct ListPartitionsAction {}
 
-impl Action<ListPartitionsArgs, ListPartitionsError> for ListPartitionsAction {
+impl<D: BlockDevice> Action<D, ListPartitionsArgs, ListPartitionsError> for ListPartitionsAction {
     fn invoke(
-        image: &mut crate::image::Image,
+        image: &mut Image<D>,
         args: ListPartitionsArgs,
     ) -> Result<(), ListPartitionsError> {
         // Determine partition table type
         let pt = read_partition_table(image);
 
+        if args.output == OutputFormat::Json {
+            let listing = JsonListing::new(pt, &args.device, image);
+
+            println!("{}", serde_json::to_string_pretty(&listing).unwrap());
+            return Ok(());
+        }
+
+        let print_partition = |p: &dyn Display, fs: Option<FsInfo>| match fs {
+            Some(fs) => println!("{}, {}", p, fs),
+            None => println!("{}", p),
+        };
+
         match pt {
             Some(PartitionTable::MBR(mbr)) => {
                 println!("found mbr:");
-                println!("{}", mbr);
+                for pte in &mbr.partition_table {
+                    if pte.ptype != PartitionType::Empty {
+                        print_partition(
+                            pte,
+                            probe_partition(image, pte.first_sector_lba, pte.nr_sectors),
+                        );
+                    }
+                }
+                println!();
             }
             Some(PartitionTable::GPT(gpt)) => {
                 println!("found gpt:");
-                println!("{}", gpt);
+                println!("GUID: {}", gpt.disk_guid());
+                for p in gpt.partitions() {
+                    if !p.is_empty() {
+                        print_partition(p, probe_partition(image, p.start(), p.nr_sectors()));
+                    }
+                }
+                println!();
+            }
+            Some(PartitionTable::Superfloppy(fs)) => {
+                println!("no partition table found, whole-disk filesystem:");
+                println!("{}", fs);
             }
             None => {
                 println!("no partition table found");
@@ -37,3 +102,452 @@ impl Action<ListPartitionsArgs, ListPartitionsError> for ListPartitionsAction {
         Ok(())
     }
 }
+
+pub struct DumpPartitionsArgs {
+    /// Device name used as the prefix of each partition line.
+    pub device: String,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum DumpPartitionsError {
+    /// No partition table found
+    NoPartitionTable,
+}
+
+pub struct DumpPartitionsAction {}
+
+impl<D: BlockDevice> Action<D, DumpPartitionsArgs, DumpPartitionsError> for DumpPartitionsAction {
+    fn invoke(
+        image: &mut Image<D>,
+        args: DumpPartitionsArgs,
+    ) -> Result<(), DumpPartitionsError> {
+        let pt = read_partition_table(image)
+            .filter(|pt| !matches!(pt, PartitionTable::Superfloppy(_)))
+            .ok_or(DumpPartitionsError::NoPartitionTable)?;
+
+        print!(
+            "{}",
+            sfdisk::dump(&pt, &args.device, image.len() / BLOCK_SIZE)
+        );
+
+        Ok(())
+    }
+}
+
+pub struct LoadPartitionsArgs {
+    /// Contents of the sfdisk script.
+    pub script: String,
+    pub ids: IdSource,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum LoadPartitionsError {
+    /// Invalid sfdisk script: {0}
+    ScriptError(#[from] ScriptError),
+    /// Unable to write MBR: {0}
+    MBRError(#[from] MBRError),
+    /// Unable to clear the existing GPT: {0}
+    BackupError(#[from] BackupError),
+    /// Unable to write GPT: {0}
+    ImageError(#[from] ImageError),
+}
+
+pub struct LoadPartitionsAction {}
+
+impl<D: WritableBlockDevice> Action<D, LoadPartitionsArgs, LoadPartitionsError> for LoadPartitionsAction {
+    fn invoke(
+        image: &mut Image<D>,
+        args: LoadPartitionsArgs,
+    ) -> Result<(), LoadPartitionsError> {
+        let script = Script::parse(&args.script)?;
+
+        // Keep the entry array where an existing GPT has it, e.g. clear of a bootloader.
+        let layout = match read_partition_table(image) {
+            Some(PartitionTable::GPT(gpt)) => gpt.layout(),
+            _ => Layout::default(),
+        };
+
+        match script.build(image.len() / BLOCK_SIZE, &args.ids, layout)? {
+            PartitionTable::MBR(mbr) => {
+                // Otherwise the old GPT would still shadow the new table.
+                clear_gpt_headers(image)?;
+                mbr.write(image)?
+            }
+            PartitionTable::GPT(gpt) => gpt.write(image)?,
+            PartitionTable::Superfloppy(_) => {
+                unreachable!("sfdisk scripts always describe a table")
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct BackupPartitionsArgs {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum BackupPartitionsError {
+    /// Unable to back up partition table: {0}
+    BackupError(#[from] BackupError),
+    /// Unable to write backup file
+    WriteError,
+}
+
+pub struct BackupPartitionsAction {}
+
+impl<D: BlockDevice> Action<D, BackupPartitionsArgs, BackupPartitionsError> for BackupPartitionsAction {
+    fn invoke(
+        image: &mut Image<D>,
+        args: BackupPartitionsArgs,
+    ) -> Result<(), BackupPartitionsError> {
+        let backup = TableBackup::read(image)?;
+
+        std::fs::write(&args.path, backup.to_bytes())
+            .map_err(|_| BackupPartitionsError::WriteError)?;
+
+        Ok(())
+    }
+}
+
+pub struct RestorePartitionsArgs {
+    pub path: PathBuf,
+    /// Move the backup GPT header and entries to the end of the image.
+    pub relocate_backup: bool,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum RestorePartitionsError {
+    /// Unable to restore partition table: {0}
+    BackupError(#[from] BackupError),
+    /// Unable to read backup file
+    ReadError,
+}
+
+pub struct RestorePartitionsAction {}
+
+impl<D: WritableBlockDevice> Action<D, RestorePartitionsArgs, RestorePartitionsError> for RestorePartitionsAction {
+    fn invoke(
+        image: &mut Image<D>,
+        args: RestorePartitionsArgs,
+    ) -> Result<(), RestorePartitionsError> {
+        let bytes = std::fs::read(&args.path).map_err(|_| RestorePartitionsError::ReadError)?;
+
+        TableBackup::from_bytes(&bytes)?.restore(image, args.relocate_backup)?;
+
+        Ok(())
+    }
+}
+
+pub struct SetPartitionArgs {
+    /// Partition number, starting at 1.
+    pub number: usize,
+    /// New type GUID or sfdisk type shortcut.
+    pub ptype: Option<String>,
+    pub name: Option<String>,
+    pub uuid: Option<String>,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum SetPartitionError {
+    /// No GPT partition table found
+    NoGPT,
+    /// Partition {0} does not exist
+    InvalidPartition(usize),
+    /// Invalid partition type `{0}`
+    InvalidType(String),
+    /// Invalid GUID `{0}`
+    InvalidGuid(String),
+    /// Partition name is longer than 36 UTF-16 code units
+    NameTooLong,
+    /// Unable to write GPT: {0}
+    ImageError(#[from] ImageError),
+}
+
+pub struct SetPartitionAction {}
+
+impl<D: WritableBlockDevice> Action<D, SetPartitionArgs, SetPartitionError> for SetPartitionAction {
+    fn invoke(
+        image: &mut Image<D>,
+        args: SetPartitionArgs,
+    ) -> Result<(), SetPartitionError> {
+        let mut gpt = match read_partition_table(image) {
+            Some(PartitionTable::GPT(gpt)) => gpt,
+            _ => return Err(SetPartitionError::NoGPT),
+        };
+
+        let p = args
+            .number
+            .checked_sub(1)
+            .and_then(|index| gpt.partition_mut(index))
+            .filter(|p| !p.is_empty())
+            .ok_or(SetPartitionError::InvalidPartition(args.number))?;
+
+        if let Some(ptype) = args.ptype {
+            p.set_type_guid(sfdisk::gpt_type(&ptype).ok_or(SetPartitionError::InvalidType(ptype))?);
+        }
+
+        if let Some(uuid) = args.uuid {
+            p.set_part_guid(Uuid::parse(&uuid).map_err(|_| SetPartitionError::InvalidGuid(uuid))?);
+        }
+
+        if let Some(name) = args.name {
+            if !p.set_name(name) {
+                return Err(SetPartitionError::NameTooLong);
+            }
+        }
+
+        gpt.write_tables(image)?;
+
+        Ok(())
+    }
+}
+
+pub struct SortPartitionsArgs {}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum SortPartitionsError {
+    /// No partition table found
+    NoPartitionTable,
+    /// Unable to write MBR: {0}
+    MBRError(#[from] MBRError),
+    /// Unable to write GPT: {0}
+    ImageError(#[from] ImageError),
+}
+
+pub struct SortPartitionsAction {}
+
+impl<D: WritableBlockDevice> Action<D, SortPartitionsArgs, SortPartitionsError> for SortPartitionsAction {
+    fn invoke(
+        image: &mut Image<D>,
+        _args: SortPartitionsArgs,
+    ) -> Result<(), SortPartitionsError> {
+        let mut pt = read_partition_table(image)
+            .filter(|pt| !matches!(pt, PartitionTable::Superfloppy(_)))
+            .ok_or(SortPartitionsError::NoPartitionTable)?;
+
+        let mapping = match &mut pt {
+            PartitionTable::GPT(gpt) => gpt.sort_partitions(),
+            PartitionTable::MBR(mbr) => mbr.sort_entries(),
+            PartitionTable::Superfloppy(_) => unreachable!(),
+        };
+
+        // Leave the image untouched, e.g. on read-only media.
+        if mapping.iter().all(|(old, new)| old == new) {
+            println!("partition table is already sorted");
+            return Ok(());
+        }
+
+        match pt {
+            PartitionTable::GPT(gpt) => gpt.write_tables(image)?,
+            PartitionTable::MBR(mbr) => mbr.write(image)?,
+            PartitionTable::Superfloppy(_) => unreachable!(),
+        }
+
+        for (old, new) in mapping {
+            println!("{} -> {}", old + 1, new + 1);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct WritePartitionArgs {
+    /// Partition number, starting at 1, or GPT partition name.
+    pub partition: String,
+    pub path: PathBuf,
+    /// Zero the part of the partition after the end of the file.
+    pub zero_tail: bool,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum WritePartitionError {
+    /// No partition table found
+    NoPartitionTable,
+    /// Partition `{0}` does not exist
+    InvalidPartition(String),
+    /// Partition lies outside the image
+    OutOfRange,
+    /// Unable to read input file
+    ReadError,
+    /// The file is {file_size} bytes but the partition only holds {partition_size}
+    TooLarge {
+        file_size: u64,
+        partition_size: u64,
+    },
+}
+
+pub struct WritePartitionAction {}
+
+impl<D: WritableBlockDevice> Action<D, WritePartitionArgs, WritePartitionError> for WritePartitionAction {
+    fn invoke(
+        image: &mut Image<D>,
+        args: WritePartitionArgs,
+    ) -> Result<(), WritePartitionError> {
+        let (start, nr_blocks) = read_partition_table(image)
+            .ok_or(WritePartitionError::NoPartitionTable)?
+            .find_partition(&args.partition)
+            .ok_or_else(|| WritePartitionError::InvalidPartition(args.partition.clone()))?;
+
+        if start + nr_blocks > image.len() / BLOCK_SIZE {
+            return Err(WritePartitionError::OutOfRange);
+        }
+
+        let file = File::open(&args.path).map_err(|_| WritePartitionError::ReadError)?;
+        let file_size = file
+            .metadata()
+            .map_err(|_| WritePartitionError::ReadError)?
+            .len();
+        let partition_size = nr_blocks * BLOCK_SIZE;
+
+        if file_size > partition_size {
+            return Err(WritePartitionError::TooLarge {
+                file_size,
+                partition_size,
+            });
+        }
+
+        let base = start * BLOCK_SIZE;
+        image
+            .import(base, &file, file_size)
+            .map_err(|e| match e {
+                ImageError::CopyError(_) => WritePartitionError::ReadError,
+                _ => WritePartitionError::OutOfRange,
+            })?;
+
+        println!("Wrote {} bytes to partition {}", file_size, args.partition);
+
+        if args.zero_tail {
+            image
+                .zero_bytes_sparse(base + file_size, partition_size - file_size)
+                .map_err(|_| WritePartitionError::OutOfRange)?;
+
+            println!(
+                "Zeroed {} bytes after the end of the file",
+                partition_size - file_size
+            );
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ReadPartitionArgs {
+    /// Partition number, starting at 1, or GPT partition name.
+    pub partition: String,
+    pub path: PathBuf,
+    /// Stop at the end of the filesystem rather than the end of the partition.
+    pub truncate: bool,
+    pub overwrite: bool,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ReadPartitionError {
+    /// No partition table found
+    NoPartitionTable,
+    /// Partition `{0}` does not exist
+    InvalidPartition(String),
+    /// Partition lies outside the image
+    OutOfRange,
+    /// No filesystem with a known size found in the partition
+    UnknownFilesystemSize,
+    /// The output file already exists, and force overwrite was not specified
+    FileAlreadyExistsError,
+    /// The output file is the image being read
+    SameFile,
+    /// Unable to write output file
+    WriteError,
+}
+
+pub struct ReadPartitionAction {}
+
+/// Writes the runs of non-zero blocks in `data` to `file` at `offset`, leaving the rest of the
+/// file as a hole.
+fn write_data_runs(file: &mut File, offset: u64, data: &[u8]) -> io::Result<()> {
+    let block_size = BLOCK_SIZE as usize;
+    let is_zero = |o: usize| {
+        data[o..(o + block_size).min(data.len())]
+            .iter()
+            .all(|&b| b == 0)
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        if is_zero(pos) {
+            pos += block_size;
+            continue;
+        }
+
+        let run_start = pos;
+        while pos < data.len() && !is_zero(pos) {
+            pos += block_size;
+        }
+        let run_end = pos.min(data.len());
+
+        file.seek(SeekFrom::Start(offset + run_start as u64))?;
+        file.write_all(&data[run_start..run_end])?;
+    }
+
+    Ok(())
+}
+
+impl<D: BlockDevice> Action<D, ReadPartitionArgs, ReadPartitionError> for ReadPartitionAction {
+    fn invoke(
+        image: &mut Image<D>,
+        args: ReadPartitionArgs,
+    ) -> Result<(), ReadPartitionError> {
+        let (start, nr_blocks) = read_partition_table(image)
+            .ok_or(ReadPartitionError::NoPartitionTable)?
+            .find_partition(&args.partition)
+            .ok_or_else(|| ReadPartitionError::InvalidPartition(args.partition.clone()))?;
+
+        if start + nr_blocks > image.len() / BLOCK_SIZE {
+            return Err(ReadPartitionError::OutOfRange);
+        }
+
+        let base = start * BLOCK_SIZE;
+        let mut size = nr_blocks * BLOCK_SIZE;
+        if args.truncate {
+            let head = image
+                .get_bytes(base, (PROBE_SIZE as u64).min(size) as usize)
+                .map_err(|_| ReadPartitionError::OutOfRange)?;
+            size = filesystem_size(&head)
+                .filter(|&fs_size| fs_size <= size)
+                .ok_or(ReadPartitionError::UnknownFilesystemSize)?;
+        }
+
+        // Opening the output would truncate the image before it is read.
+        if image.is_stored_at(&args.path) {
+            return Err(ReadPartitionError::SameFile);
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(args.overwrite)
+            .create_new(!args.overwrite)
+            .truncate(true)
+            .open(&args.path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => ReadPartitionError::FileAlreadyExistsError,
+                _ => ReadPartitionError::WriteError,
+            })?;
+        file.set_len(size)
+            .map_err(|_| ReadPartitionError::WriteError)?;
+
+        let mut offset = 0;
+        while offset < size {
+            let len = (COPY_CHUNK_SIZE as u64).min(size - offset);
+            let data = image
+                .get_bytes(base + offset, len as usize)
+                .map_err(|_| ReadPartitionError::OutOfRange)?;
+            write_data_runs(&mut file, offset, &data)
+                .map_err(|_| ReadPartitionError::WriteError)?;
+            offset += len;
+        }
+
+        println!("Read {} bytes from partition {}", size, args.partition);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use std::{fs::File, io::Read, path::PathBuf};
+
+use crate::{
+    image::{Image, ImageError, WritableBlockDevice},
+    pt::regions::table_regions,
+};
+
+use super::Action;
+
+pub struct RawWriteArgs {
+    /// Byte offset within the image.
+    pub offset: u64,
+    pub path: PathBuf,
+    /// Write even if the data overlaps the partition table or a partition.
+    pub force: bool,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum RawWriteError {
+    /// Unable to read input file
+    ReadError,
+    /// The data does not fit within the image
+    OutOfRange,
+    /// The data overlaps the {0}, use --force to write it anyway
+    Overlap(String),
+    /// {0}
+    ImageError(#[from] ImageError),
+}
+
+pub struct RawWriteAction {}
+
+impl<D: WritableBlockDevice> Action<D, RawWriteArgs, RawWriteError> for RawWriteAction {
+    fn invoke(
+        image: &mut Image<D>,
+        args: RawWriteArgs,
+    ) -> Result<(), RawWriteError> {
+        let file = File::open(&args.path).map_err(|_| RawWriteError::ReadError)?;
+        let metadata = file.metadata().map_err(|_| RawWriteError::ReadError)?;
+
+        // Pipes and devices have no size to go by, so read them to the end first. Reading one
+        // byte more than fits is enough to tell that the data is too large.
+        let stream = if metadata.is_file() {
+            None
+        } else {
+            let space = image.len().saturating_sub(args.offset);
+            let mut data = Vec::new();
+            (&file)
+                .take(space.saturating_add(1))
+                .read_to_end(&mut data)
+                .map_err(|_| RawWriteError::ReadError)?;
+            Some(data)
+        };
+        let len = match &stream {
+            Some(data) => data.len() as u64,
+            None => metadata.len(),
+        };
+
+        let end = args
+            .offset
+            .checked_add(len)
+            .filter(|&end| end <= image.len())
+            .ok_or(RawWriteError::OutOfRange)?;
+
+        if let Some(region) = table_regions(image)
+            .into_iter()
+            .find(|r| r.overlaps(args.offset, end))
+        {
+            if !args.force {
+                return Err(RawWriteError::Overlap(region.name));
+            }
+
+            println!("Warning: overwriting part of the {}", region.name);
+        }
+
+        match stream {
+            Some(data) => image.write_bytes_sparse(args.offset, &data)?,
+            None => image.import(args.offset, &file, len).map_err(|e| match e {
+                ImageError::CopyError(_) => RawWriteError::ReadError,
+                e => RawWriteError::ImageError(e),
+            })?,
+        }
+
+        println!("Wrote {} bytes at offset {:#x}", len, args.offset);
+
+        Ok(())
+    }
+}
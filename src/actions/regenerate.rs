@@ -0,0 +1,206 @@
+use crate::{
+    fs::files::find_files,
+    image::{Image, ImageError, WritableBlockDevice},
+    pt::{
+        ids::IdSource,
+        mbr::{MBRError, PartitionType, MBR},
+        read_partition_table, PartitionTable,
+    },
+};
+
+use super::Action;
+
+const BLOCK_SIZE: u64 = 512;
+
+const PARTUUID_PREFIX: &[u8] = b"PARTUUID=";
+
+pub struct RegenerateIdsArgs {
+    /// Rewrite `PARTUUID=` references in the fstab, boot loader entries and similar files of
+    /// FAT and ext filesystems.
+    pub update_references: bool,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum RegenerateIdsError {
+    /// No partition table found
+    NoPartitionTable,
+    /// Unable to write MBR: {0}
+    MBRError(#[from] MBRError),
+    /// Unable to update the image: {0}
+    ImageError(#[from] ImageError),
+}
+
+pub struct RegenerateIdsAction {}
+
+/// Files that may refer to partitions by PARTUUID, as paths within a root, boot or EFI system
+/// partition.
+const REFERENCE_FILES: &[&[&str]] = &[
+    &["etc", "fstab"],
+    &["boot", "grub", "grub.cfg"],
+    &["boot", "grub2", "grub.cfg"],
+    &["grub", "grub.cfg"],
+    &["grub2", "grub.cfg"],
+    &["boot", "loader", "entries", "*"],
+    &["loader", "entries", "*"],
+    &["EFI", "*", "grub.cfg"],
+    &["cmdline.txt"],
+];
+
+/// Replaces `PARTUUID=<old>` with `PARTUUID=<new>` in `data`, matching `old` case-insensitively
+/// and keeping the case of each occurrence. Returns the number of references replaced.
+fn replace_references(data: &mut [u8], replacements: &[(String, String)]) -> usize {
+    let mut count = 0;
+    let mut pos = 0;
+
+    while pos + PARTUUID_PREFIX.len() <= data.len() {
+        if !data[pos..].starts_with(PARTUUID_PREFIX) {
+            pos += 1;
+            continue;
+        }
+
+        let value_start = pos + PARTUUID_PREFIX.len();
+        pos = value_start;
+
+        for (old, new) in replacements {
+            let value_end = value_start + old.len();
+            let found = match data.get(value_start..value_end) {
+                Some(found) if found.eq_ignore_ascii_case(old.as_bytes()) => found,
+                _ => continue,
+            };
+
+            let replacement = if found.iter().any(u8::is_ascii_uppercase) {
+                new.to_uppercase()
+            } else {
+                new.to_lowercase()
+            };
+
+            data[value_start..value_end].copy_from_slice(replacement.as_bytes());
+            pos = value_end;
+            count += 1;
+            break;
+        }
+    }
+
+    count
+}
+
+/// Replaces references in the known configuration files of the FAT or ext filesystem in blocks
+/// `start` to `end`. Only the file contents are changed, which neither filesystem checksums,
+/// and files the parser cannot locate are left alone.
+fn replace_references_in(
+    image: &mut Image<impl WritableBlockDevice>,
+    start: u64,
+    end: u64,
+    replacements: &[(String, String)],
+) -> Result<Vec<(String, usize)>, ImageError> {
+    let offset = start * BLOCK_SIZE;
+    let len = (end + 1 - start) * BLOCK_SIZE;
+    let mut updated = Vec::new();
+
+    for mut file in find_files(image, offset, len, REFERENCE_FILES) {
+        let count = replace_references(&mut file.contents, replacements);
+        if count == 0 {
+            continue;
+        }
+
+        for (file_offset, image_offset, len) in file.runs {
+            image.write_bytes(image_offset, &file.contents[file_offset..file_offset + len])?;
+        }
+        updated.push((file.path, count));
+    }
+
+    Ok(updated)
+}
+
+impl<D: WritableBlockDevice> Action<D, RegenerateIdsArgs, RegenerateIdsError> for RegenerateIdsAction {
+    fn invoke(
+        image: &mut Image<D>,
+        args: RegenerateIdsArgs,
+    ) -> Result<(), RegenerateIdsError> {
+        let ids = IdSource::Random;
+
+        // Pairs of (old, new) PARTUUID values, and the partitions to search for references.
+        let mut replacements = Vec::new();
+        let mut ranges = Vec::new();
+
+        match read_partition_table(image).ok_or(RegenerateIdsError::NoPartitionTable)? {
+            PartitionTable::GPT(mut gpt) => {
+                let disk_guid = ids.disk_guid();
+                println!("disk: {} -> {}", gpt.disk_guid(), disk_guid);
+                gpt.set_disk_guid(disk_guid);
+
+                for index in 0..gpt.partitions().len() {
+                    let p = gpt.partition_mut(index).unwrap();
+                    if p.is_empty() {
+                        continue;
+                    }
+
+                    let old = p.part_guid();
+                    let new = ids.partition_guid(index, p.name());
+                    println!("partition {}: {} -> {}", index + 1, old, new);
+
+                    p.set_part_guid(new);
+                    replacements.push((old.to_string(), new.to_string()));
+                    ranges.push((p.start(), p.end()));
+                }
+
+                gpt.write_tables(image)?;
+
+                // Protective MBRs normally carry no signature, but some tools set one anyway.
+                if let Some(mut mbr) = MBR::read(image).filter(|mbr| mbr.disk_signature != 0) {
+                    let new = ids.disk_signature();
+                    println!(
+                        "disk signature: 0x{:08x} -> 0x{:08x}",
+                        mbr.disk_signature, new
+                    );
+
+                    mbr.disk_signature = new;
+                    mbr.write(image)?;
+                }
+            }
+            PartitionTable::MBR(mut mbr) => {
+                let old = mbr.disk_signature;
+                let new = ids.disk_signature();
+                println!("disk signature: 0x{:08x} -> 0x{:08x}", old, new);
+
+                mbr.disk_signature = new;
+                mbr.write(image)?;
+
+                for (i, pte) in mbr.partition_table.iter().enumerate() {
+                    if pte.ptype == PartitionType::Empty {
+                        continue;
+                    }
+
+                    replacements.push((
+                        format!("{:08x}-{:02x}", old, i + 1),
+                        format!("{:08x}-{:02x}", new, i + 1),
+                    ));
+                    ranges.push((
+                        pte.first_sector_lba,
+                        pte.first_sector_lba + pte.nr_sectors.max(1) - 1,
+                    ));
+                }
+            }
+            PartitionTable::Superfloppy(_) => return Err(RegenerateIdsError::NoPartitionTable),
+        }
+
+        if args.update_references {
+            let nr_blocks = image.len() / BLOCK_SIZE;
+
+            for (start, end) in ranges {
+                if end >= nr_blocks {
+                    continue;
+                }
+
+                for (path, count) in replace_references_in(image, start, end, &replacements)? {
+                    println!(
+                        "updated {} PARTUUID reference(s) in {} of the filesystem at LBA {}",
+                        count, path, start
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use humansize::BINARY;
+
+use crate::image::{Image, ImageError, WritableBlockDevice};
+
+use super::Action;
+
+const BLOCK_SIZE: usize = 512;
+
+pub struct SparsifyArgs {
+    /// Size of the chunks that are checked for zeros and deallocated, in bytes.
+    pub granularity: usize,
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum SparsifyError {
+    /// The granularity must be a non-zero multiple of 512 bytes
+    InvalidGranularity,
+    /// {0}
+    ImageError(#[from] ImageError),
+}
+
+pub struct SparsifyAction {}
+
+/// Returns the byte ranges of the runs of all-zero chunks of `granularity` bytes in the image.
+fn zero_runs(
+    image: &Image<impl WritableBlockDevice>,
+    granularity: usize,
+) -> Result<Vec<(u64, u64)>, ImageError> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+
+    for offset in (0..image.len()).step_by(granularity) {
+        let len = (granularity as u64).min(image.len() - offset);
+        if !image
+            .get_bytes(offset, len as usize)?
+            .iter()
+            .all(|&b| b == 0)
+        {
+            continue;
+        }
+
+        match runs.last_mut() {
+            Some((_, end)) if *end == offset => *end += len,
+            _ => runs.push((offset, offset + len)),
+        }
+    }
+
+    Ok(runs)
+}
+
+impl<D: WritableBlockDevice> Action<D, SparsifyArgs, SparsifyError> for SparsifyAction {
+    fn invoke(image: &mut Image<D>, args: SparsifyArgs) -> Result<(), SparsifyError> {
+        if args.granularity == 0 || !args.granularity.is_multiple_of(BLOCK_SIZE) {
+            return Err(SparsifyError::InvalidGranularity);
+        }
+
+        let before = image.allocated();
+
+        let runs = zero_runs(image, args.granularity)?;
+        for &(start, end) in &runs {
+            image.punch_hole(start, end - start)?;
+        }
+
+        let zeros: u64 = runs.iter().map(|(start, end)| end - start).sum();
+        println!(
+            "{} of zeros in {} runs",
+            humansize::format_size(zeros, BINARY),
+            runs.len()
+        );
+
+        match (before, image.allocated()) {
+            (Some(before), Some(after)) => {
+                let reclaimed = before.saturating_sub(after);
+                println!(
+                    "reclaimed {} ({} bytes)",
+                    humansize::format_size(reclaimed, BINARY),
+                    reclaimed
+                )
+            }
+            _ => println!("unable to tell how much space was reclaimed"),
+        }
+
+        Ok(())
+    }
+}
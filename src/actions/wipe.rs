@@ -0,0 +1,145 @@
+use crate::{
+    fs::signatures::{find_filesystem_signatures, Signature},
+    image::{BlockDevice, Image, ImageError, WritableBlockDevice},
+    pt::{
+        mbr::PartitionType,
+        raw::{RawGPTHeader, GPT_SIGNATURE},
+        read_partition_table, PartitionTable,
+    },
+};
+
+use super::Action;
+
+const BLOCK_SIZE: u64 = 512;
+
+pub struct WipeArgs {}
+
+/// Lists the signatures that [`WipeAction`] would erase, without write access to the image.
+pub struct WipeDryRunArgs {}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum WipeError {
+    /// Generic Error
+    GenericError,
+    /// {0}
+    ImageError(#[from] ImageError),
+}
+
+pub struct WipeAction {}
+
+pub struct WipeDryRunAction {}
+
+/// Finds the MBR boot signature and both GPT header signatures. The backup header is looked for
+/// both where the primary header points and in the last block, so a stale backup left behind by
+/// an earlier table is found too.
+fn table_signatures(image: &Image<impl BlockDevice>, is_gpt: bool) -> Vec<Signature> {
+    let nr_blocks = image.len() / BLOCK_SIZE;
+    let mut found = Vec::new();
+
+    if nr_blocks == 0 {
+        return found;
+    }
+
+    if image.get_bytes(0x1fe, 2).is_ok_and(|b| *b == [0x55, 0xaa]) {
+        let name = if is_gpt { "PMBR" } else { "dos" };
+        found.push(Signature::new(String::from(name), 0x1fe, 2));
+    }
+
+    let mut header_blocks = vec![1, nr_blocks - 1];
+    if let Ok(primary) = image.read::<RawGPTHeader>(BLOCK_SIZE) {
+        if primary.signature == GPT_SIGNATURE {
+            header_blocks.push(primary.other_header_lba);
+        }
+    }
+
+    for block in header_blocks {
+        if image
+            .get_blocks(block, 1)
+            .is_ok_and(|b| b[..8] == GPT_SIGNATURE)
+        {
+            found.push(Signature::new(
+                String::from("gpt"),
+                block * BLOCK_SIZE,
+                GPT_SIGNATURE.len(),
+            ));
+        }
+    }
+
+    found
+}
+
+/// Returns the byte range of every partition in the partition table.
+fn partition_ranges(pt: &Option<PartitionTable>) -> Vec<(u64, u64)> {
+    match pt {
+        Some(PartitionTable::GPT(gpt)) => gpt
+            .partitions()
+            .iter()
+            .filter(|p| !p.is_empty())
+            .map(|p| (p.start() * BLOCK_SIZE, (p.end() + 1) * BLOCK_SIZE))
+            .collect(),
+        Some(PartitionTable::MBR(mbr)) => mbr
+            .partition_table
+            .iter()
+            .filter(|pte| pte.ptype != PartitionType::Empty)
+            .map(|pte| {
+                (
+                    pte.first_sector_lba * BLOCK_SIZE,
+                    (pte.first_sector_lba + pte.nr_sectors) * BLOCK_SIZE,
+                )
+            })
+            .collect(),
+        Some(PartitionTable::Superfloppy(_)) | None => Vec::new(),
+    }
+}
+
+/// Returns the partition table and filesystem signatures on the image, in order of offset.
+fn find_signatures(image: &Image<impl BlockDevice>) -> Vec<Signature> {
+    let pt = read_partition_table(image);
+
+    // A filesystem may also start at the beginning of the disk, e.g. an ISO9660 image.
+    let mut signatures = find_filesystem_signatures(image, 0, image.len());
+    for (start, end) in partition_ranges(&pt) {
+        signatures.extend(find_filesystem_signatures(image, start, end));
+    }
+    signatures.extend(table_signatures(
+        image,
+        matches!(pt, Some(PartitionTable::GPT(_))),
+    ));
+
+    signatures.sort_by_key(|s| s.offset);
+    signatures.dedup_by_key(|s| s.offset);
+    signatures
+}
+
+impl<D: WritableBlockDevice> Action<D, WipeArgs, WipeError> for WipeAction {
+    fn invoke(image: &mut Image<D>, _args: WipeArgs) -> Result<(), WipeError> {
+        let signatures = find_signatures(image);
+        if signatures.is_empty() {
+            println!("no signatures found");
+            return Ok(());
+        }
+
+        for s in &signatures {
+            s.erase(image)?;
+            println!("erased {}", s);
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Action<D, WipeDryRunArgs, WipeError> for WipeDryRunAction {
+    fn invoke(image: &mut Image<D>, _args: WipeDryRunArgs) -> Result<(), WipeError> {
+        let signatures = find_signatures(image);
+        if signatures.is_empty() {
+            println!("no signatures found");
+            return Ok(());
+        }
+
+        for s in &signatures {
+            println!("would erase {}", s);
+        }
+
+        Ok(())
+    }
+}
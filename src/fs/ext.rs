@@ -0,0 +1,299 @@
+use crate::image::BlockDevice;
+
+use super::{
+    files::{DirEntry, Filesystem, Layout, Volume, MAX_CHAIN, MAX_FILE_SIZE},
+    le,
+};
+
+const SUPERBLOCK: u64 = 1024;
+const ROOT_INODE: usize = 2;
+
+const INCOMPAT_FILETYPE: usize = 0x2;
+/// The journal holds changes that have not been written to the filesystem yet.
+const INCOMPAT_RECOVER: usize = 0x4;
+const INCOMPAT_64BIT: usize = 0x80;
+
+const INODE_FLAG_EXTENTS: usize = 0x80000;
+const INODE_FLAG_INLINE_DATA: usize = 0x1000_0000;
+
+const MODE_TYPE_MASK: usize = 0xf000;
+const MODE_DIRECTORY: usize = 0x4000;
+const MODE_REGULAR: usize = 0x8000;
+
+const EXTENT_MAGIC: usize = 0xf30a;
+/// Extent lengths above this mark uninitialized extents, which read as zeros.
+const MAX_INIT_EXTENT_LEN: usize = 32768;
+/// Deepest extent tree, as in the kernel.
+const MAX_EXTENT_DEPTH: usize = 5;
+
+/// Number of block pointers in an inode that point directly at data.
+const DIRECT_BLOCKS: usize = 12;
+
+pub(super) struct Ext<'a, 'b, D: BlockDevice> {
+    volume: &'a Volume<'b, D>,
+    block_size: usize,
+    inodes_per_group: usize,
+    inode_size: usize,
+    nr_groups: usize,
+    /// Offset of the group descriptor table.
+    descriptors: u64,
+    descriptor_size: usize,
+    has_filetype: bool,
+}
+
+struct Inode {
+    mode: usize,
+    size: u64,
+    flags: usize,
+    /// The `i_block` field, holding extents or block pointers.
+    blocks: Vec<u8>,
+}
+
+impl<'a, 'b, D: BlockDevice> Ext<'a, 'b, D> {
+    /// Opens the filesystem, unless its journal needs recovery, in which case the files may
+    /// not be in their final place yet.
+    pub(super) fn open(volume: &'a Volume<'b, D>) -> Option<Self> {
+        let sb = volume.read(SUPERBLOCK, 1024)?;
+        if sb[0x38..0x3a] != [0x53, 0xef] {
+            return None;
+        }
+
+        let incompat = le::<4>(&sb, 0x60)?;
+        if incompat & INCOMPAT_RECOVER != 0 {
+            return None;
+        }
+
+        let nr_blocks = le::<4>(&sb, 0x4)?;
+        let first_data_block = le::<4>(&sb, 0x14)?;
+        let block_size = 1024usize.checked_shl(le::<4>(&sb, 0x18)? as u32)?;
+        let blocks_per_group = le::<4>(&sb, 0x20)?;
+        let inodes_per_group = le::<4>(&sb, 0x28)?;
+        let inode_size = match le::<4>(&sb, 0x4c)? {
+            0 => 128,
+            _ => le::<2>(&sb, 0x58)?,
+        };
+        let descriptor_size = match incompat & INCOMPAT_64BIT {
+            0 => 32,
+            _ => le::<2>(&sb, 0xfe)?.max(32),
+        };
+
+        if block_size > 65536 || blocks_per_group == 0 || inodes_per_group == 0 || inode_size < 128
+        {
+            return None;
+        }
+
+        Some(Ext {
+            volume,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            nr_groups: nr_blocks.div_ceil(blocks_per_group),
+            descriptors: (first_data_block as u64 + 1) * block_size as u64,
+            descriptor_size,
+            has_filetype: incompat & INCOMPAT_FILETYPE != 0,
+        })
+    }
+
+    fn read_block(&self, block: u64) -> Option<Vec<u8>> {
+        self.volume
+            .read(block.checked_mul(self.block_size as u64)?, self.block_size)
+    }
+
+    fn inode(&self, number: usize) -> Option<Inode> {
+        let group = number.checked_sub(1)? / self.inodes_per_group;
+        let index = (number - 1) % self.inodes_per_group;
+        if group >= self.nr_groups {
+            return None;
+        }
+
+        let descriptor = self.volume.read(
+            self.descriptors + group as u64 * self.descriptor_size as u64,
+            self.descriptor_size,
+        )?;
+        let mut table = le::<4>(&descriptor, 0x8)? as u64;
+        if self.descriptor_size >= 64 {
+            table |= (le::<4>(&descriptor, 0x28)? as u64) << 32;
+        }
+
+        let offset = table
+            .checked_mul(self.block_size as u64)?
+            .checked_add(index as u64 * self.inode_size as u64)?;
+        let inode = self.volume.read(offset, 128)?;
+
+        Some(Inode {
+            mode: le::<2>(&inode, 0x0)?,
+            size: le::<4>(&inode, 0x4)? as u64 | (le::<4>(&inode, 0x6c)? as u64) << 32,
+            flags: le::<4>(&inode, 0x20)?,
+            blocks: inode[0x28..0x28 + 60].to_vec(),
+        })
+    }
+
+    /// Adds the data blocks of the extent tree node `node` to `runs`, as block numbers.
+    fn extent_runs(
+        &self,
+        node: &[u8],
+        depth: usize,
+        runs: &mut Vec<(usize, u64, usize)>,
+    ) -> Option<()> {
+        if le::<2>(node, 0)? != EXTENT_MAGIC || depth > MAX_EXTENT_DEPTH {
+            return None;
+        }
+
+        let nr_entries = le::<2>(node, 2)?;
+        let node_depth = le::<2>(node, 6)?;
+
+        for i in 0..nr_entries {
+            let entry = node.get(12 + i * 12..24 + i * 12)?;
+
+            if node_depth == 0 {
+                let len = le::<2>(entry, 4)?;
+                if len > MAX_INIT_EXTENT_LEN {
+                    continue;
+                }
+
+                let start = le::<4>(entry, 8)? as u64 | (le::<2>(entry, 6)? as u64) << 32;
+                runs.push((le::<4>(entry, 0)?, start, len));
+            } else {
+                let leaf = le::<4>(entry, 4)? as u64 | (le::<2>(entry, 8)? as u64) << 32;
+                self.extent_runs(&self.read_block(leaf)?, depth + 1, runs)?;
+            }
+
+            if runs.len() > MAX_CHAIN {
+                return None;
+            }
+        }
+
+        Some(())
+    }
+
+    /// Adds the data blocks reached through `pointers` with `level` levels of indirection to
+    /// `runs`, starting at file block `*next`.
+    fn mapped_runs(
+        &self,
+        pointers: &[u8],
+        level: usize,
+        next: &mut usize,
+        last: usize,
+        runs: &mut Vec<(usize, u64, usize)>,
+    ) -> Option<()> {
+        let per_block = (self.block_size / 4).pow(level as u32);
+
+        for pointer in pointers.chunks_exact(4) {
+            if *next >= last {
+                break;
+            }
+
+            let block = le::<4>(pointer, 0)? as u64;
+            if block == 0 {
+                // A hole.
+                *next += per_block;
+            } else if level == 0 {
+                runs.push((*next, block, 1));
+                *next += 1;
+            } else {
+                self.mapped_runs(&self.read_block(block)?, level - 1, next, last, runs)?;
+            }
+        }
+
+        Some(())
+    }
+
+    /// Returns the contents of a directory or file, in the form of its layout.
+    fn inode_layout(&self, inode: &Inode) -> Option<Layout> {
+        if inode.flags & INODE_FLAG_INLINE_DATA != 0 || inode.size > MAX_FILE_SIZE as u64 {
+            return None;
+        }
+        let size = inode.size as usize;
+
+        let mut runs = Vec::new();
+        if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            self.extent_runs(&inode.blocks, 0, &mut runs)?;
+        } else {
+            let last = size.div_ceil(self.block_size);
+            let mut next = 0;
+            let (direct, indirect) = inode.blocks.split_at(DIRECT_BLOCKS * 4);
+
+            self.mapped_runs(direct, 0, &mut next, last, &mut runs)?;
+            for (level, pointer) in indirect.chunks_exact(4).enumerate() {
+                self.mapped_runs(pointer, level + 1, &mut next, last, &mut runs)?;
+            }
+        }
+
+        Some(Layout {
+            size,
+            runs: runs
+                .into_iter()
+                .map(|(file_block, block, len)| {
+                    Some((
+                        file_block.checked_mul(self.block_size)?,
+                        block.checked_mul(self.block_size as u64)?,
+                        len * self.block_size,
+                    ))
+                })
+                .collect::<Option<_>>()?,
+        })
+    }
+}
+
+impl<D: BlockDevice> Filesystem for Ext<'_, '_, D> {
+    fn root(&self) -> usize {
+        ROOT_INODE
+    }
+
+    fn read_dir(&self, dir: usize) -> Option<Vec<DirEntry>> {
+        let inode = self.inode(dir)?;
+        if inode.mode & MODE_TYPE_MASK != MODE_DIRECTORY {
+            return None;
+        }
+
+        let mut entries = Vec::new();
+        for (_, offset, len) in self.inode_layout(&inode)?.runs {
+            let data = self.volume.read(offset, len)?;
+
+            for block in data.chunks_exact(self.block_size) {
+                let mut pos = 0;
+                while pos + 8 <= block.len() {
+                    let number = le::<4>(block, pos)?;
+                    let rec_len = le::<2>(block, pos + 4)?;
+                    let name_len = match self.has_filetype {
+                        true => le::<1>(block, pos + 6)?,
+                        false => le::<2>(block, pos + 6)?,
+                    };
+                    if rec_len < 8 {
+                        break;
+                    }
+
+                    // Unused entries, including the tail holding the block's checksum, have no
+                    // inode.
+                    if number != 0 {
+                        if let Some(name) = block.get(pos + 8..pos + 8 + name_len) {
+                            let is_dir = self
+                                .inode(number)
+                                .is_some_and(|i| i.mode & MODE_TYPE_MASK == MODE_DIRECTORY);
+
+                            entries.push(DirEntry {
+                                name: String::from_utf8_lossy(name).to_string(),
+                                id: number,
+                                is_dir,
+                                size: 0,
+                            });
+                        }
+                    }
+
+                    pos += rec_len;
+                }
+            }
+        }
+
+        Some(entries)
+    }
+
+    fn layout(&self, file: &DirEntry) -> Option<Layout> {
+        let inode = self.inode(file.id)?;
+        if inode.mode & MODE_TYPE_MASK != MODE_REGULAR {
+            return None;
+        }
+
+        self.inode_layout(&inode)
+    }
+}
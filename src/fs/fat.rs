@@ -0,0 +1,301 @@
+use std::collections::HashSet;
+
+use crate::image::BlockDevice;
+
+use super::{
+    files::{DirEntry, Filesystem, Layout, Volume, MAX_CHAIN, MAX_FILE_SIZE},
+    le,
+};
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// Marks an entry that is free, as are all the entries after it.
+const END_OF_DIR: u8 = 0x00;
+const DELETED: u8 = 0xe5;
+
+/// Identifies the fixed root directory of FAT12 and FAT16, which has no clusters.
+const FIXED_ROOT: usize = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+pub(super) struct Fat<'a, 'b, D: BlockDevice> {
+    volume: &'a Volume<'b, D>,
+    fat_type: FatType,
+    cluster_size: usize,
+    /// Offset of the first FAT.
+    fat_offset: u64,
+    /// Offset and size of the fixed root directory of FAT12 and FAT16.
+    root_offset: u64,
+    root_size: usize,
+    /// First cluster of the root directory of FAT32.
+    root_cluster: usize,
+    /// Offset of cluster 2, the first data cluster.
+    data_offset: u64,
+    nr_clusters: usize,
+}
+
+/// Checksum of an 8.3 name, which long name entries repeat to tie them to it.
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+fn short_name(entry: &[u8]) -> String {
+    let base = String::from_utf8_lossy(&entry[0..8]).trim_end().to_string();
+    let ext = String::from_utf8_lossy(&entry[8..11])
+        .trim_end()
+        .to_string();
+
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+/// Characters of a long name entry, in the three fields that hold them.
+fn long_name_chars(entry: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    [1..11, 14..26, 28..32]
+        .into_iter()
+        .flat_map(|range| entry[range].chunks_exact(2))
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+}
+
+impl<'a, 'b, D: BlockDevice> Fat<'a, 'b, D> {
+    pub(super) fn open(volume: &'a Volume<'b, D>) -> Option<Self> {
+        let bs = volume.read(0, 512)?;
+        if bs[0x1fe..0x200] != [0x55, 0xaa]
+            || (&bs[0x36..0x39] != b"FAT" && &bs[0x52..0x57] != b"FAT32")
+        {
+            return None;
+        }
+
+        let sector_size = le::<2>(&bs, 0x0b)?;
+        let sectors_per_cluster = le::<1>(&bs, 0x0d)?;
+        let reserved = le::<2>(&bs, 0x0e)?;
+        let nr_fats = le::<1>(&bs, 0x10)?;
+        let root_entries = le::<2>(&bs, 0x11)?;
+        let nr_sectors = match le::<2>(&bs, 0x13)? {
+            0 => le::<4>(&bs, 0x20)?,
+            n => n,
+        };
+        let fat_sectors = match le::<2>(&bs, 0x16)? {
+            0 => le::<4>(&bs, 0x24)?,
+            n => n,
+        };
+
+        if !sector_size.is_power_of_two() || sector_size < 512 || sectors_per_cluster == 0 {
+            return None;
+        }
+
+        let root_sectors = (root_entries * DIR_ENTRY_SIZE).div_ceil(sector_size);
+        let root_sector = reserved.checked_add(nr_fats.checked_mul(fat_sectors)?)?;
+        let data_sector = root_sector.checked_add(root_sectors)?;
+        let nr_clusters = nr_sectors.checked_sub(data_sector)? / sectors_per_cluster;
+
+        // The type is determined by the number of clusters alone.
+        let fat_type = match nr_clusters {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+
+        Some(Fat {
+            volume,
+            fat_type,
+            cluster_size: sectors_per_cluster.checked_mul(sector_size)?,
+            fat_offset: reserved as u64 * sector_size as u64,
+            root_offset: root_sector as u64 * sector_size as u64,
+            root_size: root_sectors * sector_size,
+            root_cluster: le::<4>(&bs, 0x2c)?,
+            data_offset: data_sector as u64 * sector_size as u64,
+            nr_clusters,
+        })
+    }
+
+    /// Returns the cluster after `cluster` in its chain, or `None` at the end of the chain.
+    fn next_cluster(&self, cluster: usize) -> Option<usize> {
+        let next = match self.fat_type {
+            FatType::Fat12 => {
+                let offset = (cluster + cluster / 2) as u64;
+                let value = le::<2>(&self.volume.read(self.fat_offset + offset, 2)?, 0)?;
+                if cluster.is_multiple_of(2) {
+                    value & 0xfff
+                } else {
+                    value >> 4
+                }
+            }
+            FatType::Fat16 => le::<2>(
+                &self.volume.read(self.fat_offset + cluster as u64 * 2, 2)?,
+                0,
+            )?,
+            FatType::Fat32 => {
+                le::<4>(
+                    &self.volume.read(self.fat_offset + cluster as u64 * 4, 4)?,
+                    0,
+                )? & 0x0fff_ffff
+            }
+        };
+
+        self.is_data_cluster(next).then_some(next)
+    }
+
+    /// Returns true for clusters that hold data, rather than marking free, bad or last clusters.
+    fn is_data_cluster(&self, cluster: usize) -> bool {
+        (2..self.nr_clusters + 2).contains(&cluster)
+    }
+
+    /// Returns the chain of clusters starting at `first`, up to the clusters holding `max_size`
+    /// bytes. A chain that loops back on itself ends before the first repeated cluster.
+    fn chain(&self, first: usize, max_size: usize) -> Vec<usize> {
+        let max_len = max_size
+            .div_ceil(self.cluster_size)
+            .min(self.nr_clusters)
+            .min(MAX_CHAIN);
+        let mut clusters = Vec::new();
+        let mut visited = HashSet::new();
+        let mut cluster = Some(first).filter(|&c| self.is_data_cluster(c));
+
+        while let Some(c) = cluster {
+            if clusters.len() >= max_len || !visited.insert(c) {
+                break;
+            }
+
+            clusters.push(c);
+            cluster = self.next_cluster(c);
+        }
+
+        clusters
+    }
+
+    fn cluster_offset(&self, cluster: usize) -> u64 {
+        self.data_offset + (cluster - 2) as u64 * self.cluster_size as u64
+    }
+}
+
+impl<D: BlockDevice> Filesystem for Fat<'_, '_, D> {
+    fn names_match(&self, name: &str, pattern: &str) -> bool {
+        name.eq_ignore_ascii_case(pattern)
+    }
+
+    fn root(&self) -> usize {
+        match self.fat_type {
+            FatType::Fat32 => self.root_cluster,
+            _ => FIXED_ROOT,
+        }
+    }
+
+    fn read_dir(&self, dir: usize) -> Option<Vec<DirEntry>> {
+        let data = if dir == FIXED_ROOT {
+            self.volume.read(self.root_offset, self.root_size)?
+        } else {
+            // Directories are bounded like the files looked for.
+            let mut data = Vec::new();
+            for cluster in self.chain(dir, MAX_FILE_SIZE) {
+                data.extend(
+                    self.volume
+                        .read(self.cluster_offset(cluster), self.cluster_size)?,
+                );
+            }
+            data
+        };
+
+        let mut entries = Vec::new();
+        // Parts of a long name, and the checksum of the short name they belong to.
+        let mut long_name: Vec<(u8, Vec<u16>)> = Vec::new();
+        let mut long_name_checksum = None;
+
+        for entry in data.chunks_exact(DIR_ENTRY_SIZE) {
+            let attr = entry[11];
+
+            match entry[0] {
+                END_OF_DIR => break,
+                DELETED => {
+                    long_name.clear();
+                    continue;
+                }
+                _ => {}
+            }
+
+            if attr & 0x3f == ATTR_LONG_NAME {
+                long_name.push((entry[0] & 0x1f, long_name_chars(entry).collect()));
+                long_name_checksum = Some(entry[13]);
+                continue;
+            }
+
+            let parts = std::mem::take(&mut long_name);
+            if attr & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+
+            let name = if !parts.is_empty()
+                && long_name_checksum == Some(short_name_checksum(&entry[0..11]))
+            {
+                let mut parts = parts;
+                parts.sort_by_key(|(order, _)| *order);
+                let chars: Vec<u16> = parts
+                    .into_iter()
+                    .flat_map(|(_, chars)| chars)
+                    .take_while(|&c| c != 0 && c != 0xffff)
+                    .collect();
+                String::from_utf16_lossy(&chars)
+            } else {
+                short_name(entry)
+            };
+
+            let is_dir = attr & ATTR_DIRECTORY != 0;
+            let first_cluster = (le::<2>(entry, 20)? << 16) | le::<2>(entry, 26)?;
+            entries.push(DirEntry {
+                name,
+                // A directory starting at cluster 0 is the root directory.
+                id: if is_dir && first_cluster == 0 {
+                    self.root()
+                } else {
+                    first_cluster
+                },
+                is_dir,
+                size: le::<4>(entry, 28)?,
+            });
+        }
+
+        Some(entries)
+    }
+
+    fn layout(&self, file: &DirEntry) -> Option<Layout> {
+        if file.size > MAX_FILE_SIZE {
+            return None;
+        }
+
+        let mut runs: Vec<(usize, u64, usize)> = Vec::new();
+        let mut file_offset = 0;
+
+        for cluster in self.chain(file.id, file.size) {
+            if file_offset >= file.size {
+                break;
+            }
+
+            let offset = self.cluster_offset(cluster);
+            match runs.last_mut() {
+                Some((_, start, len)) if *start + *len as u64 == offset => {
+                    *len += self.cluster_size
+                }
+                _ => runs.push((file_offset, offset, self.cluster_size)),
+            }
+            file_offset += self.cluster_size;
+        }
+
+        Some(Layout {
+            size: file.size,
+            runs,
+        })
+    }
+}
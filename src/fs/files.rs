@@ -0,0 +1,165 @@
+//! Minimal read-only access to the files of FAT and ext filesystems, enough to find a few
+//! configuration files by path and locate their contents within the image.
+
+use crate::image::{BlockDevice, Image};
+
+use super::{ext::Ext, fat::Fat};
+
+/// Files larger than this are skipped, as the configuration files looked for are small.
+pub const MAX_FILE_SIZE: usize = 1024 * 1024;
+
+/// Longest chain of directories or blocks followed, so that loops in a corrupt filesystem end.
+pub(super) const MAX_CHAIN: usize = 1 << 20;
+
+/// A filesystem within an image, read through bounds-checked accesses relative to its start.
+pub(super) struct Volume<'a, D: BlockDevice> {
+    image: &'a Image<D>,
+    start: u64,
+    len: u64,
+}
+
+impl<D: BlockDevice> Volume<'_, D> {
+    /// Returns the offset within the image of `offset` within the filesystem.
+    pub(super) fn image_offset(&self, offset: u64) -> u64 {
+        self.start + offset
+    }
+
+    pub(super) fn read(&self, offset: u64, len: usize) -> Option<Vec<u8>> {
+        if offset.checked_add(len as u64)? > self.len {
+            return None;
+        }
+
+        self.image
+            .get_bytes(self.start + offset, len)
+            .ok()
+            .map(|b| b.into_owned())
+    }
+}
+
+/// An entry of a directory.
+pub(super) struct DirEntry {
+    pub name: String,
+    /// Identifies the file within its filesystem, e.g. by inode number.
+    pub id: usize,
+    pub is_dir: bool,
+    /// Size of the file, for filesystems that record it in the directory rather than with the
+    /// file itself.
+    pub size: usize,
+}
+
+/// Where the contents of a file are stored, as `(file offset, volume offset, len)` runs in file
+/// order. Parts of the file not covered by a run are holes that read as zeros.
+pub(super) struct Layout {
+    pub size: usize,
+    pub runs: Vec<(usize, u64, usize)>,
+}
+
+pub(super) trait Filesystem {
+    /// Returns the name matching used by the filesystem, which is case-insensitive for FAT.
+    fn names_match(&self, name: &str, pattern: &str) -> bool {
+        name == pattern
+    }
+
+    fn root(&self) -> usize;
+
+    fn read_dir(&self, dir: usize) -> Option<Vec<DirEntry>>;
+
+    fn layout(&self, file: &DirEntry) -> Option<Layout>;
+}
+
+/// A file found by [`find_files`], with the contents read from the image.
+pub struct FoundFile {
+    /// Path within the filesystem.
+    pub path: String,
+    pub contents: Vec<u8>,
+    /// Runs of `(file offset, image offset, len)`, each stored contiguously in the image.
+    pub runs: Vec<(usize, u64, usize)>,
+}
+
+fn find<D: BlockDevice>(
+    fs: &impl Filesystem,
+    volume: &Volume<'_, D>,
+    dir: usize,
+    path: &str,
+    pattern: &[&str],
+    found: &mut Vec<FoundFile>,
+) {
+    let Some((first, rest)) = pattern.split_first() else {
+        return;
+    };
+    let Some(entries) = fs.read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries {
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        if *first != "*" && !fs.names_match(&entry.name, first) {
+            continue;
+        }
+
+        let entry_path = format!("{}/{}", path, entry.name);
+        match (entry.is_dir, rest.is_empty()) {
+            (true, false) => find(fs, volume, entry.id, &entry_path, rest, found),
+            (false, true) => {
+                if let Some(file) = read_file(fs, volume, &entry, entry_path) {
+                    found.push(file);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn read_file<D: BlockDevice>(
+    fs: &impl Filesystem,
+    volume: &Volume<'_, D>,
+    entry: &DirEntry,
+    path: String,
+) -> Option<FoundFile> {
+    let layout = fs.layout(entry)?;
+    if layout.size > MAX_FILE_SIZE {
+        return None;
+    }
+
+    let mut contents = vec![0; layout.size];
+    let mut runs = Vec::new();
+    for (file_offset, offset, len) in layout.runs {
+        let len = len.min(layout.size.checked_sub(file_offset)?);
+        contents[file_offset..file_offset + len].copy_from_slice(&volume.read(offset, len)?);
+        runs.push((file_offset, volume.image_offset(offset), len));
+    }
+
+    Some(FoundFile {
+        path,
+        contents,
+        runs,
+    })
+}
+
+/// Finds the files matching `patterns` in the FAT or ext filesystem occupying `len` bytes at
+/// `start` in the image. Each pattern is a list of path components, where `*` matches any name.
+///
+/// Returns nothing for other filesystems, and skips anything that cannot be read.
+pub fn find_files(
+    image: &Image<impl BlockDevice>,
+    start: u64,
+    len: u64,
+    patterns: &[&[&str]],
+) -> Vec<FoundFile> {
+    let volume = Volume { image, start, len };
+    let mut found = Vec::new();
+
+    if let Some(fs) = Ext::open(&volume) {
+        for pattern in patterns {
+            find(&fs, &volume, fs.root(), "", pattern, &mut found);
+        }
+    } else if let Some(fs) = Fat::open(&volume) {
+        for pattern in patterns {
+            find(&fs, &volume, fs.root(), "", pattern, &mut found);
+        }
+    }
+
+    found
+}
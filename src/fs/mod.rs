@@ -0,0 +1,21 @@
+mod ext;
+mod fat;
+pub mod files;
+pub mod probe;
+pub mod signatures;
+pub mod size;
+
+/// Amount of data at the start of a filesystem that holds everything needed to identify it.
+pub const PROBE_SIZE: usize = 128 * 1024;
+
+/// Reads a little-endian integer of `N` bytes at `offset`, if it lies within `bytes`.
+fn le<const N: usize>(bytes: &[u8], offset: usize) -> Option<usize> {
+    let field: [u8; N] = bytes.get(offset..offset + N)?.try_into().ok()?;
+
+    Some(
+        field
+            .iter()
+            .rev()
+            .fold(0usize, |acc, &b| (acc << 8) | b as usize),
+    )
+}
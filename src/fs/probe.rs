@@ -0,0 +1,348 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::image::{BlockDevice, Image};
+
+use super::{le, PROBE_SIZE};
+
+const BLOCK_SIZE: usize = 512;
+
+/// The type, label and UUID of a filesystem or container, named as blkid names them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FsInfo {
+    pub fstype: &'static str,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+}
+
+impl FsInfo {
+    fn new(fstype: &'static str, label: Option<String>, uuid: Option<String>) -> Self {
+        FsInfo {
+            fstype,
+            label,
+            uuid,
+        }
+    }
+}
+
+impl Display for FsInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Filesystem: {}", self.fstype))?;
+
+        if let Some(label) = &self.label {
+            f.write_fmt(format_args!(", Label: {}", label))?;
+        }
+
+        if let Some(uuid) = &self.uuid {
+            f.write_fmt(format_args!(", UUID: {}", uuid))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn has_magic(fs: &[u8], offset: usize, magic: &[u8]) -> bool {
+    fs.get(offset..offset + magic.len()) == Some(magic)
+}
+
+/// Decodes a fixed-size label field, dropping NUL and space padding.
+fn label(fs: &[u8], offset: usize, len: usize) -> Option<String> {
+    let raw = fs.get(offset..offset + len)?;
+    let raw = raw.split(|&b| b == 0).next().unwrap_or(raw);
+
+    Some(String::from_utf8_lossy(raw).trim_end().to_string()).filter(|l| !l.is_empty())
+}
+
+/// Formats 16 bytes as a UUID in the usual 8-4-4-4-12 form.
+fn uuid(fs: &[u8], offset: usize) -> Option<String> {
+    let b = fs.get(offset..offset + 16)?;
+    if b.iter().all(|&b| b == 0) {
+        return None;
+    }
+
+    let hex = |r: std::ops::Range<usize>| {
+        b[r].iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    };
+
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        hex(0..4),
+        hex(4..6),
+        hex(6..8),
+        hex(8..10),
+        hex(10..16)
+    ))
+}
+
+/// Formats a FAT or exFAT volume serial number as blkid does, e.g. `1A2B-3C4D`.
+fn volume_serial(fs: &[u8], offset: usize) -> Option<String> {
+    let serial = le::<4>(fs, offset)?;
+
+    Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xffff))
+}
+
+fn probe_ext(fs: &[u8]) -> Option<FsInfo> {
+    const SB: usize = 1024;
+    const COMPAT_HAS_JOURNAL: usize = 0x4;
+    // Features unknown to ext3: extents, 64bit, flex_bg and others.
+    const INCOMPAT_EXT4: usize = 0x40 | 0x80 | 0x200 | 0x400 | 0x1000 | 0x8000 | 0x10000;
+    const RO_COMPAT_EXT4: usize = 0x8 | 0x10 | 0x20 | 0x40 | 0x400;
+
+    if !has_magic(fs, SB + 0x38, &[0x53, 0xef]) {
+        return None;
+    }
+
+    let compat = le::<4>(fs, SB + 0x5c)?;
+    let incompat = le::<4>(fs, SB + 0x60)?;
+    let ro_compat = le::<4>(fs, SB + 0x64)?;
+
+    let fstype = if incompat & INCOMPAT_EXT4 != 0 || ro_compat & RO_COMPAT_EXT4 != 0 {
+        "ext4"
+    } else if compat & COMPAT_HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    };
+
+    Some(FsInfo::new(
+        fstype,
+        label(fs, SB + 0x78, 16),
+        uuid(fs, SB + 0x68),
+    ))
+}
+
+fn probe_fat(fs: &[u8]) -> Option<FsInfo> {
+    if !has_magic(fs, 0x1fe, &[0x55, 0xaa]) {
+        return None;
+    }
+
+    // FAT32 moves the extended boot record after its larger BPB.
+    let ebr = if has_magic(fs, 0x52, b"FAT32") {
+        0x40
+    } else if has_magic(fs, 0x36, b"FAT") {
+        0x24
+    } else {
+        return None;
+    };
+
+    let label = label(fs, ebr + 0x7, 11).filter(|l| l != "NO NAME");
+
+    Some(FsInfo::new("vfat", label, volume_serial(fs, ebr + 0x3)))
+}
+
+fn probe_exfat(fs: &[u8]) -> Option<FsInfo> {
+    if !has_magic(fs, 0x3, b"EXFAT   ") {
+        return None;
+    }
+
+    // The label lives in the root directory rather than the boot sector.
+    Some(FsInfo::new("exfat", None, volume_serial(fs, 0x64)))
+}
+
+fn probe_ntfs(fs: &[u8]) -> Option<FsInfo> {
+    if !has_magic(fs, 0x3, b"NTFS    ") {
+        return None;
+    }
+
+    let serial = le::<8>(fs, 0x48)?;
+
+    Some(FsInfo::new("ntfs", None, Some(format!("{:016X}", serial))))
+}
+
+fn probe_xfs(fs: &[u8]) -> Option<FsInfo> {
+    if !has_magic(fs, 0, b"XFSB") {
+        return None;
+    }
+
+    Some(FsInfo::new("xfs", label(fs, 108, 12), uuid(fs, 32)))
+}
+
+fn probe_btrfs(fs: &[u8]) -> Option<FsInfo> {
+    const SB: usize = 0x10000;
+
+    if !has_magic(fs, SB + 0x40, b"_BHRfS_M") {
+        return None;
+    }
+
+    Some(FsInfo::new(
+        "btrfs",
+        label(fs, SB + 0x12b, 256),
+        uuid(fs, SB + 0x20),
+    ))
+}
+
+fn probe_squashfs(fs: &[u8]) -> Option<FsInfo> {
+    if !has_magic(fs, 0, b"hsqs") {
+        return None;
+    }
+
+    Some(FsInfo::new("squashfs", None, None))
+}
+
+fn probe_iso9660(fs: &[u8]) -> Option<FsInfo> {
+    const PVD: usize = 0x8000;
+
+    if !has_magic(fs, PVD, b"\x01CD001") {
+        return None;
+    }
+
+    // blkid derives the UUID from the volume creation time, YYYYMMDDHHMMSScc.
+    let created = fs.get(PVD + 813..PVD + 829)?;
+    let uuid = Some(created)
+        .filter(|c| c.iter().all(u8::is_ascii_digit) && c.iter().any(|&c| c != b'0'))
+        .map(|c| {
+            let s = String::from_utf8_lossy(c);
+            format!(
+                "{}-{}-{}-{}-{}-{}-{}",
+                &s[0..4],
+                &s[4..6],
+                &s[6..8],
+                &s[8..10],
+                &s[10..12],
+                &s[12..14],
+                &s[14..16]
+            )
+        });
+
+    Some(FsInfo::new("iso9660", label(fs, PVD + 40, 32), uuid))
+}
+
+fn probe_swap(fs: &[u8]) -> Option<FsInfo> {
+    const HEADER: usize = 1024;
+
+    // The signature sits at the end of the first page, whose size depends on the architecture.
+    [0x1000, 0x2000, 0x4000, 0x10000]
+        .iter()
+        .find(|&&page| has_magic(fs, page - 10, b"SWAPSPACE2"))?;
+
+    Some(FsInfo::new(
+        "swap",
+        label(fs, HEADER + 28, 16),
+        uuid(fs, HEADER + 12),
+    ))
+}
+
+fn probe_luks(fs: &[u8]) -> Option<FsInfo> {
+    if !has_magic(fs, 0, b"LUKS\xba\xbe") {
+        return None;
+    }
+
+    // Only LUKS2 has a label; LUKS1 keeps the cipher name there.
+    let label = match fs.get(6..8)? {
+        [0, 2] => label(fs, 24, 48),
+        _ => None,
+    };
+
+    Some(FsInfo::new("crypto_LUKS", label, self::label(fs, 168, 40)))
+}
+
+fn probe_lvm2(fs: &[u8]) -> Option<FsInfo> {
+    let header = (0..4)
+        .map(|sector| sector * BLOCK_SIZE)
+        .find(|&h| has_magic(fs, h, b"LABELONE") && has_magic(fs, h + 24, b"LVM2 001"))?;
+
+    // The PV UUID is stored as 32 characters without separators.
+    let id = fs.get(header + 32..header + 64)?;
+    let id = String::from_utf8_lossy(id);
+    let uuid = [0..6, 6..10, 10..14, 14..18, 18..22, 22..26, 26..32]
+        .into_iter()
+        .map(|r| id.get(r))
+        .collect::<Option<Vec<_>>>()
+        .map(|parts| parts.join("-"));
+
+    Some(FsInfo::new("LVM2_member", None, uuid))
+}
+
+/// Probes for md RAID superblocks, using `head` and `tail`, the first and last bytes of a
+/// partition of `size` bytes.
+fn probe_md(head: &[u8], tail: &[u8], size: u64) -> Option<FsInfo> {
+    const MAGIC: [u8; 4] = 0xa92b4efc_u32.to_le_bytes();
+
+    let v1 = |fs: &[u8], sb: usize| {
+        (has_magic(fs, sb, &MAGIC) && le::<4>(fs, sb + 4) == Some(1)).then(|| {
+            FsInfo::new(
+                "linux_raid_member",
+                label(fs, sb + 32, 32),
+                uuid(fs, sb + 16),
+            )
+        })
+    };
+
+    // Version 1.1 and 1.2 superblocks are at the start, 1.0 is near the end.
+    if let Some(fs) = v1(head, 0).or_else(|| v1(head, 0x1000)) {
+        return Some(fs);
+    }
+
+    let block_size = BLOCK_SIZE as u64;
+    let tail_start = size - tail.len() as u64;
+    let end_1_0 = ((size / block_size).checked_sub(16)? & !7) * block_size;
+    if let Some(fs) = end_1_0
+        .checked_sub(tail_start)
+        .and_then(|sb| v1(tail, usize::try_from(sb).ok()?))
+    {
+        return Some(fs);
+    }
+
+    // Version 0.90 superblocks are in the last 64 KiB aligned block.
+    let sb = (size & !0xffff)
+        .checked_sub(0x10000)?
+        .checked_sub(tail_start)?;
+    let sb = usize::try_from(sb).ok()?;
+    if has_magic(tail, sb, &MAGIC) && le::<4>(tail, sb + 4) == Some(0) {
+        let words = [5, 13, 14, 15].map(|w| le::<4>(tail, sb + w * 4).unwrap_or(0) as u32);
+        let id: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+
+        return Some(FsInfo::new("linux_raid_member", None, uuid(&id, 0)));
+    }
+
+    None
+}
+
+/// Identifies the filesystem or container in a partition of `size` bytes, from `head` and `tail`,
+/// its first and last bytes.
+pub fn probe(head: &[u8], tail: &[u8], size: u64) -> Option<FsInfo> {
+    let probes = [
+        probe_iso9660,
+        probe_exfat,
+        probe_ntfs,
+        probe_fat,
+        probe_ext,
+        probe_xfs,
+        probe_btrfs,
+        probe_squashfs,
+        probe_swap,
+    ];
+
+    // Containers first, as they may wrap something that looks like a filesystem.
+    probe_luks(head)
+        .or_else(|| probe_lvm2(head))
+        .or_else(|| probe_md(head, tail, size))
+        .or_else(|| probes.iter().find_map(|probe| probe(head)))
+}
+
+/// Probes the partition starting at `start` with `nr_blocks` blocks, ignoring any part of it
+/// beyond the end of the image.
+pub fn probe_partition(
+    image: &Image<impl BlockDevice>,
+    start: u64,
+    nr_blocks: u64,
+) -> Option<FsInfo> {
+    let block_size = BLOCK_SIZE as u64;
+    let image_blocks = image.len() / block_size;
+    if start >= image_blocks {
+        return None;
+    }
+
+    let base = start * block_size;
+    let size = nr_blocks.min(image_blocks - start) * block_size;
+    let sample = (PROBE_SIZE as u64).min(size) as usize;
+
+    probe(
+        &image.get_bytes(base, sample).ok()?,
+        &image.get_bytes(base + size - sample as u64, sample).ok()?,
+        size,
+    )
+}
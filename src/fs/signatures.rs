@@ -0,0 +1,161 @@
+use std::fmt::Display;
+
+use crate::image::{BlockDevice, Image, ImageError, WritableBlockDevice};
+
+/// Bytes identifying a filesystem or container format at a fixed offset from its start.
+struct Magic {
+    name: &'static str,
+    offset: usize,
+    bytes: &'static [u8],
+    /// Further signatures belonging to the same format, erased along with the magic if present.
+    extra: &'static [(usize, &'static [u8])],
+}
+
+const BOOT_SIGNATURE: (usize, &[u8]) = (0x1fe, &[0x55, 0xaa]);
+
+/// LUKS2 keeps a second copy of its header at one of these offsets.
+const LUKS2_SECONDARY: &[(usize, &[u8])] = &[
+    (0x4000, b"SKUL\xba\xbe"),
+    (0x8000, b"SKUL\xba\xbe"),
+    (0x10000, b"SKUL\xba\xbe"),
+    (0x20000, b"SKUL\xba\xbe"),
+    (0x40000, b"SKUL\xba\xbe"),
+    (0x80000, b"SKUL\xba\xbe"),
+    (0x100000, b"SKUL\xba\xbe"),
+    (0x200000, b"SKUL\xba\xbe"),
+    (0x400000, b"SKUL\xba\xbe"),
+];
+
+const MAGICS: &[Magic] = &[
+    Magic {
+        name: "vfat",
+        offset: 0x36,
+        bytes: b"FAT12   ",
+        extra: &[BOOT_SIGNATURE],
+    },
+    Magic {
+        name: "vfat",
+        offset: 0x36,
+        bytes: b"FAT16   ",
+        extra: &[BOOT_SIGNATURE],
+    },
+    Magic {
+        name: "vfat",
+        offset: 0x52,
+        bytes: b"FAT32   ",
+        extra: &[BOOT_SIGNATURE],
+    },
+    Magic {
+        name: "ext",
+        offset: 0x438,
+        bytes: &[0x53, 0xef],
+        extra: &[],
+    },
+    Magic {
+        name: "iso9660",
+        offset: 0x8001,
+        bytes: b"CD001",
+        extra: &[],
+    },
+    Magic {
+        name: "swap",
+        offset: 0xff6,
+        bytes: b"SWAPSPACE2",
+        extra: &[],
+    },
+    Magic {
+        name: "swap",
+        offset: 0xff6,
+        bytes: b"SWAP-SPACE",
+        extra: &[],
+    },
+    Magic {
+        name: "swap",
+        offset: 0xfff6,
+        bytes: b"SWAPSPACE2",
+        extra: &[],
+    },
+    Magic {
+        name: "crypto_LUKS",
+        offset: 0,
+        bytes: b"LUKS\xba\xbe",
+        extra: LUKS2_SECONDARY,
+    },
+];
+
+/// A signature found on the image, as an absolute byte range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub offset: u64,
+    pub len: usize,
+}
+
+impl Signature {
+    pub fn new(name: String, offset: u64, len: usize) -> Self {
+        Signature { name, offset, len }
+    }
+
+    /// Overwrites the signature with zeroes.
+    pub fn erase(&self, image: &mut Image<impl WritableBlockDevice>) -> Result<(), ImageError> {
+        image.write_bytes(self.offset, &vec![0; self.len])
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{:#010x} {:>2} bytes {}",
+            self.offset, self.len, self.name
+        ))
+    }
+}
+
+fn matches(
+    image: &Image<impl BlockDevice>,
+    start: u64,
+    end: u64,
+    offset: usize,
+    bytes: &[u8],
+) -> bool {
+    let offset = start + offset as u64;
+
+    offset + bytes.len() as u64 <= end
+        && image
+            .get_bytes(offset, bytes.len())
+            .is_ok_and(|b| *b == *bytes)
+}
+
+/// Finds the filesystem signatures of the filesystem starting at byte `start`, without reading
+/// past byte `end`.
+pub fn find_filesystem_signatures(
+    image: &Image<impl BlockDevice>,
+    start: u64,
+    end: u64,
+) -> Vec<Signature> {
+    let mut found = Vec::new();
+
+    for magic in MAGICS {
+        if !matches(image, start, end, magic.offset, magic.bytes) {
+            continue;
+        }
+
+        found.push(Signature::new(
+            String::from(magic.name),
+            start + magic.offset as u64,
+            magic.bytes.len(),
+        ));
+
+        for (offset, bytes) in magic.extra {
+            if matches(image, start, end, *offset, bytes) {
+                found.push(Signature::new(
+                    String::from(magic.name),
+                    start + *offset as u64,
+                    bytes.len(),
+                ));
+            }
+        }
+    }
+
+    found
+}
@@ -0,0 +1,56 @@
+use super::le;
+
+fn ext_size(fs: &[u8]) -> Option<u64> {
+    const SUPERBLOCK: usize = 1024;
+    const INCOMPAT_64BIT: usize = 0x80;
+
+    if fs.get(SUPERBLOCK + 0x38..SUPERBLOCK + 0x3a)? != [0x53, 0xef] {
+        return None;
+    }
+
+    let mut nr_blocks = le::<4>(fs, SUPERBLOCK + 0x4)? as u64;
+    if le::<4>(fs, SUPERBLOCK + 0x60)? & INCOMPAT_64BIT != 0 {
+        nr_blocks |= (le::<4>(fs, SUPERBLOCK + 0x150)? as u64) << 32;
+    }
+
+    let block_size = 1024u64.checked_shl(le::<4>(fs, SUPERBLOCK + 0x18)? as u32)?;
+
+    nr_blocks.checked_mul(block_size)
+}
+
+fn fat_size(fs: &[u8]) -> Option<u64> {
+    if fs.get(0x1fe..0x200)? != [0x55, 0xaa]
+        || (fs.get(0x36..0x39)? != b"FAT" && fs.get(0x52..0x57)? != b"FAT32")
+    {
+        return None;
+    }
+
+    let sector_size = le::<2>(fs, 0x0b)?;
+    let nr_sectors = match le::<2>(fs, 0x13)? {
+        0 => le::<4>(fs, 0x20)?,
+        n => n,
+    };
+
+    (nr_sectors as u64).checked_mul(sector_size as u64)
+}
+
+fn iso9660_size(fs: &[u8]) -> Option<u64> {
+    const PRIMARY_DESCRIPTOR: usize = 0x8000;
+
+    if fs.get(PRIMARY_DESCRIPTOR..PRIMARY_DESCRIPTOR + 6)? != b"\x01CD001" {
+        return None;
+    }
+
+    let nr_blocks = le::<4>(fs, PRIMARY_DESCRIPTOR + 80)?;
+    let block_size = le::<2>(fs, PRIMARY_DESCRIPTOR + 128)?;
+
+    (nr_blocks as u64).checked_mul(block_size as u64)
+}
+
+/// Returns the size in bytes of the ext, FAT or ISO9660 filesystem at the start of `fs`, as
+/// recorded in its superblock.
+pub fn filesystem_size(fs: &[u8]) -> Option<u64> {
+    ext_size(fs)
+        .or_else(|| fat_size(fs))
+        .or_else(|| iso9660_size(fs))
+}
@@ -0,0 +1,244 @@
+use std::{
+    ffi::c_void,
+    fs::{self, File},
+    io,
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        io::AsRawFd,
+    },
+    path::Path,
+};
+
+use nix::{
+    ioctl_none, ioctl_read, ioctl_read_bad, ioctl_write_ptr_bad, libc, request_code_none,
+    sys::stat::{major, makedev, minor},
+};
+
+use super::ImageError;
+
+const SECTOR_SIZE: u64 = 512;
+
+ioctl_none!(blkrrpart, 0x12, 95);
+ioctl_read_bad!(blksszget, request_code_none!(0x12, 104), libc::c_int);
+ioctl_write_ptr_bad!(blkpg, request_code_none!(0x12, 105), BlkpgIoctlArg);
+ioctl_read!(blkgetsize64, 0x12, 114, u64);
+
+const BLKPG_ADD_PARTITION: libc::c_int = 1;
+const BLKPG_DEL_PARTITION: libc::c_int = 2;
+const BLKPG_RESIZE_PARTITION: libc::c_int = 3;
+
+/// `struct blkpg_ioctl_arg` from `linux/blkpg.h`.
+#[repr(C)]
+struct BlkpgIoctlArg {
+    op: libc::c_int,
+    flags: libc::c_int,
+    datalen: libc::c_int,
+    data: *mut c_void,
+}
+
+/// `struct blkpg_partition` from `linux/blkpg.h`, with the start and length in bytes.
+#[repr(C)]
+struct BlkpgPartition {
+    start: i64,
+    length: i64,
+    pno: libc::c_int,
+    devname: [libc::c_char; 64],
+    volname: [libc::c_char; 64],
+}
+
+/// A partition as registered with the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelPartition {
+    /// Partition number, starting at 1.
+    pub number: usize,
+    /// First sector.
+    pub start: u64,
+    /// Number of sectors.
+    pub nr_sectors: u64,
+}
+
+pub fn is_block_device(file: &File) -> bool {
+    file.metadata()
+        .map(|m| m.file_type().is_block_device())
+        .unwrap_or(false)
+}
+
+/// Returns the size of a block device in bytes. Only devices with 512-byte logical sectors are
+/// supported, as the partition tables are laid out in 512-byte blocks.
+pub fn device_size(file: &File) -> Result<u64, ImageError> {
+    let fd = file.as_raw_fd();
+
+    let mut sector_size = 0;
+    unsafe { blksszget(fd, &mut sector_size) }.map_err(|_| ImageError::OpenError)?;
+    if sector_size as u64 != SECTOR_SIZE {
+        return Err(ImageError::UnsupportedSectorSize(sector_size as u32));
+    }
+
+    let mut size = 0;
+    unsafe { blkgetsize64(fd, &mut size) }.map_err(|_| ImageError::OpenError)?;
+
+    Ok(size)
+}
+
+/// Returns the sysfs directory of a block device.
+fn sysfs_dir(file: &File) -> io::Result<String> {
+    let rdev = file.metadata()?.rdev();
+
+    Ok(format!("/sys/dev/block/{}:{}", major(rdev), minor(rdev)))
+}
+
+fn read_sysfs_value(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Parses a device number in the `major:minor` form used by sysfs and mountinfo.
+fn parse_device_number(s: &str) -> Option<u64> {
+    let (major, minor) = s.trim().split_once(':')?;
+
+    Some(makedev(major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Returns the device numbers of a block device and of the partitions on it.
+fn device_numbers(file: &File) -> io::Result<Vec<u64>> {
+    let mut numbers = vec![file.metadata()?.rdev()];
+
+    for entry in fs::read_dir(sysfs_dir(file)?)? {
+        let path = entry?.path();
+        if path.join("partition").exists() {
+            if let Some(dev) = fs::read_to_string(path.join("dev"))
+                .ok()
+                .and_then(|dev| parse_device_number(&dev))
+            {
+                numbers.push(dev);
+            }
+        }
+    }
+
+    Ok(numbers)
+}
+
+/// Returns the partitions the kernel currently has registered for a block device.
+fn kernel_partitions(file: &File) -> io::Result<Vec<KernelPartition>> {
+    let mut partitions = Vec::new();
+
+    for entry in fs::read_dir(sysfs_dir(file)?)? {
+        let path = entry?.path();
+        let fields = (
+            read_sysfs_value(&path.join("partition")),
+            read_sysfs_value(&path.join("start")),
+            read_sysfs_value(&path.join("size")),
+        );
+
+        if let (Some(number), Some(start), Some(nr_sectors)) = fields {
+            partitions.push(KernelPartition {
+                number: number as usize,
+                start,
+                nr_sectors,
+            });
+        }
+    }
+
+    Ok(partitions)
+}
+
+/// Returns where a block device, or a partition on it, is mounted.
+///
+/// Mounts are matched by device number, and by the device named as the mount source for
+/// filesystems such as btrfs that report an anonymous device number.
+pub fn find_mount(file: &File) -> io::Result<Option<String>> {
+    let numbers = device_numbers(file)?;
+
+    for line in fs::read_to_string("/proc/self/mountinfo")?.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let source = fields
+            .iter()
+            .position(|&f| f == "-")
+            .and_then(|i| fields.get(i + 2));
+
+        let by_number = fields
+            .get(2)
+            .and_then(|n| parse_device_number(n))
+            .is_some_and(|n| numbers.contains(&n));
+        let by_source = source
+            .and_then(|s| fs::metadata(s).ok())
+            .is_some_and(|m| m.file_type().is_block_device() && numbers.contains(&m.rdev()));
+
+        if by_number || by_source {
+            return Ok(Some(fields.get(4).unwrap_or(&"?").to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn blkpg_partition(file: &File, op: libc::c_int, p: &KernelPartition) -> io::Result<()> {
+    let mut part = BlkpgPartition {
+        start: (p.start * SECTOR_SIZE) as i64,
+        length: (p.nr_sectors * SECTOR_SIZE) as i64,
+        pno: p.number as libc::c_int,
+        devname: [0; 64],
+        volname: [0; 64],
+    };
+    let arg = BlkpgIoctlArg {
+        op,
+        flags: 0,
+        datalen: std::mem::size_of::<BlkpgPartition>() as libc::c_int,
+        data: &mut part as *mut BlkpgPartition as *mut c_void,
+    };
+
+    unsafe { blkpg(file.as_raw_fd(), &arg) }?;
+    Ok(())
+}
+
+/// Tells the kernel about a changed partition table.
+///
+/// The whole table is re-read with `BLKRRPART` if possible. That fails while any partition is
+/// in use, in which case the partitions that changed are updated one by one with `BLKPG`, as
+/// `partx` does; partitions in use still keep their old extent.
+///
+/// Without a list of `partitions`, e.g. for a table with logical partitions that only the kernel
+/// numbers, a busy device is left as it is and an error returned.
+pub fn reread_partitions(file: &File, partitions: Option<&[KernelPartition]>) -> io::Result<()> {
+    file.sync_all()?;
+
+    // A partition has no partitions of its own to re-read.
+    if Path::new(&sysfs_dir(file)?).join("partition").exists() {
+        return Ok(());
+    }
+
+    let partitions = match unsafe { blkrrpart(file.as_raw_fd()) } {
+        Ok(_) => return Ok(()),
+        // The device does not support partitions, e.g. a loop device without partition scanning.
+        Err(nix::errno::Errno::EINVAL) => return Ok(()),
+        Err(nix::errno::Errno::EBUSY) => partitions.ok_or(nix::errno::Errno::EBUSY)?,
+        Err(e) => return Err(e.into()),
+    };
+
+    let existing = kernel_partitions(file)?;
+    let mut result = Ok(());
+
+    let old = |new: &KernelPartition| existing.iter().find(|p| p.number == new.number);
+    let kept = |old: &KernelPartition| {
+        partitions
+            .iter()
+            .any(|p| p.number == old.number && p.start == old.start)
+    };
+
+    // Deletions go first, so that the partitions that grow or move do not overlap them.
+    for old in existing.iter().filter(|old| !kept(old)) {
+        result = result.and(blkpg_partition(file, BLKPG_DEL_PARTITION, old));
+    }
+
+    for new in partitions {
+        let r = match old(new) {
+            Some(old) if old == new => Ok(()),
+            Some(old) if old.start == new.start => {
+                blkpg_partition(file, BLKPG_RESIZE_PARTITION, new)
+            }
+            _ => blkpg_partition(file, BLKPG_ADD_PARTITION, new),
+        };
+        result = result.and(r);
+    }
+
+    result
+}
@@ -0,0 +1,396 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    ops::Deref,
+    os::unix::{
+        fs::{FileExt, MetadataExt},
+        io::AsRawFd,
+    },
+    path::Path,
+};
+
+use memmap::{Mmap, MmapMut};
+use nix::fcntl::FallocateFlags;
+
+use super::{
+    blockdev::{self, KernelPartition},
+    extents, ImageError,
+};
+
+/// Storage holding the contents of an image, addressed in bytes.
+pub trait BlockDevice {
+    /// Size of the device in bytes.
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Returns the whole contents if they are addressable in memory, so that reads can borrow
+    /// them instead of copying.
+    fn as_slice(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Returns the number of bytes of storage actually allocated to the device, if known.
+    fn allocated(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns the byte ranges within the `len` bytes at `offset` that may hold data, skipping
+    /// holes where the device can tell them apart.
+    fn data_extents(&self, offset: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        Ok(vec![(offset, offset + len)])
+    }
+
+    /// Returns the device and inode numbers of the file holding the contents, if there is one.
+    fn file_id(&self) -> Option<(u64, u64)> {
+        None
+    }
+}
+
+/// A block device that can also be written to.
+pub trait WritableBlockDevice: BlockDevice {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Deallocates `len` bytes at `offset`, which read as zeros afterwards.
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()>;
+
+    /// Copies `len` bytes at `src_offset` in `src` to `offset` without passing them through
+    /// user space. Returns `false` if the device does not support this.
+    fn copy_from_file(
+        &mut self,
+        _offset: u64,
+        _src: &File,
+        _src_offset: u64,
+        _len: u64,
+    ) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Hides the writability of a device opened read-only, so that only inspection is possible.
+pub struct ReadOnly<D: BlockDevice>(pub(super) D);
+
+impl<D: BlockDevice> BlockDevice for ReadOnly<D> {
+    fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.0.read_at(offset, buf)
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        self.0.as_slice()
+    }
+
+    fn allocated(&self) -> Option<u64> {
+        self.0.allocated()
+    }
+
+    fn data_extents(&self, offset: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        self.0.data_extents(offset, len)
+    }
+
+    fn file_id(&self) -> Option<(u64, u64)> {
+        self.0.file_id()
+    }
+}
+
+pub(super) fn out_of_range() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "access beyond the end of the device",
+    )
+}
+
+/// Returns the range of `len` bytes at `offset` if it lies within `size` bytes.
+fn range(offset: u64, len: usize, size: usize) -> io::Result<std::ops::Range<usize>> {
+    let start = usize::try_from(offset).map_err(|_| out_of_range())?;
+
+    match start.checked_add(len) {
+        Some(end) if end <= size => Ok(start..end),
+        _ => Err(out_of_range()),
+    }
+}
+
+pub(super) fn allocated(file: &File) -> Option<u64> {
+    file.metadata().ok().map(|m| m.blocks() * 512)
+}
+
+pub(super) fn file_id(file: &File) -> Option<(u64, u64)> {
+    file.metadata().ok().map(|m| (m.dev(), m.ino()))
+}
+
+pub(super) fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let mode = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+    nix::fcntl::fallocate(file.as_raw_fd(), mode, offset as i64, len as i64)?;
+
+    Ok(())
+}
+
+/// A file mapped into memory, writable unless mapped with a read-only `Mmap`.
+pub struct MmapDevice<M = MmapMut> {
+    file: File,
+    mem: M,
+}
+
+impl MmapDevice {
+    pub fn from_file(file: File) -> Result<Self, ImageError> {
+        // The size of a block device is not known to mmap, so leave those to FileDevice.
+        if blockdev::is_block_device(&file) {
+            return Err(ImageError::MapError);
+        }
+
+        let mem = unsafe { Mmap::map(&file).map_err(|_| ImageError::MapError)? }
+            .make_mut()
+            .map_err(|_| ImageError::MapError)?;
+
+        Ok(MmapDevice { file, mem })
+    }
+
+    /// Maps a file that may have been opened read-only.
+    pub fn from_file_read_only(file: File) -> Result<ReadOnly<MmapDevice<Mmap>>, ImageError> {
+        if blockdev::is_block_device(&file) {
+            return Err(ImageError::MapError);
+        }
+
+        let mem = unsafe { Mmap::map(&file).map_err(|_| ImageError::MapError)? };
+
+        Ok(ReadOnly(MmapDevice { file, mem }))
+    }
+}
+
+impl<M: Deref<Target = [u8]>> BlockDevice for MmapDevice<M> {
+    fn len(&self) -> u64 {
+        self.mem.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.copy_from_slice(&self.mem[range(offset, buf.len(), self.mem.len())?]);
+        Ok(())
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(&self.mem)
+    }
+
+    fn allocated(&self) -> Option<u64> {
+        allocated(&self.file)
+    }
+
+    fn data_extents(&self, offset: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        extents::data_extents(&self.file, offset, len)
+    }
+
+    fn file_id(&self) -> Option<(u64, u64)> {
+        file_id(&self.file)
+    }
+}
+
+impl WritableBlockDevice for MmapDevice {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let range = range(offset, data.len(), self.mem.len())?;
+        self.mem[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        range(offset, len as usize, self.mem.len())?;
+
+        // The mapping is shared, so it sees the hole as zeros.
+        punch_hole(&self.file, offset, len)
+    }
+
+    fn copy_from_file(
+        &mut self,
+        offset: u64,
+        src: &File,
+        src_offset: u64,
+        len: u64,
+    ) -> io::Result<bool> {
+        range(offset, len as usize, self.mem.len())?;
+
+        // As with holes, the shared mapping sees what the kernel writes to the file.
+        extents::copy_range(src, src_offset, &self.file, offset, len)
+    }
+}
+
+/// A file or block device accessed with `pread` and `pwrite`, which needs no address space for
+/// its contents.
+pub struct FileDevice {
+    file: File,
+    len: u64,
+    is_block_device: bool,
+}
+
+impl FileDevice {
+    pub fn from_file(file: File) -> Result<Self, ImageError> {
+        let is_block_device = blockdev::is_block_device(&file);
+        let len = if is_block_device {
+            blockdev::device_size(&file)?
+        } else {
+            file.metadata().map_err(|_| ImageError::OpenError)?.len()
+        };
+
+        Ok(FileDevice {
+            file,
+            len,
+            is_block_device,
+        })
+    }
+
+    pub fn open<P>(path: P) -> Result<Self, ImageError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|_| ImageError::OpenError)?;
+
+        // Changing the tables under a mounted filesystem would corrupt it.
+        if blockdev::is_block_device(&file) {
+            if let Some(mount_point) =
+                blockdev::find_mount(&file).map_err(|_| ImageError::OpenError)?
+            {
+                return Err(ImageError::Mounted(mount_point));
+            }
+        }
+
+        Self::from_file(file)
+    }
+
+    pub fn open_read_only<P>(path: P) -> Result<ReadOnly<Self>, ImageError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::open(path).map_err(|_| ImageError::OpenError)?;
+
+        Ok(ReadOnly(Self::from_file(file)?))
+    }
+
+    pub fn is_block_device(&self) -> bool {
+        self.is_block_device
+    }
+
+    /// Makes the kernel pick up changes to the partition table of a block device. Does nothing
+    /// for a regular file.
+    pub fn reread_partitions(&self, partitions: Option<&[KernelPartition]>) -> io::Result<()> {
+        if !self.is_block_device {
+            return Ok(());
+        }
+
+        blockdev::reread_partitions(&self.file, partitions)
+    }
+}
+
+impl BlockDevice for FileDevice {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset.saturating_add(buf.len() as u64) > self.len {
+            return Err(out_of_range());
+        }
+
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn allocated(&self) -> Option<u64> {
+        if self.is_block_device {
+            return None;
+        }
+
+        allocated(&self.file)
+    }
+
+    fn data_extents(&self, offset: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        extents::data_extents(&self.file, offset, len)
+    }
+
+    fn file_id(&self) -> Option<(u64, u64)> {
+        file_id(&self.file)
+    }
+}
+
+impl WritableBlockDevice for FileDevice {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if offset.saturating_add(data.len() as u64) > self.len {
+            return Err(out_of_range());
+        }
+
+        self.file.write_all_at(data, offset)
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if offset.saturating_add(len) > self.len {
+            return Err(out_of_range());
+        }
+
+        punch_hole(&self.file, offset, len)
+    }
+
+    fn copy_from_file(
+        &mut self,
+        offset: u64,
+        src: &File,
+        src_offset: u64,
+        len: u64,
+    ) -> io::Result<bool> {
+        if offset.saturating_add(len) > self.len {
+            return Err(out_of_range());
+        }
+
+        extents::copy_range(src, src_offset, &self.file, offset, len)
+    }
+}
+
+/// An image held entirely in memory, e.g. for tests.
+pub struct MemoryDevice {
+    data: Vec<u8>,
+}
+
+impl MemoryDevice {
+    pub fn new(data: Vec<u8>) -> Self {
+        MemoryDevice { data }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl BlockDevice for MemoryDevice {
+    fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.copy_from_slice(&self.data[range(offset, buf.len(), self.data.len())?]);
+        Ok(())
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(&self.data)
+    }
+}
+
+impl WritableBlockDevice for MemoryDevice {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let range = range(offset, data.len(), self.data.len())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let range = range(offset, len as usize, self.data.len())?;
+        self.data[range].fill(0);
+        Ok(())
+    }
+}
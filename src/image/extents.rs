@@ -0,0 +1,102 @@
+use std::{fs::File, io, os::unix::io::AsRawFd};
+
+use nix::{
+    errno::Errno,
+    ioctl_write_ptr,
+    unistd::{lseek, Whence},
+};
+
+ioctl_write_ptr!(ficlonerange, 0x94, 13, FileCloneRange);
+
+/// `struct file_clone_range` from `linux/fs.h`.
+#[repr(C)]
+struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+}
+
+/// Returns the byte ranges of the `len` bytes at `offset` in `file` that hold data, skipping
+/// holes.
+///
+/// Files on filesystems that cannot report holes are taken to be all data.
+pub fn data_extents(file: &File, offset: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let end = offset + len;
+    let mut extents = Vec::new();
+    let mut pos = offset;
+
+    while pos < end {
+        let start = match lseek(fd, pos as i64, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // No data after `pos`.
+            Err(Errno::ENXIO) => break,
+            Err(Errno::EINVAL) => {
+                extents.push((pos, end));
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if start >= end {
+            break;
+        }
+
+        let hole = lseek(fd, start as i64, Whence::SeekHole)? as u64;
+        extents.push((start, hole.min(end)));
+        pos = hole;
+    }
+
+    Ok(extents)
+}
+
+/// Copies `len` bytes from `src` to `dst` within the kernel, sharing the blocks with a reflink
+/// where the filesystem supports it.
+///
+/// Returns `false` without copying anything if neither is possible between these files, e.g.
+/// because `dst` is a block device.
+pub fn copy_range(
+    src: &File,
+    src_offset: u64,
+    dst: &File,
+    dst_offset: u64,
+    len: u64,
+) -> io::Result<bool> {
+    // Cloning needs both files on the same filesystem and ranges aligned to its blocks, so
+    // failing here is common and copying is tried next.
+    let range = FileCloneRange {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset,
+        src_length: len,
+        dest_offset: dst_offset,
+    };
+    if unsafe { ficlonerange(dst.as_raw_fd(), &range) }.is_ok() {
+        return Ok(true);
+    }
+
+    let mut copied = 0;
+    while copied < len {
+        let mut off_in = (src_offset + copied) as i64;
+        let mut off_out = (dst_offset + copied) as i64;
+
+        match nix::fcntl::copy_file_range(
+            src.as_raw_fd(),
+            Some(&mut off_in),
+            dst.as_raw_fd(),
+            Some(&mut off_out),
+            (len - copied) as usize,
+        ) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => copied += n as u64,
+            Err(Errno::EINTR) => {}
+            Err(Errno::EXDEV | Errno::EINVAL | Errno::EOPNOTSUPP | Errno::ENOSYS)
+                if copied == 0 =>
+            {
+                return Ok(false)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(true)
+}
@@ -0,0 +1,352 @@
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::{FileExt, MetadataExt},
+    path::Path,
+};
+
+use memmap::Mmap;
+
+pub use blockdev::KernelPartition;
+pub use device::{
+    BlockDevice, FileDevice, MemoryDevice, MmapDevice, ReadOnly, WritableBlockDevice,
+};
+pub use qcow2::Qcow2Device;
+
+mod blockdev;
+mod device;
+mod extents;
+mod qcow2;
+pub mod vhd;
+
+const BLOCK_SIZE: usize = 512;
+
+/// Amount of a file read into memory at once when it cannot be copied within the kernel.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Error during creation of disk image.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ImageError {
+    /// Unable to open image file
+    OpenError,
+    /// Unable to map image file
+    MapError,
+    /// Access beyond the end of the image
+    OutOfRange,
+    /// The device has {0}-byte sectors, only 512-byte sectors are supported
+    UnsupportedSectorSize(u32),
+    /// The device or one of its partitions is mounted at {0}
+    Mounted(String),
+    /// Unable to deallocate blocks of the image: {0}
+    PunchHoleError(io::Error),
+    /// Unable to copy the file into the image: {0}
+    CopyError(io::Error),
+    /// Malformed qcow2 image: {0}
+    InvalidQcow2(&'static str),
+    /// Unsupported qcow2 image: {0}
+    UnsupportedQcow2(&'static str),
+    /// I/O error: {0}
+    Io(io::Error),
+}
+
+/// Format of the file holding an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// The contents of the disk as they are.
+    Raw,
+    Qcow2,
+}
+
+impl ImageFormat {
+    /// Detects the format of an existing image from its contents. Anything that is not
+    /// recognised, or cannot be read, is taken to be raw.
+    ///
+    /// Block devices are always raw without looking at them, as whatever uses the disk may have
+    /// written anything to its first sectors.
+    pub fn detect<P>(path: P) -> ImageFormat
+    where
+        P: AsRef<Path>,
+    {
+        match File::open(path) {
+            Ok(file) if !blockdev::is_block_device(&file) && Qcow2Device::is_qcow2(&file) => {
+                ImageFormat::Qcow2
+            }
+            _ => ImageFormat::Raw,
+        }
+    }
+}
+
+/// A structure with a fixed-size, little-endian on-disk encoding.
+pub trait OnDisk: Sized {
+    /// Size of the encoded structure in bytes.
+    const SIZE: usize;
+
+    /// Decodes the structure from the first `SIZE` bytes of `bytes`.
+    fn decode(bytes: &[u8]) -> Self;
+
+    /// Encodes the structure into the first `SIZE` bytes of `bytes`.
+    fn encode(&self, bytes: &mut [u8]);
+}
+
+/// A disk image stored on a block device, addressed in blocks or bytes.
+pub struct Image<D: BlockDevice = MmapDevice> {
+    block_size: usize,
+    device: D,
+}
+
+impl Image<MmapDevice> {
+    pub fn from_file(file: File) -> Result<Self, ImageError> {
+        Ok(Self::new(MmapDevice::from_file(file)?))
+    }
+
+    pub fn open<P>(path: P) -> Result<Self, ImageError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(false)
+            .truncate(false)
+            .append(false)
+            .open(path)
+            .map_err(|_| ImageError::OpenError)?;
+
+        Self::from_file(file)
+    }
+}
+
+impl Image<ReadOnly<MmapDevice<Mmap>>> {
+    /// Opens an image for inspection only, which needs no write permission.
+    pub fn open_read_only<P>(path: P) -> Result<Self, ImageError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::open(path).map_err(|_| ImageError::OpenError)?;
+
+        Ok(Self::new(MmapDevice::from_file_read_only(file)?))
+    }
+}
+
+impl<D: BlockDevice> Image<D> {
+    pub fn new(device: D) -> Self {
+        Image {
+            block_size: BLOCK_SIZE,
+            device,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn into_device(self) -> D {
+        self.device
+    }
+
+    /// Returns the byte offset of block `block_index` and the length of `block_count` blocks.
+    fn block_range(
+        &self,
+        block_index: u64,
+        block_count: usize,
+    ) -> Result<(u64, usize), ImageError> {
+        let offset = block_index.checked_mul(self.block_size as u64);
+        let len = block_count.checked_mul(self.block_size);
+
+        offset.zip(len).ok_or(ImageError::OutOfRange)
+    }
+
+    /// Checks that `len` bytes at `offset` lie within the image.
+    fn check_range(&self, offset: u64, len: u64) -> Result<(), ImageError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len() => Ok(()),
+            _ => Err(ImageError::OutOfRange),
+        }
+    }
+
+    pub fn get_blocks(
+        &self,
+        block_index: u64,
+        block_count: usize,
+    ) -> Result<Cow<'_, [u8]>, ImageError> {
+        let (offset, len) = self.block_range(block_index, block_count)?;
+        self.get_bytes(offset, len)
+    }
+
+    pub fn get_bytes(&self, offset: u64, len: usize) -> Result<Cow<'_, [u8]>, ImageError> {
+        self.check_range(offset, len as u64)?;
+
+        Ok(match self.device.as_slice() {
+            // The range lies within the slice, so it fits in a usize.
+            Some(mem) => Cow::Borrowed(&mem[offset as usize..offset as usize + len]),
+            None => {
+                let mut buf = vec![0; len];
+                self.device
+                    .read_at(offset, &mut buf)
+                    .map_err(ImageError::Io)?;
+                Cow::Owned(buf)
+            }
+        })
+    }
+
+    pub fn read<T: OnDisk>(&self, offset: u64) -> Result<T, ImageError> {
+        Ok(T::decode(&self.get_bytes(offset, T::SIZE)?))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.device.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.device.is_empty()
+    }
+
+    /// Returns the number of bytes of storage used by the image, if known.
+    pub fn allocated(&self) -> Option<u64> {
+        self.device.allocated()
+    }
+
+    /// Returns true if `path` names the file holding the image, under any name or link.
+    pub fn is_stored_at(&self, path: &Path) -> bool {
+        std::fs::metadata(path).is_ok_and(|m| self.device.file_id() == Some((m.dev(), m.ino())))
+    }
+
+    /// Returns the byte ranges within the `len` bytes at `offset` that may hold data. The rest
+    /// reads as zeros.
+    pub fn data_extents(&self, offset: u64, len: u64) -> Result<Vec<(u64, u64)>, ImageError> {
+        self.check_range(offset, len)?;
+
+        self.device
+            .data_extents(offset, len)
+            .map_err(ImageError::Io)
+    }
+}
+
+impl<D: WritableBlockDevice> Image<D> {
+    pub fn write_blocks(&mut self, block_index: u64, data: &[u8]) -> Result<(), ImageError> {
+        let (offset, _) = self.block_range(block_index, 0)?;
+        self.write_bytes(offset, data)
+    }
+
+    pub fn write_bytes(&mut self, offset: u64, data: &[u8]) -> Result<(), ImageError> {
+        self.check_range(offset, data.len() as u64)?;
+        self.device.write_at(offset, data).map_err(ImageError::Io)
+    }
+
+    /// Zeroes `len` bytes at `offset`, only writing the blocks that are not zero already.
+    pub fn zero_bytes_sparse(&mut self, offset: u64, len: u64) -> Result<(), ImageError> {
+        let zeros = vec![0; (COPY_CHUNK_SIZE as u64).min(len) as usize];
+
+        let mut pos = 0;
+        while pos < len {
+            let n = (COPY_CHUNK_SIZE as u64).min(len - pos);
+            self.write_bytes_sparse(offset + pos, &zeros[..n as usize])?;
+            pos += n;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the first `len` bytes of `src` to `offset`. Only the data extents of `src` are
+    /// copied, within the kernel where possible, and its holes are left as holes in the image.
+    pub fn import(&mut self, offset: u64, src: &File, len: u64) -> Result<(), ImageError> {
+        self.check_range(offset, len)?;
+
+        let extents = extents::data_extents(src, 0, len).map_err(ImageError::CopyError)?;
+
+        let mut pos = 0;
+        for (start, end) in extents {
+            self.copy_hole(offset + pos, start - pos)?;
+            self.copy_extent(offset + start, src, start, end - start)?;
+            pos = end;
+        }
+
+        self.copy_hole(offset + pos, len - pos)
+    }
+
+    /// Makes `len` bytes at `offset` a hole, or failing that zeroes them.
+    fn copy_hole(&mut self, offset: u64, len: u64) -> Result<(), ImageError> {
+        match self.punch_hole(offset, len) {
+            Err(ImageError::PunchHoleError(_)) => self.zero_bytes_sparse(offset, len),
+            r => r,
+        }
+    }
+
+    fn copy_extent(
+        &mut self,
+        offset: u64,
+        src: &File,
+        src_offset: u64,
+        len: u64,
+    ) -> Result<(), ImageError> {
+        let copied = self
+            .device
+            .copy_from_file(offset, src, src_offset, len)
+            .map_err(ImageError::CopyError)?;
+        if copied {
+            return Ok(());
+        }
+
+        let mut buf = vec![0; (COPY_CHUNK_SIZE as u64).min(len) as usize];
+        let mut pos = 0;
+        while pos < len {
+            let n = (COPY_CHUNK_SIZE as u64).min(len - pos);
+            src.read_exact_at(&mut buf[..n as usize], src_offset + pos)
+                .map_err(ImageError::CopyError)?;
+            self.write_bytes_sparse(offset + pos, &buf[..n as usize])?;
+            pos += n;
+        }
+
+        Ok(())
+    }
+
+    /// Deallocates `len` bytes at `offset`, leaving a hole that reads as zeros.
+    pub fn punch_hole(&mut self, offset: u64, len: u64) -> Result<(), ImageError> {
+        self.check_range(offset, len)?;
+        self.device
+            .punch_hole(offset, len)
+            .map_err(ImageError::PunchHoleError)
+    }
+
+    /// Writes `data` at `offset`, skipping blocks where both `data` and the existing contents
+    /// are zero so that holes in a sparse image are not filled in.
+    pub fn write_bytes_sparse(&mut self, offset: u64, data: &[u8]) -> Result<(), ImageError> {
+        let is_zero = |b: &[u8]| b.iter().all(|&b| b == 0);
+
+        let needed: Vec<bool> = {
+            let existing = self.get_bytes(offset, data.len())?;
+            data.chunks(self.block_size)
+                .zip(existing.chunks(self.block_size))
+                .map(|(new, old)| !is_zero(new) || !is_zero(old))
+                .collect()
+        };
+
+        // Write each run of needed blocks at once.
+        let mut block = 0;
+        while block < needed.len() {
+            if !needed[block] {
+                block += 1;
+                continue;
+            }
+
+            let run_start = block;
+            while block < needed.len() && needed[block] {
+                block += 1;
+            }
+
+            let start = run_start * self.block_size;
+            let end = (block * self.block_size).min(data.len());
+            self.write_bytes(offset + start as u64, &data[start..end])?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write<T: OnDisk>(&mut self, offset: u64, obj: &T) -> Result<(), ImageError> {
+        let mut bytes = vec![0; T::SIZE];
+        obj.encode(&mut bytes);
+
+        self.write_bytes(offset, &bytes)
+    }
+}
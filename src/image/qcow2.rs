@@ -0,0 +1,1096 @@
+//! The qcow2 format used by QEMU, in which the image is split into clusters that are only
+//! allocated in the file once written.
+//!
+//! Guest clusters are looked up through a two-level table: the L1 table points to L2 tables,
+//! whose entries point to the clusters holding the data. Every cluster of the file has a
+//! reference count, kept in refcount blocks found through the refcount table. New clusters are
+//! always appended to the end of the file.
+
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap},
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use super::{
+    blockdev,
+    device::{self, BlockDevice, ReadOnly, WritableBlockDevice},
+    ImageError,
+};
+
+const MAGIC: [u8; 4] = *b"QFI\xfb";
+
+/// Size of a version 3 header without the optional compression type. Version 2 headers are
+/// shorter, and read as if the version 3 fields were zero.
+const HEADER_SIZE: usize = 104;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+
+/// Cluster size of new images, 64 KiB as with qemu-img.
+const DEFAULT_CLUSTER_BITS: u32 = 16;
+/// Width of the reference counts of new images, 16 bits as with qemu-img.
+const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+
+/// Largest tables accepted, as in QEMU, so that a corrupt header cannot exhaust memory.
+const MAX_L1_SIZE: u64 = 32 * 1024 * 1024;
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The cluster is referenced exactly once, so it can be written in place.
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zeros (version 3 only).
+const OFLAG_ZERO: u64 = 1;
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+
+/// Incompatible feature bit for a compression type other than zlib.
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+
+/// The fields of the header this implementation uses.
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    version: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    refcount_order: u32,
+}
+
+fn be_u32(b: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(b[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(b: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(b[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Header {
+    fn decode(b: &[u8; HEADER_SIZE]) -> Result<Self, ImageError> {
+        if b[0..4] != MAGIC {
+            return Err(ImageError::InvalidQcow2("no qcow2 signature"));
+        }
+
+        let version = be_u32(b, 4);
+        if version != 2 && version != 3 {
+            return Err(ImageError::UnsupportedQcow2("only versions 2 and 3 are supported"));
+        }
+        if be_u64(b, 8) != 0 {
+            return Err(ImageError::UnsupportedQcow2("backing files are not supported"));
+        }
+        if be_u32(b, 32) != 0 {
+            return Err(ImageError::UnsupportedQcow2("encryption is not supported"));
+        }
+
+        let refcount_order = if version == 3 {
+            if (be_u32(b, 100) as usize) < HEADER_SIZE {
+                return Err(ImageError::InvalidQcow2("header too short"));
+            }
+
+            // Only zlib compression is supported, which is also what the type field defaults to.
+            let incompatible = be_u64(b, 72);
+            if incompatible & !INCOMPAT_COMPRESSION_TYPE != 0 {
+                return Err(ImageError::UnsupportedQcow2(
+                    "the image uses an unsupported feature, or is marked dirty or corrupt",
+                ));
+            }
+            if incompatible & INCOMPAT_COMPRESSION_TYPE != 0 {
+                return Err(ImageError::UnsupportedQcow2(
+                    "only zlib compression is supported",
+                ));
+            }
+
+            be_u32(b, 96)
+        } else {
+            DEFAULT_REFCOUNT_ORDER
+        };
+
+        let header = Header {
+            version,
+            cluster_bits: be_u32(b, 20),
+            size: be_u64(b, 24),
+            l1_size: be_u32(b, 36),
+            l1_table_offset: be_u64(b, 40),
+            refcount_table_offset: be_u64(b, 48),
+            refcount_table_clusters: be_u32(b, 56),
+            nb_snapshots: be_u32(b, 60),
+            refcount_order,
+        };
+
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&header.cluster_bits) {
+            return Err(ImageError::InvalidQcow2("invalid cluster size"));
+        }
+        if header.refcount_order > 6 {
+            return Err(ImageError::InvalidQcow2("invalid refcount width"));
+        }
+
+        let cluster_size = header.cluster_size();
+        if !header.l1_table_offset.is_multiple_of(cluster_size)
+            || !header.refcount_table_offset.is_multiple_of(cluster_size)
+        {
+            return Err(ImageError::InvalidQcow2("misaligned table"));
+        }
+        if header.l1_size as u64 * 8 > MAX_L1_SIZE
+            || header.refcount_table_clusters as u64 * cluster_size > MAX_REFCOUNT_TABLE_SIZE
+        {
+            return Err(ImageError::InvalidQcow2("table too large"));
+        }
+        if (header.l1_size as u64) < header.l1_entries_needed() {
+            return Err(ImageError::InvalidQcow2("L1 table too small for the image size"));
+        }
+
+        Ok(header)
+    }
+
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut b = [0; HEADER_SIZE];
+
+        b[0..4].copy_from_slice(&MAGIC);
+        b[4..8].copy_from_slice(&self.version.to_be_bytes());
+        b[20..24].copy_from_slice(&self.cluster_bits.to_be_bytes());
+        b[24..32].copy_from_slice(&self.size.to_be_bytes());
+        b[36..40].copy_from_slice(&self.l1_size.to_be_bytes());
+        b[40..48].copy_from_slice(&self.l1_table_offset.to_be_bytes());
+        b[48..56].copy_from_slice(&self.refcount_table_offset.to_be_bytes());
+        b[56..60].copy_from_slice(&self.refcount_table_clusters.to_be_bytes());
+        b[60..64].copy_from_slice(&self.nb_snapshots.to_be_bytes());
+        b[96..100].copy_from_slice(&self.refcount_order.to_be_bytes());
+        b[100..104].copy_from_slice(&(HEADER_SIZE as u32).to_be_bytes());
+
+        b
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Number of 64-bit entries in a cluster of an L2 table or the refcount table.
+    fn entries_per_cluster(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    fn l1_entries_needed(&self) -> u64 {
+        self.size
+            .div_ceil(self.cluster_size())
+            .div_ceil(self.entries_per_cluster())
+    }
+
+    /// Number of reference counts in a refcount block.
+    fn refcount_block_entries(&self) -> u64 {
+        (self.cluster_size() * 8) >> self.refcount_order
+    }
+
+    /// Bit position of the compressed size in a compressed L2 entry.
+    fn csize_shift(&self) -> u32 {
+        62 - (self.cluster_bits - 8)
+    }
+}
+
+/// What an L2 entry says about a guest cluster.
+enum Cluster {
+    Unallocated,
+    /// Reads as zeros, possibly with a cluster already allocated for it.
+    Zero(u64),
+    Normal(u64),
+    Compressed { offset: u64, size: u64 },
+}
+
+/// Compresses a cluster as raw deflate, or returns `None` if that would not make it smaller.
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    // QEMU inflates with a 4 KiB window, so matches must not reach further back.
+    let mut compress = Compress::new_with_window_bits(Compression::default(), false, 12);
+    let mut out = Vec::with_capacity(data.len() - 1);
+
+    match compress.compress_vec(data, &mut out, FlushCompress::Finish) {
+        Ok(Status::StreamEnd) => Some(out),
+        _ => None,
+    }
+}
+
+/// An image in the qcow2 format, without backing file or encryption.
+///
+/// Images with internal snapshots can only be opened read-only, as their clusters may be
+/// shared.
+pub struct Qcow2Device {
+    file: File,
+    header: Header,
+    l1: Vec<u64>,
+    /// L2 tables read so far, by offset in the file.
+    l2_cache: RefCell<HashMap<u64, Vec<u64>>>,
+    refcount_table: Vec<u64>,
+    /// Offset of the end of the last cluster in the file, where new clusters are allocated.
+    end: u64,
+    /// Write whole new clusters compressed.
+    compress: bool,
+    /// End of the compressed data written last, which the next compressed cluster may share a
+    /// cluster with.
+    compressed_end: u64,
+}
+
+impl Qcow2Device {
+    /// Returns true if `file` starts with the qcow2 signature.
+    pub fn is_qcow2(file: &File) -> bool {
+        let mut magic = [0; 4];
+        file.read_exact_at(&mut magic, 0).is_ok() && magic == MAGIC
+    }
+
+    pub fn from_file(file: File) -> Result<Self, ImageError> {
+        // Clusters are allocated past the end of the file, which a block device does not have.
+        if blockdev::is_block_device(&file) {
+            return Err(ImageError::UnsupportedQcow2(
+                "qcow2 images on block devices are not supported",
+            ));
+        }
+
+        let mut b = [0; HEADER_SIZE];
+        let len = file.metadata().map_err(|_| ImageError::OpenError)?.len();
+        let header_len = (len as usize).min(HEADER_SIZE);
+        file.read_exact_at(&mut b[..header_len], 0)
+            .map_err(|_| ImageError::OpenError)?;
+        let header = Header::decode(&b)?;
+
+        let read_table = |offset: u64, nr_entries: usize| -> Result<Vec<u64>, ImageError> {
+            let mut bytes = vec![0; nr_entries * 8];
+            file.read_exact_at(&mut bytes, offset)
+                .map_err(|_| ImageError::InvalidQcow2("table beyond the end of the file"))?;
+
+            Ok(bytes
+                .chunks_exact(8)
+                .map(|e| u64::from_be_bytes(e.try_into().unwrap()))
+                .collect())
+        };
+
+        let l1 = read_table(header.l1_table_offset, header.l1_size as usize)?;
+        let refcount_table = read_table(
+            header.refcount_table_offset,
+            (header.refcount_table_clusters as u64 * header.entries_per_cluster()) as usize,
+        )?;
+
+        let cluster_size = header.cluster_size();
+        Ok(Qcow2Device {
+            file,
+            header,
+            l1,
+            l2_cache: RefCell::new(HashMap::new()),
+            refcount_table,
+            end: len.div_ceil(cluster_size) * cluster_size,
+            compress: false,
+            compressed_end: 0,
+        })
+    }
+
+    pub fn open<P>(path: P) -> Result<Self, ImageError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|_| ImageError::OpenError)?;
+
+        let device = Self::from_file(file)?;
+        if device.header.nb_snapshots != 0 {
+            return Err(ImageError::UnsupportedQcow2(
+                "images with snapshots can only be inspected",
+            ));
+        }
+        if device.header.refcount_order < 3 {
+            return Err(ImageError::UnsupportedQcow2(
+                "images with refcounts narrower than a byte can only be inspected",
+            ));
+        }
+
+        Ok(device)
+    }
+
+    pub fn open_read_only<P>(path: P) -> Result<ReadOnly<Self>, ImageError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::open(path).map_err(|_| ImageError::OpenError)?;
+
+        Ok(ReadOnly(Self::from_file(file)?))
+    }
+
+    /// Writes an empty version 3 image of `size` bytes to `file`.
+    pub fn create(file: File, size: u64) -> Result<Self, ImageError> {
+        Self::create_with_cluster_bits(file, size, DEFAULT_CLUSTER_BITS)
+    }
+
+    fn create_with_cluster_bits(
+        file: File,
+        size: u64,
+        cluster_bits: u32,
+    ) -> Result<Self, ImageError> {
+        let mut header = Header {
+            version: 3,
+            cluster_bits,
+            size,
+            l1_size: 0,
+            l1_table_offset: 0,
+            refcount_table_offset: 0,
+            refcount_table_clusters: 1,
+            nb_snapshots: 0,
+            refcount_order: DEFAULT_REFCOUNT_ORDER,
+        };
+        let cluster_size = header.cluster_size();
+
+        let l1_size = header.l1_entries_needed();
+        if l1_size * 8 > MAX_L1_SIZE {
+            return Err(ImageError::UnsupportedQcow2("the image is too large"));
+        }
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+
+        // The header, refcount table, one refcount block and the L1 table, as qemu-img lays
+        // them out.
+        header.l1_size = l1_size as u32;
+        header.refcount_table_offset = cluster_size;
+        header.l1_table_offset = 3 * cluster_size;
+        let nr_clusters = 3 + l1_clusters;
+
+        let write = |data: &[u8], offset: u64| {
+            file.write_all_at(data, offset)
+                .map_err(|_| ImageError::OpenError)
+        };
+
+        file.set_len(nr_clusters * cluster_size)
+            .map_err(|_| ImageError::OpenError)?;
+        write(&header.encode(), 0)?;
+        write(&(2 * cluster_size).to_be_bytes(), cluster_size)?;
+        for cluster in 0..nr_clusters {
+            write(&1u16.to_be_bytes(), 2 * cluster_size + cluster * 2)?;
+        }
+
+        Self::from_file(file)
+    }
+
+    /// Makes whole clusters written to unallocated parts of the image be stored compressed,
+    /// e.g. while exporting an image.
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.header.cluster_size()
+    }
+
+    /// Returns the L1 and L2 index of the guest cluster at `offset`.
+    fn table_indices(&self, offset: u64) -> (usize, usize) {
+        let cluster = offset >> self.header.cluster_bits;
+        let l2_entries = self.header.entries_per_cluster();
+
+        ((cluster / l2_entries) as usize, (cluster % l2_entries) as usize)
+    }
+
+    /// Calls `f` with the L2 table at `offset` in the file, reading it in if necessary.
+    fn with_l2_table<R>(&self, offset: u64, f: impl FnOnce(&mut Vec<u64>) -> R) -> io::Result<R> {
+        let mut cache = self.l2_cache.borrow_mut();
+
+        let table = match cache.entry(offset) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                if !offset.is_multiple_of(self.cluster_size()) {
+                    return Err(invalid("misaligned L2 table"));
+                }
+
+                let mut bytes = vec![0; self.cluster_size() as usize];
+                self.file.read_exact_at(&mut bytes, offset)?;
+                e.insert(
+                    bytes
+                        .chunks_exact(8)
+                        .map(|e| u64::from_be_bytes(e.try_into().unwrap()))
+                        .collect(),
+                )
+            }
+        };
+
+        Ok(f(table))
+    }
+
+    /// Returns the L2 entry for the guest cluster at `offset`, which is zero if there is no L2
+    /// table for it.
+    fn l2_entry(&self, offset: u64) -> io::Result<u64> {
+        let (l1_index, l2_index) = self.table_indices(offset);
+
+        match self.l1.get(l1_index).map(|e| e & OFFSET_MASK) {
+            None | Some(0) => Ok(0),
+            Some(l2_offset) => self.with_l2_table(l2_offset, |table| table[l2_index]),
+        }
+    }
+
+    fn classify(&self, entry: u64) -> io::Result<Cluster> {
+        if entry & OFLAG_COMPRESSED != 0 {
+            let shift = self.header.csize_shift();
+            let offset = entry & ((1 << shift) - 1);
+            let nr_sectors = ((entry >> shift) & ((1 << (self.header.cluster_bits - 8)) - 1)) + 1;
+
+            return Ok(Cluster::Compressed {
+                offset,
+                size: nr_sectors * 512 - (offset & 511),
+            });
+        }
+
+        let offset = entry & OFFSET_MASK;
+        if !offset.is_multiple_of(self.cluster_size()) {
+            return Err(invalid("misaligned data cluster"));
+        }
+
+        Ok(if self.header.version >= 3 && entry & OFLAG_ZERO != 0 {
+            Cluster::Zero(offset)
+        } else if offset == 0 {
+            Cluster::Unallocated
+        } else {
+            Cluster::Normal(offset)
+        })
+    }
+
+    fn decompress(&self, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        // The last compressed cluster may end before the sectors its size is given in.
+        let mut input = vec![0; size as usize];
+        let mut len = 0;
+        while len < input.len() {
+            match self.file.read_at(&mut input[len..], offset + len as u64)? {
+                0 => break,
+                n => len += n,
+            }
+        }
+
+        let mut out = Vec::with_capacity(self.cluster_size() as usize);
+        Decompress::new(false)
+            .decompress_vec(&input[..len], &mut out, FlushDecompress::Finish)
+            .map_err(|_| invalid("corrupt compressed cluster"))?;
+        if out.len() != self.cluster_size() as usize {
+            return Err(invalid("corrupt compressed cluster"));
+        }
+
+        Ok(out)
+    }
+
+    /// Returns the contents of the guest cluster with L2 entry `entry`.
+    fn cluster_contents(&self, entry: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; self.cluster_size() as usize];
+
+        match self.classify(entry)? {
+            Cluster::Unallocated | Cluster::Zero(_) => {}
+            Cluster::Normal(offset) => self.file.read_exact_at(&mut data, offset)?,
+            Cluster::Compressed { offset, size } => data = self.decompress(offset, size)?,
+        }
+
+        Ok(data)
+    }
+
+    /// Adds `delta` to the reference count of the cluster at `offset` in the file, and returns
+    /// the new count.
+    fn update_refcount(&mut self, offset: u64, delta: i64) -> io::Result<u64> {
+        let cluster = offset >> self.header.cluster_bits;
+        let block_entries = self.header.refcount_block_entries();
+        let table_index = (cluster / block_entries) as usize;
+
+        if table_index >= self.refcount_table.len() {
+            self.grow_refcount_table(table_index + 1)?;
+        }
+
+        let mut block = self.refcount_table[table_index] & OFFSET_MASK;
+        if block == 0 {
+            block = self.end;
+            self.end += self.cluster_size();
+            self.file.set_len(self.end)?;
+
+            self.refcount_table[table_index] = block;
+            self.file.write_all_at(
+                &block.to_be_bytes(),
+                self.header.refcount_table_offset + table_index as u64 * 8,
+            )?;
+
+            // The new block is counted like any other cluster, possibly in itself.
+            self.update_refcount(block, 1)?;
+        }
+
+        let width = 1 << (self.header.refcount_order - 3);
+        let at = block + (cluster % block_entries) * width;
+
+        let mut bytes = [0; 8];
+        self.file.read_exact_at(&mut bytes[8 - width as usize..], at)?;
+        let count = u64::from_be_bytes(bytes)
+            .checked_add_signed(delta)
+            .filter(|&c| width == 8 || c < 1 << (width * 8))
+            .ok_or_else(|| invalid("reference count out of range"))?;
+        self.file
+            .write_all_at(&count.to_be_bytes()[8 - width as usize..], at)?;
+
+        Ok(count)
+    }
+
+    /// Moves the refcount table to the end of the file, with room for at least `nr_entries`.
+    fn grow_refcount_table(&mut self, nr_entries: usize) -> io::Result<()> {
+        let entries_per_cluster = self.header.entries_per_cluster() as usize;
+        let nr_entries = nr_entries
+            .max(2 * self.refcount_table.len())
+            .div_ceil(entries_per_cluster)
+            * entries_per_cluster;
+        let nr_clusters = (nr_entries / entries_per_cluster) as u64;
+        if nr_clusters * self.cluster_size() > MAX_REFCOUNT_TABLE_SIZE {
+            return Err(invalid("refcount table too large"));
+        }
+
+        let offset = self.end;
+        self.end += nr_clusters * self.cluster_size();
+        self.file.set_len(self.end)?;
+
+        self.refcount_table.resize(nr_entries, 0);
+        let bytes: Vec<u8> = self
+            .refcount_table
+            .iter()
+            .flat_map(|e| e.to_be_bytes())
+            .collect();
+        self.file.write_all_at(&bytes, offset)?;
+
+        let old_offset = self.header.refcount_table_offset;
+        let old_clusters = self.header.refcount_table_clusters as u64;
+
+        self.header.refcount_table_offset = offset;
+        self.header.refcount_table_clusters = nr_clusters as u32;
+        let header = self.header.encode();
+        self.file.write_all_at(&header[48..60], 48)?;
+
+        for i in 0..nr_clusters {
+            self.update_refcount(offset + i * self.cluster_size(), 1)?;
+        }
+        for i in 0..old_clusters {
+            self.free_cluster(old_offset + i * self.cluster_size())?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends `count` clusters to the file.
+    fn allocate_clusters(&mut self, count: u64) -> io::Result<u64> {
+        let offset = self.end;
+        self.end += count * self.cluster_size();
+        self.file.set_len(self.end)?;
+
+        for i in 0..count {
+            self.update_refcount(offset + i * self.cluster_size(), 1)?;
+        }
+
+        Ok(offset)
+    }
+
+    /// Drops a reference to the cluster at `offset`, returning its space to the host
+    /// filesystem once it is unused.
+    fn free_cluster(&mut self, offset: u64) -> io::Result<()> {
+        if self.update_refcount(offset, -1)? == 0 {
+            // Not all filesystems can punch holes, and the cluster is unused either way.
+            let _ = device::punch_hole(&self.file, offset, self.cluster_size());
+        }
+
+        Ok(())
+    }
+
+    fn free_entry(&mut self, entry: u64) -> io::Result<()> {
+        match self.classify(entry)? {
+            Cluster::Unallocated | Cluster::Zero(0) => Ok(()),
+            Cluster::Zero(offset) | Cluster::Normal(offset) => self.free_cluster(offset),
+            Cluster::Compressed { offset, size } => {
+                let first = offset >> self.header.cluster_bits;
+                let last = (offset + size - 1) >> self.header.cluster_bits;
+                for cluster in first..=last {
+                    self.free_cluster(cluster << self.header.cluster_bits)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Sets the L2 entry for the guest cluster at `offset`, allocating an L2 table if needed.
+    fn set_l2_entry(&mut self, offset: u64, entry: u64) -> io::Result<()> {
+        let (l1_index, l2_index) = self.table_indices(offset);
+
+        let mut l2_offset = self.l1[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.allocate_clusters(1)?;
+            self.l2_cache.borrow_mut().insert(
+                l2_offset,
+                vec![0; self.header.entries_per_cluster() as usize],
+            );
+
+            self.l1[l1_index] = l2_offset | OFLAG_COPIED;
+            self.file.write_all_at(
+                &self.l1[l1_index].to_be_bytes(),
+                self.header.l1_table_offset + l1_index as u64 * 8,
+            )?;
+        }
+
+        self.with_l2_table(l2_offset, |table| table[l2_index] = entry)?;
+        self.file
+            .write_all_at(&entry.to_be_bytes(), l2_offset + l2_index as u64 * 8)
+    }
+
+    /// Stores a whole cluster compressed, packed after the previous compressed cluster where
+    /// possible, and returns its L2 entry.
+    fn write_compressed(&mut self, data: &[u8]) -> io::Result<Option<u64>> {
+        let Some(compressed) = compress(data) else {
+            return Ok(None);
+        };
+        let len = compressed.len() as u64;
+        let cluster_size = self.cluster_size();
+
+        // Continue in the cluster holding the previous compressed data if nothing has been
+        // allocated after it, so that any further clusters needed follow it.
+        let last_cluster = self.compressed_end / cluster_size * cluster_size;
+        let offset = if !self.compressed_end.is_multiple_of(cluster_size)
+            && self.end == last_cluster + cluster_size
+        {
+            let needed = (self.compressed_end + len).saturating_sub(self.end);
+            self.update_refcount(last_cluster, 1)?;
+            self.allocate_clusters(needed.div_ceil(cluster_size))?;
+            self.compressed_end
+        } else {
+            self.allocate_clusters(len.div_ceil(cluster_size))?
+        };
+
+        self.file.write_all_at(&compressed, offset)?;
+        self.compressed_end = offset + len;
+
+        let nr_sectors = ((offset + len - 1) >> 9) - (offset >> 9);
+        Ok(Some(
+            offset | nr_sectors << self.header.csize_shift() | OFLAG_COMPRESSED,
+        ))
+    }
+
+    /// Writes `data` at `in_cluster` within the guest cluster at `offset`.
+    fn write_cluster(&mut self, offset: u64, in_cluster: usize, data: &[u8]) -> io::Result<()> {
+        let entry = self.l2_entry(offset)?;
+        let cluster = self.classify(entry)?;
+
+        match cluster {
+            Cluster::Normal(host) => {
+                return self.file.write_all_at(data, host + in_cluster as u64);
+            }
+            // Leave unwritten clusters sparse.
+            Cluster::Unallocated | Cluster::Zero(_) if data.iter().all(|&b| b == 0) => {
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let mut contents = self.cluster_contents(entry)?;
+        contents[in_cluster..in_cluster + data.len()].copy_from_slice(data);
+
+        let whole = data.len() as u64 == self.cluster_size();
+        let compressed = match cluster {
+            Cluster::Unallocated if self.compress && whole => self.write_compressed(&contents)?,
+            _ => None,
+        };
+
+        let new_entry = match compressed {
+            Some(entry) => entry,
+            None => {
+                let host = match cluster {
+                    Cluster::Zero(host) if host != 0 => host,
+                    _ => self.allocate_clusters(1)?,
+                };
+                self.file.write_all_at(&contents, host)?;
+                host | OFLAG_COPIED
+            }
+        };
+
+        self.set_l2_entry(offset, new_entry)?;
+        if let Cluster::Compressed { .. } = cluster {
+            self.free_entry(entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` with the guest cluster, offset within it and length of each piece of the
+    /// `len` bytes at `offset`.
+    fn for_each_cluster(
+        &mut self,
+        offset: u64,
+        len: u64,
+        mut f: impl FnMut(&mut Self, u64, usize, usize) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if offset.saturating_add(len) > self.header.size {
+            return Err(device::out_of_range());
+        }
+
+        let cluster_size = self.cluster_size();
+        let mut pos = 0;
+        while pos < len {
+            let guest = offset + pos;
+            let in_cluster = guest % cluster_size;
+            let n = (cluster_size - in_cluster).min(len - pos);
+
+            f(self, guest - in_cluster, in_cluster as usize, pos as usize)?;
+            pos += n;
+        }
+
+        Ok(())
+    }
+}
+
+impl BlockDevice for Qcow2Device {
+    fn len(&self) -> u64 {
+        self.header.size
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset.saturating_add(buf.len() as u64) > self.header.size {
+            return Err(device::out_of_range());
+        }
+
+        let cluster_size = self.cluster_size();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let guest = offset + pos as u64;
+            let in_cluster = guest % cluster_size;
+            let n = ((cluster_size - in_cluster) as usize).min(buf.len() - pos);
+            let out = &mut buf[pos..pos + n];
+
+            match self.classify(self.l2_entry(guest)?)? {
+                Cluster::Unallocated | Cluster::Zero(_) => out.fill(0),
+                Cluster::Normal(host) => self.file.read_exact_at(out, host + in_cluster)?,
+                Cluster::Compressed { offset, size } => {
+                    let data = self.decompress(offset, size)?;
+                    out.copy_from_slice(&data[in_cluster as usize..in_cluster as usize + n]);
+                }
+            }
+
+            pos += n;
+        }
+
+        Ok(())
+    }
+
+    fn allocated(&self) -> Option<u64> {
+        device::allocated(&self.file)
+    }
+
+    fn file_id(&self) -> Option<(u64, u64)> {
+        device::file_id(&self.file)
+    }
+
+    fn data_extents(&self, offset: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        let end = offset.saturating_add(len);
+        if end > self.header.size {
+            return Err(device::out_of_range());
+        }
+
+        let cluster_size = self.cluster_size();
+        let l2_span = cluster_size * self.header.entries_per_cluster();
+        let mut extents: Vec<(u64, u64)> = Vec::new();
+        let mut guest = offset;
+
+        while guest < end {
+            let (l1_index, _) = self.table_indices(guest);
+            // Skip the whole range covered by a missing L2 table at once.
+            let next = match self.l1.get(l1_index).map(|e| e & OFFSET_MASK) {
+                None | Some(0) => (guest / l2_span + 1) * l2_span,
+                _ => {
+                    let next = (guest / cluster_size + 1) * cluster_size;
+                    let has_data = match self.classify(self.l2_entry(guest)?)? {
+                        Cluster::Unallocated | Cluster::Zero(_) => false,
+                        Cluster::Normal(_) | Cluster::Compressed { .. } => true,
+                    };
+
+                    if has_data {
+                        match extents.last_mut() {
+                            Some((_, last_end)) if *last_end == guest => *last_end = next.min(end),
+                            _ => extents.push((guest, next.min(end))),
+                        }
+                    }
+                    next
+                }
+            };
+
+            guest = next;
+        }
+
+        Ok(extents)
+    }
+}
+
+impl WritableBlockDevice for Qcow2Device {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let cluster_size = self.cluster_size() as usize;
+
+        self.for_each_cluster(offset, data.len() as u64, |dev, cluster, in_cluster, pos| {
+            let n = (cluster_size - in_cluster).min(data.len() - pos);
+            dev.write_cluster(cluster, in_cluster, &data[pos..pos + n])
+        })
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let cluster_size = self.cluster_size() as usize;
+
+        self.for_each_cluster(offset, len, |dev, cluster, in_cluster, pos| {
+            let n = (cluster_size - in_cluster).min(len as usize - pos);
+            if n < cluster_size {
+                return dev.write_cluster(cluster, in_cluster, &vec![0; n]);
+            }
+
+            let entry = dev.l2_entry(cluster)?;
+            if entry == 0 {
+                return Ok(());
+            }
+
+            // Without a backing file, an unallocated cluster reads as zeros.
+            dev.set_l2_entry(cluster, 0)?;
+            dev.free_entry(entry)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a new file in the temporary directory, already unlinked so that it goes away
+    /// with the test.
+    fn temp_file(name: &str) -> File {
+        let path = std::env::temp_dir().join(format!("fisic-{}-{}", std::process::id(), name));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        file
+    }
+
+    fn reopen(device: Qcow2Device) -> Qcow2Device {
+        Qcow2Device::from_file(device.file).unwrap()
+    }
+
+    fn cluster_at(device: &Qcow2Device, offset: u64) -> Cluster {
+        device.classify(device.l2_entry(offset).unwrap()).unwrap()
+    }
+
+    fn read_all(device: &Qcow2Device) -> Vec<u8> {
+        let mut data = vec![0; device.len() as usize];
+        device.read_at(0, &mut data).unwrap();
+
+        data
+    }
+
+    /// Data that does not compress, and has no zero bytes.
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8 | 1
+            })
+            .collect()
+    }
+
+    fn stored_refcount(device: &Qcow2Device, cluster: u64) -> u64 {
+        let block_entries = device.header.refcount_block_entries();
+        let block = device
+            .refcount_table
+            .get((cluster / block_entries) as usize)
+            .map_or(0, |e| e & OFFSET_MASK);
+        if block == 0 {
+            return 0;
+        }
+
+        let width = 1 << (device.header.refcount_order - 3);
+        let mut bytes = [0; 8];
+        device
+            .file
+            .read_exact_at(
+                &mut bytes[8 - width..],
+                block + (cluster % block_entries) * width as u64,
+            )
+            .unwrap();
+
+        u64::from_be_bytes(bytes)
+    }
+
+    /// Checks that the reference count of every cluster of the file matches the number of
+    /// references to it from the header, the tables and the L2 entries.
+    fn check_refcounts(device: &Qcow2Device) {
+        let header = &device.header;
+        let cluster_size = device.cluster_size();
+        let mut expected = HashMap::new();
+        let mut add = |offset: u64, len: u64| {
+            for cluster in offset / cluster_size..(offset + len).div_ceil(cluster_size) {
+                *expected.entry(cluster).or_insert(0) += 1;
+            }
+        };
+
+        add(0, HEADER_SIZE as u64);
+        add(
+            header.refcount_table_offset,
+            header.refcount_table_clusters as u64 * cluster_size,
+        );
+        add(header.l1_table_offset, header.l1_size as u64 * 8);
+        for block in device.refcount_table.iter().map(|e| e & OFFSET_MASK) {
+            if block != 0 {
+                add(block, cluster_size);
+            }
+        }
+
+        for (l1_index, l2_offset) in device.l1.iter().map(|e| e & OFFSET_MASK).enumerate() {
+            if l2_offset == 0 {
+                continue;
+            }
+            add(l2_offset, cluster_size);
+
+            for l2_index in 0..header.entries_per_cluster() {
+                let guest =
+                    (l1_index as u64 * header.entries_per_cluster() + l2_index) * cluster_size;
+                match cluster_at(device, guest) {
+                    Cluster::Unallocated | Cluster::Zero(0) => {}
+                    Cluster::Zero(host) | Cluster::Normal(host) => add(host, cluster_size),
+                    Cluster::Compressed { offset, size } => add(offset, size),
+                }
+            }
+        }
+
+        for cluster in 0..device.end / cluster_size {
+            assert_eq!(
+                stored_refcount(device, cluster),
+                expected.get(&cluster).copied().unwrap_or(0),
+                "refcount of cluster {}",
+                cluster
+            );
+        }
+    }
+
+    #[test]
+    fn scattered_writes_round_trip() {
+        // Small clusters, so that an L2 table spans 32 KiB of the image and the first refcount
+        // table only covers 8 MiB of the file.
+        let size = 16 * 1024 * 1024;
+        let mut device =
+            Qcow2Device::create_with_cluster_bits(temp_file("scattered.qcow2"), size, 9).unwrap();
+        let mut expected = vec![0; size as usize];
+        let initial_refcount_table = device.header.refcount_table_offset;
+
+        let writes = [
+            (0, noise(1, 100)),
+            // Across the boundary between the first two L2 tables.
+            (32 * 1024 - 100, noise(2, 300)),
+            (5 * 1024 * 1024 + 7, noise(3, 3000)),
+            // Enough data to outgrow the refcount table.
+            (6 * 1024 * 1024, noise(4, 9 * 1024 * 1024)),
+            // Over data written before.
+            (50, noise(5, 100)),
+            (6 * 1024 * 1024 + 1000, vec![0; 2000]),
+        ];
+        for (offset, data) in &writes {
+            device.write_at(*offset, data).unwrap();
+            expected[*offset as usize..*offset as usize + data.len()].copy_from_slice(data);
+        }
+
+        assert_ne!(device.header.refcount_table_offset, initial_refcount_table);
+        check_refcounts(&device);
+
+        let device = reopen(device);
+        assert!(read_all(&device) == expected);
+        check_refcounts(&device);
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let size = 4 * 1024 * 1024;
+        let mut device = Qcow2Device::create(temp_file("compressed.qcow2"), size).unwrap();
+        device.set_compression(true);
+        let cluster_size = device.cluster_size();
+
+        let text: Vec<u8> = b"fisic compresses whole clusters. "
+            .iter()
+            .copied()
+            .cycle()
+            .take(20 * cluster_size as usize)
+            .collect();
+        let mut expected = vec![0; size as usize];
+        device.write_at(0, &text).unwrap();
+        expected[..text.len()].copy_from_slice(&text);
+
+        // A partial cluster, which is stored as is.
+        let tail = noise(6, 1000);
+        let tail_offset = 3 * 1024 * 1024 + 10;
+        device.write_at(tail_offset, &tail).unwrap();
+        expected[tail_offset as usize..tail_offset as usize + tail.len()].copy_from_slice(&tail);
+
+        let mut device = reopen(device);
+        assert!(matches!(cluster_at(&device, 0), Cluster::Compressed { .. }));
+        assert!(matches!(
+            cluster_at(&device, tail_offset),
+            Cluster::Normal(_)
+        ));
+        // The compressed clusters are packed together.
+        assert!(device.end < 10 * cluster_size);
+        assert!(read_all(&device) == expected);
+        check_refcounts(&device);
+
+        // Writing into a compressed cluster stores it uncompressed and drops the reference to
+        // the cluster it shared with its neighbours.
+        let patch = noise(7, 10);
+        device.write_at(cluster_size + 5, &patch).unwrap();
+        expected[cluster_size as usize + 5..cluster_size as usize + 15].copy_from_slice(&patch);
+
+        assert!(matches!(
+            cluster_at(&device, cluster_size),
+            Cluster::Normal(_)
+        ));
+        assert!(read_all(&device) == expected);
+        check_refcounts(&device);
+    }
+
+    #[test]
+    fn punch_hole_frees_clusters() {
+        let size = 4 * 1024 * 1024;
+        let mut device = Qcow2Device::create(temp_file("punch.qcow2"), size).unwrap();
+        let cluster_size = device.cluster_size();
+
+        let data = noise(8, 4 * cluster_size as usize);
+        device.write_at(0, &data).unwrap();
+        let mut expected = vec![0; size as usize];
+        expected[..data.len()].copy_from_slice(&data);
+
+        let Cluster::Normal(freed) = cluster_at(&device, cluster_size) else {
+            panic!("cluster 1 is not allocated");
+        };
+
+        // Two whole clusters and part of the next one.
+        let len = 2 * cluster_size + 100;
+        device.punch_hole(cluster_size, len).unwrap();
+        expected[cluster_size as usize..(cluster_size + len) as usize].fill(0);
+
+        assert_eq!(device.l2_entry(cluster_size).unwrap(), 0);
+        assert_eq!(device.l2_entry(2 * cluster_size).unwrap(), 0);
+        assert_ne!(device.l2_entry(3 * cluster_size).unwrap(), 0);
+        assert_eq!(stored_refcount(&device, freed / cluster_size), 0);
+        assert_eq!(
+            device.data_extents(0, size).unwrap(),
+            vec![(0, cluster_size), (3 * cluster_size, 4 * cluster_size)]
+        );
+        check_refcounts(&device);
+
+        let device = reopen(device);
+        assert!(read_all(&device) == expected);
+        check_refcounts(&device);
+    }
+}
@@ -0,0 +1,253 @@
+//! The VHD format used by Hyper-V and Azure.
+//!
+//! A fixed VHD is the raw contents of the disk followed by a 512-byte footer. A dynamic VHD
+//! starts with a copy of the footer and a header pointing to the block allocation table (BAT),
+//! which gives the sector at which each block of the disk is stored, or none if the block has
+//! never been written. Each stored block is preceded by a bitmap of its sectors that hold data.
+//! All fields are big-endian.
+
+use std::time::{Duration, SystemTime};
+
+use nuuid::Uuid;
+
+pub const FOOTER_SIZE: usize = 512;
+pub const DYNAMIC_HEADER_SIZE: usize = 1024;
+
+/// Size of the blocks of dynamic images, 2 MiB as with Hyper-V.
+pub const DYNAMIC_BLOCK_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Size of the bitmap in front of each block, one bit per sector padded to a whole sector.
+pub const BLOCK_BITMAP_SIZE: u64 = SECTOR_SIZE;
+
+/// BAT entry of a block that is not stored.
+pub const UNALLOCATED: u32 = u32::MAX;
+
+/// Largest disk a VHD can describe, 2040 GiB.
+pub const MAX_SIZE: u64 = 0xff00_0000 * SECTOR_SIZE;
+
+/// Azure only accepts disks whose size is a whole number of MiB.
+pub const SIZE_ALIGNMENT: u64 = 1024 * 1024;
+
+const SECTOR_SIZE: u64 = 512;
+
+const FOOTER_COOKIE: [u8; 8] = *b"conectix";
+const DYNAMIC_HEADER_COOKIE: [u8; 8] = *b"cxsparse";
+
+/// The reserved feature bit, which must always be set.
+const FEATURES: u32 = 2;
+const VERSION: u32 = 0x0001_0000;
+/// Data offset of a fixed image, which has no header.
+const NO_DATA_OFFSET: u64 = u64::MAX;
+
+const CREATOR_APPLICATION: [u8; 4] = *b"fsic";
+const CREATOR_HOST_OS: [u8; 4] = *b"Wi2k";
+
+/// 2000-01-01 00:00:00 UTC, from which VHD timestamps are counted.
+const VHD_EPOCH: Duration = Duration::from_secs(946_684_800);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VhdType {
+    Fixed,
+    Dynamic,
+}
+
+impl VhdType {
+    fn code(self) -> u32 {
+        match self {
+            VhdType::Fixed => 2,
+            VhdType::Dynamic => 3,
+        }
+    }
+}
+
+/// Cylinders, heads and sectors per track, computed as in the VHD specification.
+///
+/// Disks larger than the geometry can describe are capped at 65535 * 16 * 255 sectors.
+pub fn geometry(size: u64) -> (u16, u8, u8) {
+    let total_sectors = (size / SECTOR_SIZE).min(65535 * 16 * 255);
+
+    let (sectors_per_track, heads, cylinder_times_heads) = if total_sectors >= 65535 * 16 * 63 {
+        (255, 16, total_sectors / 255)
+    } else {
+        let mut sectors_per_track = 17;
+        let mut cylinder_times_heads = total_sectors / sectors_per_track;
+        let mut heads = cylinder_times_heads.div_ceil(1024).max(4);
+
+        if cylinder_times_heads >= heads * 1024 || heads > 16 {
+            sectors_per_track = 31;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        if cylinder_times_heads >= heads * 1024 {
+            sectors_per_track = 63;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+
+        (sectors_per_track, heads, cylinder_times_heads)
+    };
+
+    (
+        (cylinder_times_heads / heads) as u16,
+        heads as u8,
+        sectors_per_track as u8,
+    )
+}
+
+/// One's complement of the sum of all bytes, taken with the checksum field zeroed.
+fn checksum(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32))
+}
+
+/// Seconds since the VHD epoch, or zero for earlier times.
+fn timestamp(time: SystemTime) -> u32 {
+    time.duration_since(SystemTime::UNIX_EPOCH + VHD_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+/// The footer at the end of every VHD.
+#[derive(Debug, Clone, Copy)]
+pub struct Footer {
+    pub vhd_type: VhdType,
+    /// Size of the disk in bytes.
+    pub size: u64,
+    pub uuid: Uuid,
+    pub created: SystemTime,
+}
+
+impl Footer {
+    pub fn encode(&self) -> [u8; FOOTER_SIZE] {
+        let mut b = [0; FOOTER_SIZE];
+
+        let data_offset = match self.vhd_type {
+            VhdType::Fixed => NO_DATA_OFFSET,
+            // The dynamic header follows the copy of the footer at the start of the file.
+            VhdType::Dynamic => FOOTER_SIZE as u64,
+        };
+        let (cylinders, heads, sectors_per_track) = geometry(self.size);
+
+        b[0..8].copy_from_slice(&FOOTER_COOKIE);
+        b[8..12].copy_from_slice(&FEATURES.to_be_bytes());
+        b[12..16].copy_from_slice(&VERSION.to_be_bytes());
+        b[16..24].copy_from_slice(&data_offset.to_be_bytes());
+        b[24..28].copy_from_slice(&timestamp(self.created).to_be_bytes());
+        b[28..32].copy_from_slice(&CREATOR_APPLICATION);
+        b[32..36].copy_from_slice(&VERSION.to_be_bytes());
+        b[36..40].copy_from_slice(&CREATOR_HOST_OS);
+        // Original and current size.
+        b[40..48].copy_from_slice(&self.size.to_be_bytes());
+        b[48..56].copy_from_slice(&self.size.to_be_bytes());
+        b[56..58].copy_from_slice(&cylinders.to_be_bytes());
+        b[58] = heads;
+        b[59] = sectors_per_track;
+        b[60..64].copy_from_slice(&self.vhd_type.code().to_be_bytes());
+        b[68..84].copy_from_slice(&self.uuid.to_bytes());
+
+        let checksum = checksum(&b);
+        b[64..68].copy_from_slice(&checksum.to_be_bytes());
+
+        b
+    }
+}
+
+/// The header of a dynamic VHD, which locates the BAT.
+#[derive(Debug, Clone, Copy)]
+pub struct DynamicHeader {
+    /// Offset of the BAT in the file.
+    pub table_offset: u64,
+    /// Number of entries in the BAT, one for each block of the disk.
+    pub max_table_entries: u32,
+}
+
+impl DynamicHeader {
+    pub fn encode(&self) -> [u8; DYNAMIC_HEADER_SIZE] {
+        let mut b = [0; DYNAMIC_HEADER_SIZE];
+
+        b[0..8].copy_from_slice(&DYNAMIC_HEADER_COOKIE);
+        // Unused, as there is no further header.
+        b[8..16].copy_from_slice(&NO_DATA_OFFSET.to_be_bytes());
+        b[16..24].copy_from_slice(&self.table_offset.to_be_bytes());
+        b[24..28].copy_from_slice(&VERSION.to_be_bytes());
+        b[28..32].copy_from_slice(&self.max_table_entries.to_be_bytes());
+        b[32..36].copy_from_slice(&(DYNAMIC_BLOCK_SIZE as u32).to_be_bytes());
+
+        let checksum = checksum(&b);
+        b[36..40].copy_from_slice(&checksum.to_be_bytes());
+
+        b
+    }
+}
+
+/// Size of a BAT with `entries` entries, padded to a whole sector.
+pub fn table_size(entries: u32) -> u64 {
+    (entries as u64 * 4).next_multiple_of(SECTOR_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn be_u32(b: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(b[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Checks the checksum at `offset` against the one's complement of the sum of the other
+    /// bytes.
+    fn checksum_valid(b: &[u8], offset: usize) -> bool {
+        let sum = b
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !(offset..offset + 4).contains(i))
+            .fold(0u32, |sum, (_, &b)| sum + b as u32);
+
+        be_u32(b, offset) == !sum
+    }
+
+    #[test]
+    fn geometry_matches_specification() {
+        assert_eq!(geometry(127 * MIB), (1019, 15, 17));
+        assert_eq!(geometry(2048 * MIB), (4161, 16, 63));
+        assert_eq!(geometry(30 * 1024 * MIB), (62415, 16, 63));
+        assert_eq!(geometry(127 * 1024 * MIB), (65278, 16, 255));
+        // Capped at 65535 * 16 * 255 sectors.
+        assert_eq!(geometry(65535 * 16 * 255 * 512), (65535, 16, 255));
+        assert_eq!(geometry(MAX_SIZE), (65535, 16, 255));
+    }
+
+    #[test]
+    fn footer_checksum() {
+        let footer = Footer {
+            vhd_type: VhdType::Dynamic,
+            size: 64 * MIB,
+            uuid: Uuid::parse("5d7333b4-77b3-417c-a1a5-001bb35b7961").unwrap(),
+            created: SystemTime::UNIX_EPOCH + VHD_EPOCH + Duration::from_secs(1000),
+        }
+        .encode();
+
+        assert_eq!(&footer[0..8], b"conectix");
+        assert_eq!(u64::from_be_bytes(footer[16..24].try_into().unwrap()), 512);
+        assert_eq!(be_u32(&footer, 24), 1000);
+        assert_eq!(
+            u64::from_be_bytes(footer[48..56].try_into().unwrap()),
+            64 * MIB
+        );
+        assert_eq!(be_u32(&footer, 60), 3);
+        assert!(checksum_valid(&footer, 64));
+    }
+
+    #[test]
+    fn dynamic_header_checksum() {
+        let header = DynamicHeader {
+            table_offset: 1536,
+            max_table_entries: 32,
+        }
+        .encode();
+
+        assert_eq!(&header[0..8], b"cxsparse");
+        assert_eq!(be_u32(&header, 28), 32);
+        assert_eq!(be_u32(&header, 32), DYNAMIC_BLOCK_SIZE as u32);
+        assert!(checksum_valid(&header, 36));
+    }
+}
@@ -1,3 +1,4 @@
 pub mod pt;
+pub mod fs;
 pub mod image;
 pub mod actions;
@@ -1,14 +1,34 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
 use clap::{Args, Parser, Subcommand, ValueEnum};
 use color_eyre::eyre::{eyre, Result};
 use fisic::{
-    actions::Action,
     actions::{
-        create::{invoke as InvokeCreate, CreateActionArgs},
+        convert::ConvertArgs,
+        create::{invoke as InvokeCreate, Allocation, CreateActionArgs},
+        disk::SetDiskArgs,
+        export::ExportArgs,
+        info::InfoArgs,
         init::InitActionArgs,
-        partitions::ListPartitionsArgs,
+        partitions::{
+            BackupPartitionsArgs, DumpPartitionsArgs, ListPartitionsArgs, LoadPartitionsArgs,
+            ReadPartitionArgs, RestorePartitionsArgs, SetPartitionArgs, SortPartitionsArgs,
+            WritePartitionArgs,
+        },
+        raw::RawWriteArgs,
+        regenerate::RegenerateIdsArgs,
+        sparsify::SparsifyArgs,
+        wipe::{WipeArgs, WipeDryRunArgs},
+    },
+    actions::{Action, OutputFormat},
+    image::{
+        vhd::VhdType, BlockDevice, FileDevice, Image, ImageError, ImageFormat, Qcow2Device,
+        WritableBlockDevice,
     },
-    image::Image,
-    pt::PartitionTableType,
+    pt::{gpt::Layout, ids::IdSource, read_partition_table, PartitionTableType},
 };
 
 #[derive(Parser, Debug)]
@@ -19,6 +39,11 @@ struct FisicArgs {
 
     #[arg(short, required = true)]
     image: String,
+
+    /// Format of the image, detected from its contents with auto except on block devices,
+    /// which are always raw
+    #[arg(long, default_value = "auto")]
+    format: InputFormatType,
 }
 
 #[derive(Args, Debug)]
@@ -31,36 +56,381 @@ struct CreateAction {
 
     #[arg(long)]
     init_pt: Option<InitType>,
+
+    #[arg(long, default_value = "raw")]
+    format: FormatType,
+
+    /// How to allocate space for the image
+    #[arg(long, default_value = "sparse")]
+    allocation: AllocationType,
+
+    /// Derive GUIDs and disk signatures from this seed, $FISIC_SEED or $SOURCE_DATE_EPOCH
+    /// instead of generating random ones
+    #[arg(long)]
+    seed: Option<String>,
+
+    /// Number of GPT partition entries
+    #[arg(long, default_value_t = 128)]
+    entries: usize,
+
+    /// LBA of the primary GPT partition entry array, e.g. to keep space free for a bootloader
+    #[arg(long, default_value_t = 2)]
+    entries_lba: u64,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 enum InitType {
     MBR,
     GPT,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum FormatType {
+    Raw,
+    Qcow2,
+}
+
+impl From<FormatType> for ImageFormat {
+    fn from(value: FormatType) -> Self {
+        match value {
+            FormatType::Raw => ImageFormat::Raw,
+            FormatType::Qcow2 => ImageFormat::Qcow2,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum InputFormatType {
+    Raw,
+    Qcow2,
+    Auto,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum ExportType {
+    /// Fixed VHD, the contents of the disk followed by a footer, as required by Azure
+    Vhd,
+    /// Dynamic VHD, which only stores the blocks that are not all zeros
+    VhdDynamic,
+}
+
+impl From<ExportType> for VhdType {
+    fn from(value: ExportType) -> Self {
+        match value {
+            ExportType::Vhd => VhdType::Fixed,
+            ExportType::VhdDynamic => VhdType::Dynamic,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum AllocationType {
+    /// Only set the size, blocks are allocated as they are written
+    Sparse,
+    /// Reserve all blocks with fallocate
+    Full,
+    /// Write zeros over the whole image
+    Zero,
+}
+
+impl From<AllocationType> for Allocation {
+    fn from(value: AllocationType) -> Self {
+        match value {
+            AllocationType::Sparse => Allocation::Sparse,
+            AllocationType::Full => Allocation::Full,
+            AllocationType::Zero => Allocation::Zero,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum OutputType {
+    Text,
+    Json,
+}
+
+impl From<OutputType> for OutputFormat {
+    fn from(value: OutputType) -> Self {
+        match value {
+            OutputType::Text => OutputFormat::Text,
+            OutputType::Json => OutputFormat::Json,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct InfoAction {
+    #[arg(long, default_value = "text")]
+    output: OutputType,
+}
+
 #[derive(Args, Debug)]
 struct InitAction {
     #[arg(long)]
     init_type: InitType,
+
+    /// Derive GUIDs and disk signatures from this seed, $FISIC_SEED or $SOURCE_DATE_EPOCH
+    /// instead of generating random ones
+    #[arg(long)]
+    seed: Option<String>,
+
+    /// Number of GPT partition entries
+    #[arg(long, default_value_t = 128)]
+    entries: usize,
+
+    /// LBA of the primary GPT partition entry array, e.g. to keep space free for a bootloader
+    #[arg(long, default_value_t = 2)]
+    entries_lba: u64,
 }
 
 #[derive(Subcommand, Debug)]
 enum ActionCommand {
     Create(CreateAction),
     Init(InitAction),
-    Info,
+    Info(InfoAction),
     Partitions {
         #[command(subcommand)]
         action: PartitionsAction,
     },
+    Disk {
+        #[command(subcommand)]
+        action: DiskAction,
+    },
+    Raw {
+        #[command(subcommand)]
+        action: RawAction,
+    },
+    /// Replace the disk and partition GUIDs and the MBR disk signature with fresh ones
+    RegenerateIds {
+        /// Also rewrite matching PARTUUID= references in fstab and boot loader files of FAT and ext
+        /// filesystems
+        #[arg(long, action)]
+        update_references: bool,
+    },
+    /// Erase partition table and filesystem signatures, like wipefs
+    Wipe {
+        /// List the signatures that would be erased without erasing them
+        #[arg(long, action)]
+        dry_run: bool,
+    },
+    /// Copy the image to a new file in another format
+    Convert {
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[arg(long)]
+        format: FormatType,
+
+        /// Compress the clusters of a qcow2 image
+        #[arg(long, action)]
+        compress: bool,
+
+        #[arg(long, action)]
+        overwrite: bool,
+    },
+    /// Write the image to a new file in a format for another hypervisor
+    Export {
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[arg(long)]
+        format: ExportType,
+
+        #[arg(long, action)]
+        overwrite: bool,
+
+        /// Derive the UUID from this seed, $FISIC_SEED or $SOURCE_DATE_EPOCH instead of generating
+        /// a random one
+        #[arg(long)]
+        seed: Option<String>,
+    },
+    /// Deallocate all-zero blocks of the image, leaving its contents unchanged
+    Sparsify {
+        /// Size of the blocks checked for zeros, such as 4K or 1M
+        #[arg(long, default_value = "4K")]
+        granularity: String,
+    },
+}
+
+impl ActionCommand {
+    /// Returns true for commands that only inspect the image, which can then be opened without
+    /// write permission.
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            ActionCommand::Info(_)
+                | ActionCommand::Convert { .. }
+                | ActionCommand::Export { .. }
+                | ActionCommand::Wipe { dry_run: true }
+                | ActionCommand::Partitions {
+                    action: PartitionsAction::List { .. }
+                        | PartitionsAction::Dump
+                        | PartitionsAction::Backup { .. }
+                        | PartitionsAction::Read { .. },
+                }
+        )
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum RawAction {
+    /// Write a file at a fixed offset outside the partitions, e.g. a bootloader
+    Write {
+        /// Byte offset such as 8K, or a sector number with an `s` suffix such as 64s
+        #[arg(long)]
+        offset: String,
+
+        #[arg(long)]
+        file: PathBuf,
+
+        /// Write even if the data overlaps the partition table or a partition
+        #[arg(long, action)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DiskAction {
+    /// Change properties of the partition table itself
+    Set {
+        /// New disk GUID
+        #[arg(long)]
+        uuid: Option<String>,
+    },
 }
 
 #[derive(Subcommand, Debug)]
 enum PartitionsAction {
-    List,
+    List {
+        #[arg(long, default_value = "text")]
+        output: OutputType,
+    },
+    /// Write the partition layout to stdout as an sfdisk script
+    Dump,
+    /// Replace the partition table with the layout in an sfdisk script read from stdin
+    Load {
+        /// Derive GUIDs and disk signatures from this seed, $FISIC_SEED or $SOURCE_DATE_EPOCH
+        /// instead of generating random ones
+        #[arg(long)]
+        seed: Option<String>,
+    },
+    /// Save the MBR, both GPT headers and both entry arrays to a file
+    Backup { file: PathBuf },
+    /// Restore the partition tables from a file written by `backup`
+    Restore {
+        file: PathBuf,
+
+        /// Move the backup GPT to the end of the image, e.g. after it has been resized
+        #[arg(long, action)]
+        relocate_backup: bool,
+    },
+    /// Compact the partition entries and order them by starting LBA
+    Sort,
+    /// Change the type, name or unique GUID of a GPT partition in place
+    Set {
+        /// Partition number, starting at 1
+        number: usize,
+
+        /// New type GUID, or one of the sfdisk shortcuts L, S, U, R and V
+        #[arg(long = "type")]
+        ptype: Option<String>,
+
+        #[arg(long)]
+        name: Option<String>,
+
+        /// New unique partition GUID
+        #[arg(long)]
+        uuid: Option<String>,
+    },
+    /// Copy a file, such as a filesystem image, into a partition
+    Write {
+        /// Partition number, starting at 1, or GPT partition name
+        partition: String,
+
+        #[arg(long)]
+        file: PathBuf,
+
+        /// Zero the rest of the partition after the end of the file
+        #[arg(long, action)]
+        zero_tail: bool,
+    },
+    /// Copy the contents of a partition to a sparse file
+    Read {
+        /// Partition number, starting at 1, or GPT partition name
+        partition: String,
+
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Stop at the end of the ext, FAT or ISO9660 filesystem instead of the partition
+        #[arg(long, action)]
+        truncate: bool,
+
+        #[arg(long, action)]
+        overwrite: bool,
+    },
+}
+
+/// Parses an image offset given in bytes with an optional unit, or in sectors with an `s` suffix.
+fn parse_offset(value: &str) -> Result<u64> {
+    let bytes = match value.strip_suffix('s') {
+        Some(sectors) => sectors
+            .parse::<u64>()?
+            .checked_mul(512)
+            .ok_or_else(|| eyre!("offset out of range"))?,
+        None => parse_size::Config::new()
+            .with_binary()
+            .parse_size(value)
+            .map_err(|e| eyre!("offset parsing failed: {}", e))?,
+    };
+
+    Ok(bytes)
+}
+
+/// Chooses where new identifiers come from: an explicit seed, then `FISIC_SEED`, then
+/// `SOURCE_DATE_EPOCH` for reproducible builds, and otherwise random generation.
+///
+/// The file name of `path` is mixed into a seed taken from `SOURCE_DATE_EPOCH`, so that the
+/// images built in the same pipeline do not all get the same identifiers.
+fn id_source(seed: Option<String>, path: impl AsRef<Path>) -> IdSource {
+    let epoch_seed = || {
+        let epoch = std::env::var("SOURCE_DATE_EPOCH").ok()?;
+        let name = path.as_ref().file_name().unwrap_or_default();
+        Some(format!("{}/{}", epoch, name.to_string_lossy()))
+    };
+
+    match seed
+        .or_else(|| std::env::var("FISIC_SEED").ok())
+        .or_else(epoch_seed)
+    {
+        Some(seed) => IdSource::Seeded(seed),
+        None => IdSource::Random,
+    }
 }
 
+/// Returns the format of the existing image at `path`.
+fn image_format(format: InputFormatType, path: &str) -> ImageFormat {
+    match format {
+        InputFormatType::Raw => ImageFormat::Raw,
+        InputFormatType::Qcow2 => ImageFormat::Qcow2,
+        InputFormatType::Auto => ImageFormat::detect(path),
+    }
+}
+
+/// Returns `SOURCE_DATE_EPOCH` if it is set, for reproducible builds, and otherwise the current
+/// time.
+fn creation_time() -> SystemTime {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .map_or_else(SystemTime::now, |secs| {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+        })
+}
+
+/// Converts the arguments of `create`, leaving the identifiers random: they depend on the path of
+/// the image, which the caller sets them from with [`id_source`].
 impl TryFrom<CreateAction> for CreateActionArgs {
     type Error = color_eyre::eyre::Error;
 
@@ -75,10 +445,18 @@ impl TryFrom<CreateAction> for CreateActionArgs {
             size: parse_size::parse_size(value.size)
                 .map_err(|e| eyre!("size parsing failed: {}", e))?
                 .try_into()?,
+            format: value.format.into(),
+            allocation: value.allocation.into(),
+            ids: IdSource::Random,
+            gpt_layout: Layout {
+                nr_entries: value.entries,
+                entries_lba: value.entries_lba,
+            },
         })
     }
 }
 
+/// Converts the arguments of `init`, leaving the identifiers random like [`CreateActionArgs`].
 impl TryFrom<InitAction> for InitActionArgs {
     type Error = color_eyre::eyre::Error;
 
@@ -88,32 +466,278 @@ impl TryFrom<InitAction> for InitActionArgs {
                 InitType::MBR => PartitionTableType::MBR,
                 InitType::GPT => PartitionTableType::GPT,
             },
+            ids: IdSource::Random,
+            gpt_layout: Layout {
+                nr_entries: value.entries,
+                entries_lba: value.entries_lba,
+            },
         })
     }
 }
 
+/// Runs an action that only inspects an existing image.
+fn inspect(image: &mut Image<impl BlockDevice>, action: ActionCommand, device: &str) -> Result<()> {
+    match action {
+        ActionCommand::Info(a) => fisic::actions::info::InfoAction::invoke(
+            image,
+            InfoArgs {
+                output: a.output.into(),
+                device: device.to_string(),
+            },
+        )?,
+        ActionCommand::Partitions {
+            action: PartitionsAction::List { output },
+        } => fisic::actions::partitions::ListPartitionsAction::invoke(
+            image,
+            ListPartitionsArgs {
+                output: output.into(),
+                device: device.to_string(),
+            },
+        )?,
+        ActionCommand::Partitions {
+            action: PartitionsAction::Dump,
+        } => fisic::actions::partitions::DumpPartitionsAction::invoke(
+            image,
+            DumpPartitionsArgs {
+                device: device.to_string(),
+            },
+        )?,
+        ActionCommand::Partitions {
+            action: PartitionsAction::Backup { file },
+        } => fisic::actions::partitions::BackupPartitionsAction::invoke(
+            image,
+            BackupPartitionsArgs { path: file },
+        )?,
+        ActionCommand::Partitions {
+            action:
+                PartitionsAction::Read {
+                    partition,
+                    output,
+                    truncate,
+                    overwrite,
+                },
+        } => fisic::actions::partitions::ReadPartitionAction::invoke(
+            image,
+            ReadPartitionArgs {
+                partition,
+                path: output,
+                truncate,
+                overwrite,
+            },
+        )?,
+        ActionCommand::Convert {
+            output,
+            format,
+            compress,
+            overwrite,
+        } => fisic::actions::convert::ConvertAction::invoke(
+            image,
+            ConvertArgs {
+                path: output,
+                format: format.into(),
+                compress,
+                overwrite,
+            },
+        )?,
+        ActionCommand::Export {
+            output,
+            format,
+            overwrite,
+            seed,
+        } => fisic::actions::export::ExportAction::invoke(
+            image,
+            ExportArgs {
+                ids: id_source(seed, &output),
+                path: output,
+                format: format.into(),
+                overwrite,
+                created: creation_time(),
+            },
+        )?,
+        ActionCommand::Wipe { dry_run: true } => {
+            fisic::actions::wipe::WipeDryRunAction::invoke(image, WipeDryRunArgs {})?
+        }
+        _ => panic!("unsupported"),
+    }
+
+    Ok(())
+}
+
+/// Runs an action that modifies the existing image at `path`.
+fn invoke(
+    image: &mut Image<impl WritableBlockDevice>,
+    action: ActionCommand,
+    path: &str,
+) -> Result<()> {
+    match action {
+        ActionCommand::Init(a) => fisic::actions::init::InitAction::invoke(
+            image,
+            InitActionArgs {
+                ids: id_source(a.seed.clone(), path),
+                ..a.try_into()?
+            },
+        )?,
+        ActionCommand::Partitions {
+            action: PartitionsAction::Load { seed },
+        } => fisic::actions::partitions::LoadPartitionsAction::invoke(
+            image,
+            LoadPartitionsArgs {
+                script: std::io::read_to_string(std::io::stdin())?,
+                ids: id_source(seed, path),
+            },
+        )?,
+        ActionCommand::Partitions {
+            action:
+                PartitionsAction::Restore {
+                    file,
+                    relocate_backup,
+                },
+        } => fisic::actions::partitions::RestorePartitionsAction::invoke(
+            image,
+            RestorePartitionsArgs {
+                path: file,
+                relocate_backup,
+            },
+        )?,
+        ActionCommand::Partitions {
+            action:
+                PartitionsAction::Set {
+                    number,
+                    ptype,
+                    name,
+                    uuid,
+                },
+        } => fisic::actions::partitions::SetPartitionAction::invoke(
+            image,
+            SetPartitionArgs {
+                number,
+                ptype,
+                name,
+                uuid,
+            },
+        )?,
+        ActionCommand::Partitions {
+            action: PartitionsAction::Sort,
+        } => {
+            fisic::actions::partitions::SortPartitionsAction::invoke(image, SortPartitionsArgs {})?
+        }
+        ActionCommand::Partitions {
+            action:
+                PartitionsAction::Write {
+                    partition,
+                    file,
+                    zero_tail,
+                },
+        } => fisic::actions::partitions::WritePartitionAction::invoke(
+            image,
+            WritePartitionArgs {
+                partition,
+                path: file,
+                zero_tail,
+            },
+        )?,
+        ActionCommand::Disk {
+            action: DiskAction::Set { uuid },
+        } => fisic::actions::disk::SetDiskAction::invoke(image, SetDiskArgs { uuid })?,
+        ActionCommand::RegenerateIds { update_references } => {
+            fisic::actions::regenerate::RegenerateIdsAction::invoke(
+                image,
+                RegenerateIdsArgs { update_references },
+            )?
+        }
+        ActionCommand::Wipe { dry_run: false } => {
+            fisic::actions::wipe::WipeAction::invoke(image, WipeArgs {})?
+        }
+        ActionCommand::Sparsify { granularity } => {
+            fisic::actions::sparsify::SparsifyAction::invoke(
+                image,
+                SparsifyArgs {
+                    granularity: parse_offset(&granularity)?.try_into()?,
+                },
+            )?
+        }
+        ActionCommand::Raw {
+            action:
+                RawAction::Write {
+                    offset,
+                    file,
+                    force,
+                },
+        } => fisic::actions::raw::RawWriteAction::invoke(
+            image,
+            RawWriteArgs {
+                offset: parse_offset(&offset)?,
+                path: file,
+                force,
+            },
+        )?,
+        _ => panic!("unsupported"),
+    }
+
+    Ok(())
+}
+
+/// Tells the kernel about the partition table of a block device after it has been modified.
+fn reread_partitions(image: &Image<FileDevice>) {
+    if !image.device().is_block_device() {
+        return;
+    }
+
+    let partitions = read_partition_table(image).and_then(|pt| pt.kernel_partitions());
+    if let Err(e) = image.device().reread_partitions(partitions.as_deref()) {
+        println!(
+            "Warning: unable to re-read the partition table, the kernel still uses the old one: {}",
+            e
+        );
+    }
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
 
     let args = FisicArgs::parse();
 
-    Ok(match args.action {
-        ActionCommand::Create(a) => InvokeCreate(&args.image, a.try_into()?)?,
-        _ => {
-            let mut image = Image::open(args.image)?;
-
-            match args.action {
-                ActionCommand::Init(a) => {
-                    fisic::actions::init::InitAction::invoke(&mut image, a.try_into()?)?
-                }
-                ActionCommand::Partitions {
-                    action: PartitionsAction::List,
-                } => fisic::actions::partitions::ListPartitionsAction::invoke(
-                    &mut image,
-                    ListPartitionsArgs {},
-                )?,
-                _ => panic!("unsupported"),
+    match args.action {
+        ActionCommand::Create(a) => InvokeCreate(
+            &args.image,
+            CreateActionArgs {
+                ids: id_source(a.seed.clone(), &args.image),
+                ..a.try_into()?
+            },
+        )?,
+        action if image_format(args.format, &args.image) == ImageFormat::Qcow2 => {
+            if action.is_read_only() {
+                let mut image = Image::new(Qcow2Device::open_read_only(&args.image)?);
+                inspect(&mut image, action, &args.image)?
+            } else {
+                invoke(
+                    &mut Image::new(Qcow2Device::open(&args.image)?),
+                    action,
+                    &args.image,
+                )?
             }
         }
-    })
+        action if action.is_read_only() => match Image::open_read_only(&args.image) {
+            Ok(mut image) => inspect(&mut image, action, &args.image)?,
+            Err(ImageError::MapError) => inspect(
+                &mut Image::new(FileDevice::open_read_only(&args.image)?),
+                action,
+                &args.image,
+            )?,
+            Err(e) => return Err(e.into()),
+        },
+        action => match Image::open(&args.image) {
+            Ok(mut image) => invoke(&mut image, action, &args.image)?,
+            // Block devices, and images too large for the address space, can still be accessed
+            // with pread/pwrite.
+            Err(ImageError::MapError) => {
+                let mut image = Image::new(FileDevice::open(&args.image)?);
+                invoke(&mut image, action, &args.image)?;
+                reread_partitions(&image);
+            }
+            Err(e) => return Err(e.into()),
+        },
+    }
+
+    Ok(())
 }
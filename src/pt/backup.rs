@@ -0,0 +1,445 @@
+use crate::image::{BlockDevice, Image, ImageError, OnDisk, WritableBlockDevice};
+
+use super::raw::{RawGPTHeader, RawGPTPartitionEntry, RawMBR, GPT_SIGNATURE};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Error while taking or restoring a partition table backup.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum BackupError {
+    /// no MBR found on the image
+    NoMBR,
+    /// the backup file is truncated or malformed
+    MalformedBackup,
+    /// the {0} GPT header has an invalid checksum
+    InvalidChecksum(&'static str),
+    /// the {0} GPT partition entry array has an invalid checksum
+    InvalidEntriesChecksum(&'static str),
+    /// the {0} GPT header is malformed
+    InvalidHeader(&'static str),
+    /// the GPT partition entry array lies outside the image
+    EntriesOutOfRange,
+    /// the backed-up table does not fit this image, relocate the backup header to restore it
+    ImageTooSmall,
+    /// partition {0} extends beyond the last usable LBA of this image
+    PartitionOutOfRange(usize),
+    /// {0}
+    ImageError(#[from] ImageError),
+}
+
+/// A copy of the MBR, both GPT headers and both GPT partition entry arrays of an image.
+///
+/// The on-disk format is one sector each for the MBR, the primary header and the backup header,
+/// followed by the primary and then the backup entry arrays. If the image has no GPT, the header
+/// sectors are zero and there are no entry arrays.
+pub struct TableBackup {
+    mbr: RawMBR,
+    primary: RawGPTHeader,
+    backup: RawGPTHeader,
+    primary_entries: Vec<u8>,
+    backup_entries: Vec<u8>,
+}
+
+/// Encodes `obj` into a sector of its own.
+fn encode_sector(obj: &impl OnDisk) -> [u8; BLOCK_SIZE] {
+    let mut sector = [0; BLOCK_SIZE];
+    obj.encode(&mut sector);
+
+    sector
+}
+
+fn nr_entry_blocks(hdr: &RawGPTHeader) -> usize {
+    hdr.entries_size().div_ceil(BLOCK_SIZE)
+}
+
+fn has_signature(hdr: &RawGPTHeader) -> bool {
+    hdr.signature == GPT_SIGNATURE
+}
+
+/// Checks that a header with a signature has sizes and addresses that are safe to use.
+fn check_header(hdr: &RawGPTHeader, name: &'static str) -> Result<(), BackupError> {
+    if has_signature(hdr) && !hdr.is_valid() {
+        return Err(BackupError::InvalidHeader(name));
+    }
+
+    Ok(())
+}
+
+fn header_checksum_valid(hdr: &RawGPTHeader) -> bool {
+    let mut copy = *hdr;
+    copy.header_checksum = 0;
+
+    copy.compute_checksum() == hdr.header_checksum
+}
+
+fn entries_checksum_valid(hdr: &RawGPTHeader, entries: &[u8]) -> bool {
+    let mut crc = crc_any::CRC::crc32();
+    crc.digest(&entries[..hdr.entries_size()]);
+
+    crc.get_crc() as u32 == hdr.partition_entries_checksum
+}
+
+/// Checks the checksums of a header with a signature and of its entry array, so that a backup
+/// is only taken or restored if the tables it holds are intact.
+fn check_checksums(
+    hdr: &RawGPTHeader,
+    entries: &[u8],
+    name: &'static str,
+) -> Result<(), BackupError> {
+    if !has_signature(hdr) {
+        return Ok(());
+    }
+
+    if !header_checksum_valid(hdr) {
+        return Err(BackupError::InvalidChecksum(name));
+    }
+    if !entries_checksum_valid(hdr, entries) {
+        return Err(BackupError::InvalidEntriesChecksum(name));
+    }
+
+    Ok(())
+}
+
+fn read_entries(
+    image: &Image<impl BlockDevice>,
+    hdr: &RawGPTHeader,
+) -> Result<Vec<u8>, BackupError> {
+    image
+        .get_blocks(hdr.partition_entries_lba, nr_entry_blocks(hdr))
+        .map(|entries| entries.to_vec())
+        .map_err(|_| BackupError::EntriesOutOfRange)
+}
+
+/// Zeroes the GPT headers found on `image`, in the second and last blocks and wherever the
+/// primary header points, so that an MBR-only table is not shadowed by a stale GPT. Blocks
+/// without a header are left alone, as they may hold boot code.
+pub(crate) fn clear_gpt_headers(image: &mut Image<impl WritableBlockDevice>) -> Result<(), BackupError> {
+    let nr_blocks = image.len() / BLOCK_SIZE as u64;
+    if nr_blocks < 2 {
+        return Ok(());
+    }
+
+    let mut blocks = vec![1, nr_blocks - 1];
+    let primary = image.read::<RawGPTHeader>(BLOCK_SIZE as u64)?;
+    if has_signature(&primary) {
+        blocks.push(primary.other_header_lba);
+    }
+
+    for block in blocks {
+        if image
+            .get_blocks(block, 1)
+            .is_ok_and(|b| b[..8] == GPT_SIGNATURE)
+        {
+            image.write_blocks(block, &[0; BLOCK_SIZE])?;
+        }
+    }
+
+    Ok(())
+}
+
+impl TableBackup {
+    pub fn read(image: &Image<impl BlockDevice>) -> Result<Self, BackupError> {
+        let mbr = image.read::<RawMBR>(0)?;
+        if mbr.signature != [0x55, 0xaa] {
+            return Err(BackupError::NoMBR);
+        }
+
+        let mut primary = image.read::<RawGPTHeader>(BLOCK_SIZE as u64)?;
+        if !has_signature(&primary) {
+            primary = RawGPTHeader::decode(&[0; BLOCK_SIZE]);
+        }
+
+        let mut backup = RawGPTHeader::decode(&[0; BLOCK_SIZE]);
+        let mut primary_entries = Vec::new();
+        let mut backup_entries = Vec::new();
+
+        if has_signature(&primary) {
+            check_header(&primary, "primary")?;
+
+            let backup_offset = primary.other_header_lba.checked_mul(BLOCK_SIZE as u64);
+            if let Some(Ok(hdr)) = backup_offset.map(|offset| image.read::<RawGPTHeader>(offset)) {
+                backup = hdr;
+            }
+
+            check_header(&backup, "backup")?;
+
+            primary_entries = read_entries(image, &primary)?;
+            if has_signature(&backup) {
+                backup_entries = read_entries(image, &backup)?;
+            }
+
+            check_checksums(&primary, &primary_entries, "primary")?;
+            check_checksums(&backup, &backup_entries, "backup")?;
+        }
+
+        Ok(TableBackup {
+            mbr,
+            primary,
+            backup,
+            primary_entries,
+            backup_entries,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        out.extend_from_slice(&encode_sector(&self.mbr));
+        out.extend_from_slice(&encode_sector(&self.primary));
+        out.extend_from_slice(&encode_sector(&self.backup));
+
+        out.extend_from_slice(&self.primary_entries);
+        out.extend_from_slice(&self.backup_entries);
+
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BackupError> {
+        if bytes.len() < 3 * BLOCK_SIZE {
+            return Err(BackupError::MalformedBackup);
+        }
+
+        let mbr = RawMBR::decode(&bytes[0..BLOCK_SIZE]);
+        let primary = RawGPTHeader::decode(&bytes[BLOCK_SIZE..2 * BLOCK_SIZE]);
+        let backup = RawGPTHeader::decode(&bytes[2 * BLOCK_SIZE..3 * BLOCK_SIZE]);
+
+        check_header(&primary, "primary")?;
+        check_header(&backup, "backup")?;
+
+        let mut offset = 3 * BLOCK_SIZE;
+        let mut entries = |hdr: &RawGPTHeader| -> Result<Vec<u8>, BackupError> {
+            if !has_signature(hdr) {
+                return Ok(Vec::new());
+            }
+
+            let len = nr_entry_blocks(hdr) * BLOCK_SIZE;
+            let e = bytes
+                .get(offset..)
+                .and_then(|rest| rest.get(..len))
+                .ok_or(BackupError::MalformedBackup)?;
+            offset += len;
+
+            Ok(e.to_vec())
+        };
+
+        let primary_entries = entries(&primary)?;
+        let backup_entries = entries(&backup)?;
+
+        if offset != bytes.len() || mbr.signature != [0x55, 0xaa] {
+            return Err(BackupError::MalformedBackup);
+        }
+
+        check_checksums(&primary, &primary_entries, "primary")?;
+        check_checksums(&backup, &backup_entries, "backup")?;
+
+        Ok(TableBackup {
+            mbr,
+            primary,
+            backup,
+            primary_entries,
+            backup_entries,
+        })
+    }
+
+    /// Moves the backup header and entry array to the end of an image of `nr_blocks`, adjusting
+    /// the last usable LBA and the protective MBR to match.
+    pub fn relocate(&mut self, nr_blocks: u64) -> Result<(), BackupError> {
+        if !has_signature(&self.primary) {
+            return Ok(());
+        }
+
+        if !has_signature(&self.backup) {
+            self.backup = self.primary;
+            self.backup_entries = self.primary_entries.clone();
+        }
+
+        let nr_entry_blocks = nr_entry_blocks(&self.backup) as u64;
+        if nr_blocks < 3 + 2 * nr_entry_blocks {
+            return Err(BackupError::ImageTooSmall);
+        }
+
+        let alt_header_block = nr_blocks - 1;
+        let alt_entries_block = alt_header_block - nr_entry_blocks;
+        let last_usable_lba = alt_entries_block - 1;
+
+        let entry_size = self.primary.partition_entry_size as usize;
+        for (i, entry) in self
+            .primary_entries
+            .chunks_exact(entry_size)
+            .take(self.primary.nr_partition_entries as usize)
+            .enumerate()
+        {
+            let ending_lba = RawGPTPartitionEntry::decode(entry).ending_lba;
+            if ending_lba > last_usable_lba {
+                return Err(BackupError::PartitionOutOfRange(i + 1));
+            }
+        }
+
+        self.primary.other_header_lba = alt_header_block;
+        self.primary.last_usable_lba = last_usable_lba;
+
+        self.backup.this_header_lba = alt_header_block;
+        self.backup.other_header_lba = self.primary.this_header_lba;
+        self.backup.partition_entries_lba = alt_entries_block;
+        self.backup.last_usable_lba = last_usable_lba;
+
+        for hdr in [&mut self.primary, &mut self.backup] {
+            hdr.header_checksum = 0;
+            hdr.header_checksum = hdr.compute_checksum();
+        }
+
+        if self.mbr.partition_entries[0].ptype == 0xee {
+            self.mbr.partition_entries[0].nr_sectors = (nr_blocks - 1).min(u32::MAX as u64) as u32;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the blocks holding the backed-up tables, as `(first block, data)`.
+    pub fn blocks(&self) -> Vec<(u64, Vec<u8>)> {
+        let mut blocks = vec![(0, encode_sector(&self.mbr).to_vec())];
+
+        for (hdr, entries) in [
+            (&self.primary, &self.primary_entries),
+            (&self.backup, &self.backup_entries),
+        ] {
+            if has_signature(hdr) {
+                blocks.push((hdr.this_header_lba, encode_sector(hdr).to_vec()));
+                blocks.push((hdr.partition_entries_lba, entries.clone()));
+            }
+        }
+
+        blocks
+    }
+
+    /// Writes the backed-up tables back to `image`, optionally relocating the backup GPT to the
+    /// end of the image first.
+    pub fn restore(
+        mut self,
+        image: &mut Image<impl WritableBlockDevice>,
+        relocate: bool,
+    ) -> Result<(), BackupError> {
+        let nr_blocks = image.len() / BLOCK_SIZE as u64;
+
+        if relocate {
+            self.relocate(nr_blocks)?;
+        }
+
+        for hdr in [&self.primary, &self.backup] {
+            if has_signature(hdr)
+                && (hdr.this_header_lba >= nr_blocks
+                    || hdr
+                        .partition_entries_lba
+                        .saturating_add(nr_entry_blocks(hdr) as u64)
+                        > nr_blocks)
+            {
+                return Err(BackupError::ImageTooSmall);
+            }
+        }
+
+        if !has_signature(&self.primary) {
+            clear_gpt_headers(image)?;
+        }
+
+        for (block, data) in self.blocks() {
+            image.write_blocks(block, &data)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nuuid::Uuid;
+
+    use super::*;
+    use crate::{
+        image::MemoryDevice,
+        pt::{gpt::GPT, ids::IdSource, raw::GPT_PTYPE_LINUX_FS},
+    };
+
+    const NR_BLOCKS: u64 = 16384;
+
+    fn image(nr_blocks: u64) -> Image<MemoryDevice> {
+        Image::new(MemoryDevice::new(vec![0; nr_blocks as usize * BLOCK_SIZE]))
+    }
+
+    fn gpt_image() -> (GPT, Image<MemoryDevice>) {
+        let mut gpt = GPT::with_ids(&IdSource::Seeded(String::from("test")));
+        let linux = Uuid::parse(GPT_PTYPE_LINUX_FS).unwrap();
+        gpt.add_partition(linux, String::from("root"), 2048, 8191);
+
+        let mut image = image(NR_BLOCKS);
+        gpt.write(&mut image).unwrap();
+
+        (gpt, image)
+    }
+
+    #[test]
+    fn backup_restore_round_trip() {
+        let (gpt, src) = gpt_image();
+
+        let bytes = TableBackup::read(&src).unwrap().to_bytes();
+        let mut dst = image(NR_BLOCKS);
+        TableBackup::from_bytes(&bytes)
+            .unwrap()
+            .restore(&mut dst, false)
+            .unwrap();
+
+        assert_eq!(
+            dst.get_bytes(0, dst.len() as usize).unwrap(),
+            src.get_bytes(0, src.len() as usize).unwrap()
+        );
+
+        let restored = GPT::read(&dst).unwrap();
+        assert_eq!(restored.disk_guid(), gpt.disk_guid());
+        assert_eq!(restored.partitions()[0].end(), 8191);
+    }
+
+    #[test]
+    fn restore_relocates_backup_to_end() {
+        let (_, src) = gpt_image();
+
+        let bytes = TableBackup::read(&src).unwrap().to_bytes();
+        let mut dst = image(2 * NR_BLOCKS);
+        TableBackup::from_bytes(&bytes)
+            .unwrap()
+            .restore(&mut dst, true)
+            .unwrap();
+
+        let backup = dst
+            .read::<RawGPTHeader>((2 * NR_BLOCKS - 1) * BLOCK_SIZE as u64)
+            .unwrap();
+        assert!(header_checksum_valid(&backup));
+        assert_eq!(backup.this_header_lba, 2 * NR_BLOCKS - 1);
+        assert!(GPT::read(&dst).is_ok());
+    }
+
+    #[test]
+    fn damaged_header_is_refused_at_backup_time() {
+        let (_, mut image) = gpt_image();
+        let last = NR_BLOCKS - 1;
+        let mut backup = image.get_blocks(last, 1).unwrap().to_vec();
+        backup[40] ^= 1;
+        image.write_blocks(last, &backup).unwrap();
+
+        assert!(matches!(
+            TableBackup::read(&image),
+            Err(BackupError::InvalidChecksum("backup"))
+        ));
+    }
+
+    #[test]
+    fn damaged_entries_are_refused_at_backup_time() {
+        let (_, mut image) = gpt_image();
+        let mut entries = image.get_blocks(2, 1).unwrap().to_vec();
+        entries[0] ^= 1;
+        image.write_blocks(2, &entries).unwrap();
+
+        assert!(matches!(
+            TableBackup::read(&image),
+            Err(BackupError::InvalidEntriesChecksum("primary"))
+        ));
+    }
+}
@@ -2,15 +2,37 @@ use std::fmt::Display;
 
 use super::{
     mbr::PartitionType as MBRPartitionType,
-    raw::{RawGPTHeader, RawGPTPartitionEntry, GPT_PTYPE_EMPTY},
+    raw::{
+        RawGPTHeader, RawGPTPartitionEntry, GPT_PTYPE_BIOS_BOOT, GPT_PTYPE_EFI_SYSTEM,
+        GPT_PTYPE_EMPTY, GPT_PTYPE_LINUX_FS, GPT_PTYPE_LINUX_LVM, GPT_PTYPE_LINUX_RAID,
+        GPT_PTYPE_LINUX_SWAP, GPT_PTYPE_MBR, GPT_SIGNATURE,
+    },
+};
+use crate::image::{BlockDevice, Image, ImageError, OnDisk, WritableBlockDevice};
+use crate::pt::{
+    ids::IdSource,
+    mbr::{MBRError, MBR},
 };
-use crate::image::Image;
-use crate::pt::mbr::MBR;
 use humansize::BINARY;
 use nuuid::Uuid;
 
 const BLOCK_SIZE: usize = 512;
 
+/// Error while reading a GPT.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum GPTError {
+    /// No protective MBR found
+    NoProtectiveMBR,
+    /// No GPT header found
+    NoSignature,
+    /// The GPT header is malformed
+    InvalidHeader,
+    /// The GPT partition entry array lies outside the image
+    EntriesOutOfRange,
+    /// Partition {0} lies outside the usable area of the disk
+    InvalidPartition(usize),
+}
+
 fn compute_crc32(data: &[u8]) -> u32 {
     let mut crc = crc_any::CRC::crc32();
     crc.digest(data);
@@ -18,13 +40,28 @@ fn compute_crc32(data: &[u8]) -> u32 {
     crc.get_crc() as u32
 }
 
+/// Human-readable names of well-known partition types.
+const TYPE_NAMES: [(&str, &str); 7] = [
+    (GPT_PTYPE_MBR, "MBR partition scheme"),
+    (GPT_PTYPE_EFI_SYSTEM, "EFI System"),
+    (GPT_PTYPE_BIOS_BOOT, "BIOS boot"),
+    (GPT_PTYPE_LINUX_FS, "Linux filesystem"),
+    (GPT_PTYPE_LINUX_SWAP, "Linux swap"),
+    (GPT_PTYPE_LINUX_LVM, "Linux LVM"),
+    (GPT_PTYPE_LINUX_RAID, "Linux RAID"),
+];
+
+/// Maximum number of UTF-16 code units in a partition name.
+const NAME_LEN: usize = 36;
+
 #[derive(Clone, Debug)]
 pub struct Partition {
     part_guid: Uuid,
     type_guid: Uuid,
     name: String,
-    start: usize,
-    end: usize,
+    start: u64,
+    end: u64,
+    attributes: u64,
 }
 
 impl Partition {
@@ -35,37 +72,121 @@ impl Partition {
             name: String::from(""),
             start: 0,
             end: 0,
+            attributes: 0,
         }
     }
 
-    pub fn new(type_guid: Uuid, name: String, start: usize, end: usize) -> Self {
+    pub fn new(type_guid: Uuid, name: String, start: u64, end: u64) -> Self {
         Partition {
             part_guid: Uuid::new_v4(),
             type_guid,
             name,
             start,
             end,
+            attributes: 0,
         }
     }
 
     pub fn from_raw(pte: RawGPTPartitionEntry) -> Self {
+        let units: Vec<u16> = pte
+            .name
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+
         Partition {
             part_guid: Uuid::from_bytes_me(pte.ident),
             type_guid: Uuid::from_bytes_me(pte.ptype),
-            name: String::from_utf8(pte.name.to_vec()).unwrap(),
-            start: pte.starting_lba as usize,
-            end: pte.ending_lba as usize,
+            name: String::from_utf16_lossy(&units),
+            start: pte.starting_lba,
+            end: pte.ending_lba,
+            attributes: pte.attributes,
+        }
+    }
+
+    pub fn to_raw(&self) -> RawGPTPartitionEntry {
+        let mut name = [0u8; NAME_LEN * 2];
+
+        for (i, c) in self.name.encode_utf16().take(NAME_LEN).enumerate() {
+            name[i * 2..i * 2 + 2].copy_from_slice(&c.to_le_bytes());
+        }
+
+        RawGPTPartitionEntry {
+            ptype: self.type_guid.to_bytes_me(),
+            ident: self.part_guid.to_bytes_me(),
+            starting_lba: self.start,
+            ending_lba: self.end,
+            attributes: self.attributes,
+            name,
         }
     }
 
     pub fn is_empty(&self) -> bool {
         self.type_guid == Uuid::parse(GPT_PTYPE_EMPTY).unwrap()
     }
+
+    pub fn part_guid(&self) -> Uuid {
+        self.part_guid
+    }
+
+    pub fn set_part_guid(&mut self, part_guid: Uuid) {
+        self.part_guid = part_guid;
+    }
+
+    pub fn type_guid(&self) -> Uuid {
+        self.type_guid
+    }
+
+    pub fn type_name(&self) -> Option<&'static str> {
+        TYPE_NAMES
+            .iter()
+            .find(|(guid, _)| Uuid::parse(guid).unwrap() == self.type_guid)
+            .map(|(_, name)| *name)
+    }
+
+    pub fn set_type_guid(&mut self, type_guid: Uuid) {
+        self.type_guid = type_guid;
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Renames the partition, returning false if `name` does not fit in an entry.
+    pub fn set_name(&mut self, name: String) -> bool {
+        if name.encode_utf16().count() > NAME_LEN {
+            return false;
+        }
+
+        self.name = name;
+        true
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn nr_sectors(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn attributes(&self) -> u64 {
+        self.attributes
+    }
+
+    pub fn set_attributes(&mut self, attributes: u64) {
+        self.attributes = attributes;
+    }
 }
 
 impl Display for Partition {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let bytes = (self.end - self.start + 1) * BLOCK_SIZE;
+        let bytes = self.nr_sectors() * BLOCK_SIZE as u64;
 
         f.write_fmt(format_args!(
             "ID: {}, Type: {}, Name: {}, Start: {}, End: {}, Sectors: {}, Size: {}",
@@ -74,7 +195,7 @@ impl Display for Partition {
             self.name,
             self.start,
             self.end,
-            self.end - self.start + 1,
+            self.nr_sectors(),
             humansize::format_size(bytes, BINARY)
         ))
     }
@@ -84,87 +205,232 @@ impl Display for Partition {
 pub struct GPT {
     partitions: Vec<Partition>,
     disk_guid: Uuid,
+    entries_lba: u64,
+    first_usable_lba: u64,
+}
+
+/// Fewest partition entries allowed in a new table, as UEFI requires the entry array to be at
+/// least 16 KiB.
+pub const MIN_ENTRIES: usize = 128;
+
+/// Size and placement of the GPT partition entry array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub nr_entries: usize,
+    /// LBA of the primary entry array. The backup array always sits just before the backup header.
+    pub entries_lba: u64,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            nr_entries: 128,
+            entries_lba: 2,
+        }
+    }
+}
+
+impl Default for GPT {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GPT {
     pub fn new() -> Self {
-        GPT {
-            partitions: vec![Partition::new_empty(); 128],
-            disk_guid: Uuid::new_v4(),
+        Self::with_ids(&IdSource::Random)
+    }
+
+    pub fn with_ids(ids: &IdSource) -> Self {
+        Self::with_layout(ids, Layout::default())
+    }
+
+    /// Creates an empty table whose partition area starts right after the primary entry array.
+    pub fn with_layout(ids: &IdSource, layout: Layout) -> Self {
+        let mut gpt = GPT {
+            partitions: vec![Partition::new_empty(); layout.nr_entries],
+            disk_guid: ids.disk_guid(),
+            entries_lba: layout.entries_lba,
+            first_usable_lba: 0,
+        };
+
+        gpt.first_usable_lba = layout.entries_lba + gpt.nr_entry_blocks();
+        gpt
+    }
+
+    pub fn layout(&self) -> Layout {
+        Layout {
+            nr_entries: self.partitions.len(),
+            entries_lba: self.entries_lba,
         }
     }
 
-    pub fn add_partition(&mut self, type_guid: Uuid, name: String, start: usize, end: usize) -> () {
-        self.partitions
-            .push(Partition::new(type_guid, name, start, end));
+    /// Returns true if the table leaves room for at least one partition block on an image of
+    /// `nr_blocks`.
+    pub fn fits(&self, nr_blocks: u64) -> bool {
+        self.entries_lba >= 2
+            && self.partitions.len() >= MIN_ENTRIES
+            && nr_blocks > 3 + 2 * self.nr_entry_blocks()
+            && self.first_usable_lba <= self.usable_range(nr_blocks).1
+    }
+
+    pub fn disk_guid(&self) -> Uuid {
+        self.disk_guid
+    }
+
+    pub fn set_disk_guid(&mut self, disk_guid: Uuid) {
+        self.disk_guid = disk_guid;
+    }
+
+    pub fn partitions(&self) -> &[Partition] {
+        &self.partitions
+    }
+
+    /// Places a new partition in the first empty entry, returning its index, or `None` if the
+    /// entry array is full.
+    pub fn add_partition(
+        &mut self,
+        type_guid: Uuid,
+        name: String,
+        start: u64,
+        end: u64,
+    ) -> Option<usize> {
+        let index = self.partitions.iter().position(|e| e.is_empty())?;
+        self.partitions[index] = Partition::new(type_guid, name, start, end);
+
+        Some(index)
     }
 
-    fn write_protective_mbr(&self, image: &mut Image) {
-        let mbr = MBR::new_protective(image.len() / super::mbr::MBR_SECTOR_SIZE);
-        mbr.write(image);
+    pub fn set_partition(&mut self, index: usize, p: Partition) {
+        self.partitions[index] = p;
     }
 
-    fn write_entries(&self, image: &mut Image, entries_start_idx: usize) -> u32 {
-        let nr_entries = self.partitions.len();
-        let entries_size = nr_entries * std::mem::size_of::<RawGPTPartitionEntry>();
-        let nr_entry_blocks = (entries_size + (BLOCK_SIZE - 1)) / BLOCK_SIZE;
+    pub fn partition_mut(&mut self, index: usize) -> Option<&mut Partition> {
+        self.partitions.get_mut(index)
+    }
 
-        let blocks = image.get_blocks_mut(entries_start_idx, nr_entry_blocks);
-        blocks.fill(0);
+    /// Moves the partitions to the front of the entry array in order of their starting LBA.
+    ///
+    /// Returns the (old, new) index of every partition.
+    pub fn sort_partitions(&mut self) -> Vec<(usize, usize)> {
+        let mut used: Vec<(usize, Partition)> = self
+            .partitions
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(_, p)| !p.is_empty())
+            .collect();
+        used.sort_by_key(|(_, p)| p.start);
+
+        self.partitions.fill(Partition::new_empty());
+
+        used.into_iter()
+            .enumerate()
+            .map(|(new, (old, p))| {
+                self.partitions[new] = p;
+                (old, new)
+            })
+            .collect()
+    }
+
+    pub fn nr_entry_blocks(&self) -> u64 {
+        let entries_size = self.partitions.len() * RawGPTPartitionEntry::SIZE;
+        entries_size.div_ceil(BLOCK_SIZE) as u64
+    }
 
-        // TODO: Restrict to just the entries?
-        compute_crc32(blocks)
+    /// Returns the first and last LBA that partitions may occupy on an image of `nr_blocks`.
+    pub fn usable_range(&self, nr_blocks: u64) -> (u64, u64) {
+        (
+            self.first_usable_lba,
+            nr_blocks - 2 - self.nr_entry_blocks(),
+        )
+    }
+
+    fn write_protective_mbr(
+        &self,
+        image: &mut Image<impl WritableBlockDevice>,
+    ) -> Result<(), ImageError> {
+        let mbr = MBR::new_protective(image.len() / super::mbr::MBR_SECTOR_SIZE as u64);
+        match mbr.write(image) {
+            Err(MBRError::ImageError(e)) => Err(e),
+            Err(_) => unreachable!("protective MBR entries are saturated to fit"),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn write_entries(
+        &self,
+        image: &mut Image<impl WritableBlockDevice>,
+        entries_start_idx: u64,
+    ) -> Result<u32, ImageError> {
+        let entry_size = RawGPTPartitionEntry::SIZE;
+        let mut entries = vec![0; self.nr_entry_blocks() as usize * BLOCK_SIZE];
+
+        for (p, bytes) in self
+            .partitions
+            .iter()
+            .zip(entries.chunks_exact_mut(entry_size))
+        {
+            p.to_raw().encode(bytes);
+        }
+
+        image.write_blocks(entries_start_idx, &entries)?;
+
+        Ok(compute_crc32(
+            &entries[..self.partitions.len() * entry_size],
+        ))
     }
 
     fn write_table(
         &self,
-        image: &mut Image,
-        this_block_idx: usize,
-        alternative_block_idx: usize,
-        entries_start_idx: usize,
-        valid_range: (usize, usize),
-    ) {
-        let entries_checksum = self.write_entries(image, entries_start_idx);
+        image: &mut Image<impl WritableBlockDevice>,
+        this_block_idx: u64,
+        alternative_block_idx: u64,
+        entries_start_idx: u64,
+        valid_range: (u64, u64),
+    ) -> Result<(), ImageError> {
+        let entries_checksum = self.write_entries(image, entries_start_idx)?;
 
         let mut hdr = RawGPTHeader::new();
 
-        hdr.this_header_lba = this_block_idx as u64;
-        hdr.other_header_lba = alternative_block_idx as u64;
-        hdr.first_usable_lba = valid_range.0 as u64;
-        hdr.last_usable_lba = valid_range.1 as u64;
+        hdr.this_header_lba = this_block_idx;
+        hdr.other_header_lba = alternative_block_idx;
+        hdr.first_usable_lba = valid_range.0;
+        hdr.last_usable_lba = valid_range.1;
         hdr.disk_guid = self.disk_guid.to_bytes_me();
-        hdr.partition_entries_lba = entries_start_idx as u64;
+        hdr.partition_entries_lba = entries_start_idx;
         hdr.nr_partition_entries = self.partitions.len() as u32;
         hdr.partition_entries_checksum = entries_checksum;
         hdr.header_checksum = hdr.compute_checksum();
 
-        image.write(this_block_idx * BLOCK_SIZE, hdr);
+        image.write(this_block_idx * BLOCK_SIZE as u64, &hdr)
     }
 
-    pub fn write(&self, image: &mut Image) {
-        self.write_protective_mbr(image);
+    pub fn write(&self, image: &mut Image<impl WritableBlockDevice>) -> Result<(), ImageError> {
+        self.write_protective_mbr(image)?;
+        self.write_tables(image)
+    }
 
-        let nr_blocks = image.len() / BLOCK_SIZE;
+    /// Writes both GPT headers and entry arrays, leaving the MBR untouched.
+    pub fn write_tables(
+        &self,
+        image: &mut Image<impl WritableBlockDevice>,
+    ) -> Result<(), ImageError> {
+        let nr_blocks = image.len() / BLOCK_SIZE as u64;
 
         let primary_header_block = 1;
         let alt_header_block = nr_blocks - 1;
 
-        let nr_entries = self.partitions.len();
-        let entries_size = nr_entries * std::mem::size_of::<RawGPTPartitionEntry>();
-        let nr_entry_blocks = (entries_size + (BLOCK_SIZE - 1)) / BLOCK_SIZE;
-
-        let valid_range = (
-            primary_header_block + nr_entry_blocks + 1,
-            alt_header_block - nr_entry_blocks - 1,
-        );
+        let nr_entry_blocks = self.nr_entry_blocks();
+        let valid_range = self.usable_range(nr_blocks);
 
         self.write_table(
             image,
             primary_header_block,
             alt_header_block,
-            primary_header_block + 1,
+            self.entries_lba,
             valid_range,
-        );
+        )?;
 
         self.write_table(
             image,
@@ -172,43 +438,55 @@ impl GPT {
             primary_header_block,
             alt_header_block - nr_entry_blocks,
             valid_range,
-        );
+        )
     }
 
-    fn read_partitions(image: &Image, mut offset: usize, count: usize) -> Vec<Partition> {
-        let mut p = Vec::new();
-
-        for i in 0..count {
-            let pte: RawGPTPartitionEntry = image.read(offset);
-            p.push(Partition::from_raw(pte));
-
-            offset += std::mem::size_of::<RawGPTPartitionEntry>();
+    /// Reads the GPT described by the primary header, checking every size and address in it
+    /// against the image so that a corrupt or hostile table is rejected instead of trusted.
+    pub fn read(image: &Image<impl BlockDevice>) -> Result<GPT, GPTError> {
+        let mbr = MBR::read(image).ok_or(GPTError::NoProtectiveMBR)?;
+        if mbr.partition_table[0].ptype != MBRPartitionType::ProtectiveMBR {
+            return Err(GPTError::NoProtectiveMBR);
         }
 
-        p
-    }
+        let hdr = image
+            .read::<RawGPTHeader>(BLOCK_SIZE as u64)
+            .map_err(|_| GPTError::NoSignature)?;
+        if hdr.signature != GPT_SIGNATURE {
+            return Err(GPTError::NoSignature);
+        }
 
-    pub fn read(image: &Image) -> Option<GPT> {
-        let mbr = MBR::read(image).unwrap();
+        if !hdr.is_valid() {
+            return Err(GPTError::InvalidHeader);
+        }
 
-        match mbr.partition_table[0].ptype {
-            MBRPartitionType::ProtectiveMBR => {
-                let gpt = image.read::<RawGPTHeader>(BLOCK_SIZE);
-                if gpt.signature == [0x45, 0x46, 0x49, 0x20, 0x50, 0x41, 0x52, 0x54] {
-                    Some(GPT {
-                        partitions: Self::read_partitions(
-                            image,
-                            gpt.partition_entries_lba as usize * BLOCK_SIZE,
-                            gpt.nr_partition_entries as usize,
-                        ),
-                        disk_guid: Uuid::from_bytes(gpt.disk_guid),
-                    })
-                } else {
-                    None
-                }
+        let entries = image
+            .get_blocks(
+                hdr.partition_entries_lba,
+                hdr.entries_size().div_ceil(BLOCK_SIZE),
+            )
+            .map_err(|_| GPTError::EntriesOutOfRange)?;
+
+        let partitions: Vec<Partition> = entries
+            .chunks_exact(hdr.partition_entry_size as usize)
+            .take(hdr.nr_partition_entries as usize)
+            .map(|e| Partition::from_raw(RawGPTPartitionEntry::decode(e)))
+            .collect();
+
+        let usable = hdr.first_usable_lba..=hdr.last_usable_lba;
+        for (i, p) in partitions.iter().enumerate() {
+            let valid = usable.contains(&p.start) && usable.contains(&p.end) && p.start <= p.end;
+            if !p.is_empty() && !valid {
+                return Err(GPTError::InvalidPartition(i + 1));
             }
-            _ => None,
         }
+
+        Ok(GPT {
+            partitions,
+            disk_guid: Uuid::from_bytes_me(hdr.disk_guid),
+            entries_lba: hdr.partition_entries_lba,
+            first_usable_lba: hdr.first_usable_lba,
+        })
     }
 }
 
@@ -225,3 +503,83 @@ impl Display for GPT {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::MemoryDevice;
+
+    const NR_BLOCKS: u64 = 16384;
+
+    fn image() -> Image<MemoryDevice> {
+        Image::new(MemoryDevice::new(vec![0; NR_BLOCKS as usize * BLOCK_SIZE]))
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let ids = IdSource::Seeded(String::from("test"));
+        let mut gpt = GPT::with_ids(&ids);
+        let esp = Uuid::parse(GPT_PTYPE_EFI_SYSTEM).unwrap();
+        let linux = Uuid::parse(GPT_PTYPE_LINUX_FS).unwrap();
+        gpt.add_partition(esp, String::from("esp"), 2048, 4095);
+        gpt.add_partition(linux, String::from("root"), 4096, 16000);
+        gpt.partition_mut(1).unwrap().set_attributes(1 << 60);
+
+        let mut image = image();
+        gpt.write(&mut image).unwrap();
+
+        let read = GPT::read(&image).unwrap();
+        assert_eq!(read.disk_guid(), gpt.disk_guid());
+        assert_eq!(read.layout(), gpt.layout());
+        assert_eq!(read.usable_range(NR_BLOCKS), (34, NR_BLOCKS - 34));
+
+        for (written, read) in gpt.partitions().iter().zip(read.partitions()) {
+            assert_eq!(read.part_guid(), written.part_guid());
+            assert_eq!(read.type_guid(), written.type_guid());
+            assert_eq!(read.name(), written.name());
+            assert_eq!(read.start(), written.start());
+            assert_eq!(read.end(), written.end());
+            assert_eq!(read.attributes(), written.attributes());
+        }
+    }
+
+    #[test]
+    fn backup_header_points_back() {
+        let mut image = image();
+        GPT::new().write(&mut image).unwrap();
+
+        let primary = image.read::<RawGPTHeader>(BLOCK_SIZE as u64).unwrap();
+        let backup = image
+            .read::<RawGPTHeader>((NR_BLOCKS - 1) * BLOCK_SIZE as u64)
+            .unwrap();
+
+        assert_eq!(primary.other_header_lba, NR_BLOCKS - 1);
+        assert_eq!(backup.signature, GPT_SIGNATURE);
+        assert_eq!(backup.this_header_lba, NR_BLOCKS - 1);
+        assert_eq!(backup.other_header_lba, 1);
+        assert_eq!(backup.partition_entries_lba, NR_BLOCKS - 33);
+        assert_eq!(
+            backup.partition_entries_checksum,
+            primary.partition_entries_checksum
+        );
+    }
+
+    #[test]
+    fn partition_outside_usable_range_is_rejected() {
+        let mut gpt = GPT::new();
+        gpt.add_partition(
+            Uuid::parse(GPT_PTYPE_LINUX_FS).unwrap(),
+            String::new(),
+            2048,
+            NR_BLOCKS - 1,
+        );
+
+        let mut image = image();
+        gpt.write(&mut image).unwrap();
+
+        assert!(matches!(
+            GPT::read(&image),
+            Err(GPTError::InvalidPartition(1))
+        ));
+    }
+}
@@ -0,0 +1,59 @@
+use nuuid::Uuid;
+
+/// Namespace for the name-based GUIDs derived from a seed.
+const FISIC_NAMESPACE: &str = "5d7333b4-77b3-417c-a1a5-001bb35b7961";
+
+/// Source of the identifiers stamped into new partition tables.
+///
+/// With a seed, every identifier is derived from the seed and the position of the object it
+/// identifies, so that building the same layout twice produces bit-identical tables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdSource {
+    Random,
+    Seeded(String),
+}
+
+impl IdSource {
+    fn derive(seed: &str, parts: &[&str]) -> Uuid {
+        let mut name = seed.as_bytes().to_vec();
+        for part in parts {
+            name.push(0);
+            name.extend_from_slice(part.as_bytes());
+        }
+
+        Uuid::new_v5(Uuid::parse(FISIC_NAMESPACE).unwrap(), &name)
+    }
+
+    pub fn disk_guid(&self) -> Uuid {
+        match self {
+            IdSource::Random => Uuid::new_v4(),
+            IdSource::Seeded(seed) => Self::derive(seed, &["disk"]),
+        }
+    }
+
+    /// Returns the UUID identifying an image file, such as an exported VHD.
+    pub fn image_uuid(&self) -> Uuid {
+        match self {
+            IdSource::Random => Uuid::new_v4(),
+            IdSource::Seeded(seed) => Self::derive(seed, &["image"]),
+        }
+    }
+
+    /// Returns the unique GUID of the partition in entry `index` with name `label`.
+    pub fn partition_guid(&self, index: usize, label: &str) -> Uuid {
+        match self {
+            IdSource::Random => Uuid::new_v4(),
+            IdSource::Seeded(seed) => Self::derive(seed, &["partition", &index.to_string(), label]),
+        }
+    }
+
+    pub fn disk_signature(&self) -> u32 {
+        let uuid = match self {
+            IdSource::Random => Uuid::new_v4(),
+            IdSource::Seeded(seed) => Self::derive(seed, &["mbr-signature"]),
+        };
+
+        let bytes = uuid.to_bytes();
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+}
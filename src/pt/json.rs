@@ -0,0 +1,183 @@
+use serde::Serialize;
+
+use crate::{
+    fs::probe::{probe_partition, FsInfo},
+    image::{BlockDevice, Image},
+};
+
+use super::{mbr::EntryStatus, mbr::PartitionType, sfdisk, PartitionTable};
+
+const SECTOR_SIZE: usize = 512;
+
+/// A partition table in the shape of `sfdisk --json` output.
+#[derive(Serialize)]
+pub struct JsonPartitionTable {
+    pub label: &'static str,
+    pub id: String,
+    pub device: String,
+    pub unit: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firstlba: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lastlba: Option<u64>,
+    pub sectorsize: usize,
+    pub partitions: Vec<JsonPartition>,
+}
+
+#[derive(Serialize)]
+pub struct JsonPartition {
+    pub node: String,
+    pub number: usize,
+    pub start: u64,
+    pub end: u64,
+    pub size: u64,
+    #[serde(rename = "type")]
+    pub ptype: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typename: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attrs: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub bootable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fstype: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fslabel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fsuuid: Option<String>,
+}
+
+impl JsonPartition {
+    /// Adds the type, label and UUID of the filesystem found in the partition, if any.
+    fn with_filesystem(self, fs: Option<FsInfo>) -> Self {
+        match fs {
+            Some(fs) => JsonPartition {
+                fstype: Some(fs.fstype),
+                fslabel: fs.label,
+                fsuuid: fs.uuid,
+                ..self
+            },
+            None => self,
+        }
+    }
+}
+
+impl JsonPartitionTable {
+    /// Describes `pt`, or returns `None` for a whole-disk filesystem without a table.
+    pub fn new(pt: &PartitionTable, device: &str, image: &Image<impl BlockDevice>) -> Option<Self> {
+        let nr_blocks = image.len() / SECTOR_SIZE as u64;
+
+        Some(match pt {
+            PartitionTable::GPT(gpt) => {
+                let (first, last) = gpt.usable_range(nr_blocks);
+
+                JsonPartitionTable {
+                    label: "gpt",
+                    id: gpt.disk_guid().to_string().to_uppercase(),
+                    device: device.to_string(),
+                    unit: "sectors",
+                    firstlba: Some(first),
+                    lastlba: Some(last),
+                    sectorsize: SECTOR_SIZE,
+                    partitions: gpt
+                        .partitions()
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, p)| !p.is_empty())
+                        .map(|(i, p)| {
+                            JsonPartition {
+                                node: sfdisk::partition_node(device, i + 1),
+                                number: i + 1,
+                                start: p.start(),
+                                end: p.end(),
+                                size: p.nr_sectors(),
+                                ptype: p.type_guid().to_string().to_uppercase(),
+                                typename: p.type_name(),
+                                uuid: Some(p.part_guid().to_string().to_uppercase()),
+                                name: Some(p.name().to_string()).filter(|n| !n.is_empty()),
+                                attrs: Some(sfdisk::format_attributes(p.attributes()))
+                                    .filter(|a| !a.is_empty()),
+                                bootable: false,
+                                fstype: None,
+                                fslabel: None,
+                                fsuuid: None,
+                            }
+                            .with_filesystem(probe_partition(
+                                image,
+                                p.start(),
+                                p.nr_sectors(),
+                            ))
+                        })
+                        .collect(),
+                }
+            }
+            PartitionTable::MBR(mbr) => JsonPartitionTable {
+                label: "dos",
+                id: format!("0x{:08x}", mbr.disk_signature),
+                device: device.to_string(),
+                unit: "sectors",
+                firstlba: None,
+                lastlba: None,
+                sectorsize: SECTOR_SIZE,
+                partitions: mbr
+                    .partition_table
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, pte)| pte.ptype != PartitionType::Empty)
+                    .map(|(i, pte)| {
+                        JsonPartition {
+                            node: sfdisk::partition_node(device, i + 1),
+                            number: i + 1,
+                            start: pte.first_sector_lba,
+                            end: pte.first_sector_lba + pte.nr_sectors.max(1) - 1,
+                            size: pte.nr_sectors,
+                            ptype: format!("{:x}", pte.ptype.to_byte()),
+                            typename: pte.ptype.name(),
+                            uuid: None,
+                            name: None,
+                            attrs: None,
+                            bootable: pte.status == EntryStatus::Bootable,
+                            fstype: None,
+                            fslabel: None,
+                            fsuuid: None,
+                        }
+                        .with_filesystem(probe_partition(
+                            image,
+                            pte.first_sector_lba,
+                            pte.nr_sectors,
+                        ))
+                    })
+                    .collect(),
+            },
+            PartitionTable::Superfloppy(_) => return None,
+        })
+    }
+}
+
+/// Top-level document printed by `partitions list --output json`.
+#[derive(Serialize)]
+pub struct JsonListing {
+    pub partitiontable: Option<JsonPartitionTable>,
+    /// The filesystem covering the whole disk, if there is no partition table.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filesystem: Option<FsInfo>,
+}
+
+impl JsonListing {
+    pub fn new(pt: Option<PartitionTable>, device: &str, image: &Image<impl BlockDevice>) -> Self {
+        match pt {
+            Some(PartitionTable::Superfloppy(fs)) => JsonListing {
+                partitiontable: None,
+                filesystem: Some(fs),
+            },
+            pt => JsonListing {
+                partitiontable: pt.and_then(|pt| JsonPartitionTable::new(&pt, device, image)),
+                filesystem: None,
+            },
+        }
+    }
+}
@@ -1,20 +1,35 @@
 use std::fmt::Display;
 
-use super::raw::{RawMBR, RawMBRPartitionEntry};
-use crate::image::Image;
+use super::{
+    ids::IdSource,
+    raw::{RawMBR, RawMBRPartitionEntry},
+};
+use crate::image::{BlockDevice, Image, ImageError, WritableBlockDevice};
 
 pub const MBR_SECTOR_SIZE: usize = 512;
 
+/// Error while encoding an MBR.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
 pub enum MBRError {
+    /// The MBR is malformed
     MBRFormatError,
+    /// A sector address does not fit in the 32 bits an MBR entry provides
+    LBAOutOfRange,
+    /// Partition {0} lies beyond the 2^32 sectors an MBR can address
+    PartitionOutOfRange(usize),
+    /// {0}
+    ImageError(#[from] ImageError),
 }
 
+/// Largest sector address or count representable in an MBR partition entry.
+const MAX_SECTORS: u64 = u32::MAX as u64;
+
 #[inline]
 fn u32_to_le(v: u32) -> [u8; 4] {
     [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum EntryStatus {
     Bootable,
     NotBootable,
@@ -24,7 +39,48 @@ pub enum EntryStatus {
 pub enum PartitionType {
     Empty,
     ProtectiveMBR,
-    Unknown,
+    Unknown(u8),
+}
+
+impl PartitionType {
+    pub fn from_byte(v: u8) -> Self {
+        match v {
+            0 => PartitionType::Empty,
+            0xee => PartitionType::ProtectiveMBR,
+            v => PartitionType::Unknown(v),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            PartitionType::Empty => 0,
+            PartitionType::ProtectiveMBR => 0xee,
+            PartitionType::Unknown(v) => *v,
+        }
+    }
+
+    /// Returns the conventional name of the partition type, as shown by fdisk.
+    pub fn name(&self) -> Option<&'static str> {
+        match self.to_byte() {
+            0x00 => Some("Empty"),
+            0x01 => Some("FAT12"),
+            0x04 => Some("FAT16 <32M"),
+            0x05 => Some("Extended"),
+            0x06 => Some("FAT16"),
+            0x07 => Some("HPFS/NTFS/exFAT"),
+            0x0b => Some("W95 FAT32"),
+            0x0c => Some("W95 FAT32 (LBA)"),
+            0x0e => Some("W95 FAT16 (LBA)"),
+            0x0f => Some("W95 Ext'd (LBA)"),
+            0x82 => Some("Linux swap / Solaris"),
+            0x83 => Some("Linux"),
+            0x8e => Some("Linux LVM"),
+            0xee => Some("GPT"),
+            0xef => Some("EFI (FAT-12/16/32)"),
+            0xfd => Some("Linux raid autodetect"),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -64,9 +120,9 @@ impl CHS {
 
     pub fn from_raw(bytes: &[u8; 3]) -> Self {
         CHS {
-            head: 0,
-            sector: 0,
-            cylinder: 0,
+            head: bytes[0] as usize,
+            sector: (bytes[1] & 0x3f) as usize,
+            cylinder: (((bytes[1] & 0xc0) as usize) << 2) | bytes[2] as usize,
         }
     }
 
@@ -81,15 +137,18 @@ impl CHS {
         }
     }
 
-    pub fn from_lba(lba: usize) -> Self {
-        let cylinder = Self::saturate(lba / (HEADS_PER_CYLINDER * SECTORS_PER_TRACK), 0x3ff);
-        let head = Self::saturate((lba / SECTORS_PER_TRACK) % HEADS_PER_CYLINDER, 0xff);
-        let sector = Self::saturate((lba % SECTORS_PER_TRACK) + 1, 0x3f);
+    pub fn from_lba(lba: u64) -> Self {
+        let heads = HEADS_PER_CYLINDER as u64;
+        let sectors = SECTORS_PER_TRACK as u64;
+
+        let cylinder = Self::saturate(lba / (heads * sectors), 0x3ff);
+        let head = Self::saturate((lba / sectors) % heads, 0xff);
+        let sector = Self::saturate((lba % sectors) + 1, 0x3f);
 
         CHS {
-            head,
-            sector,
-            cylinder,
+            head: head as usize,
+            sector: sector as usize,
+            cylinder: cylinder as usize,
         }
     }
 
@@ -108,12 +167,13 @@ pub struct PartitionEntry {
     pub ptype: PartitionType,
     pub first_sector: CHS,
     pub last_sector: CHS,
-    pub first_sector_lba: usize,
-    pub nr_sectors: usize,
+    pub first_sector_lba: u64,
+    pub nr_sectors: u64,
 }
 
 #[derive(Debug)]
 pub struct MBR {
+    pub disk_signature: u32,
     pub partition_table: [PartitionEntry; 4],
 }
 
@@ -129,33 +189,38 @@ impl PartitionEntry {
         }
     }
 
-    pub fn new(status: EntryStatus, ptype: PartitionType, first: usize, last: usize) -> Self {
+    pub fn new(status: EntryStatus, ptype: PartitionType, first: u64, last: u64) -> Self {
         PartitionEntry {
             status,
             ptype,
             first_sector: CHS::from_lba(first),
             last_sector: CHS::from_lba(last),
             first_sector_lba: first,
-            nr_sectors: last - first,
+            nr_sectors: last - first + 1,
         }
     }
 
-    pub fn to_raw(&self) -> RawMBRPartitionEntry {
-        RawMBRPartitionEntry {
+    pub fn to_raw(&self) -> Result<RawMBRPartitionEntry, MBRError> {
+        let first_sector_lba: u32 = self
+            .first_sector_lba
+            .try_into()
+            .map_err(|_| MBRError::LBAOutOfRange)?;
+        let nr_sectors: u32 = self
+            .nr_sectors
+            .try_into()
+            .map_err(|_| MBRError::LBAOutOfRange)?;
+
+        Ok(RawMBRPartitionEntry {
             status: match self.status {
                 EntryStatus::Bootable => 0x80,
                 EntryStatus::NotBootable => 0x00,
             },
             first_sector_chs: self.first_sector.to_bytes(),
-            ptype: match self.ptype {
-                PartitionType::Empty => 0,
-                PartitionType::ProtectiveMBR => 0xee,
-                _ => 0xff,
-            },
+            ptype: self.ptype.to_byte(),
             last_sector_chs: self.last_sector.to_bytes(),
-            first_sector_lba: self.first_sector_lba as u32,
-            nr_sectors: self.nr_sectors as u32,
-        }
+            first_sector_lba,
+            nr_sectors,
+        })
     }
 
     pub fn from_raw(raw: RawMBRPartitionEntry) -> PartitionEntry {
@@ -165,15 +230,11 @@ impl PartitionEntry {
             } else {
                 EntryStatus::NotBootable
             },
-            ptype: match raw.ptype {
-                0 => PartitionType::Empty,
-                0xee => PartitionType::ProtectiveMBR,
-                _ => PartitionType::Unknown,
-            },
+            ptype: PartitionType::from_byte(raw.ptype),
             first_sector: CHS::from_raw(&raw.first_sector_chs),
             last_sector: CHS::from_raw(&raw.last_sector_chs),
-            first_sector_lba: raw.first_sector_lba as usize,
-            nr_sectors: raw.nr_sectors as usize,
+            first_sector_lba: raw.first_sector_lba as u64,
+            nr_sectors: raw.nr_sectors as u64,
         }
     }
 }
@@ -187,9 +248,19 @@ impl Display for PartitionEntry {
     }
 }
 
+/// Offset of the 32-bit disk signature within the bootstrap area.
+const DISK_SIGNATURE_OFFSET: usize = 0x1b8;
+
+impl Default for MBR {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MBR {
     pub fn new() -> Self {
         MBR {
+            disk_signature: 0,
             partition_table: [
                 PartitionEntry::new_empty(),
                 PartitionEntry::new_empty(),
@@ -199,17 +270,28 @@ impl MBR {
         }
     }
 
-    pub fn new_protective(nr_blocks: usize) -> Self {
+    /// Creates an empty MBR with a disk signature taken from `ids`.
+    pub fn with_ids(ids: &IdSource) -> Self {
+        MBR {
+            disk_signature: ids.disk_signature(),
+            ..Self::new()
+        }
+    }
+
+    /// Creates a protective MBR covering an image of `nr_blocks`, saturating the size at
+    /// 0xFFFFFFFF sectors for disks larger than 2 TiB as UEFI requires.
+    pub fn new_protective(nr_blocks: u64) -> Self {
         let mut pe = PartitionEntry::new(
             EntryStatus::NotBootable,
             PartitionType::ProtectiveMBR,
             1,
-            nr_blocks,
+            nr_blocks.min(MAX_SECTORS + 1) - 1,
         );
 
         pe.last_sector = CHS::new_max();
 
         MBR {
+            disk_signature: 0,
             partition_table: [
                 pe,
                 PartitionEntry::new_empty(),
@@ -219,33 +301,110 @@ impl MBR {
         }
     }
 
-    pub fn set_entry(&mut self, index: usize, e: PartitionEntry) -> () {
+    pub fn set_entry(&mut self, index: usize, e: PartitionEntry) {
         self.partition_table[index] = e;
     }
 
-    pub fn to_raw(&self) -> RawMBR {
+    /// Moves the used entries to the front of the table in order of their starting LBA.
+    ///
+    /// Returns the (old, new) index of every used entry.
+    pub fn sort_entries(&mut self) -> Vec<(usize, usize)> {
+        let mut used: Vec<(usize, PartitionEntry)> = self
+            .partition_table
+            .iter_mut()
+            .map(|e| std::mem::replace(e, PartitionEntry::new_empty()))
+            .enumerate()
+            .filter(|(_, e)| e.ptype != PartitionType::Empty)
+            .collect();
+        used.sort_by_key(|(_, e)| e.first_sector_lba);
+
+        used.into_iter()
+            .enumerate()
+            .map(|(new, (old, e))| {
+                self.partition_table[new] = e;
+                (old, new)
+            })
+            .collect()
+    }
+
+    pub fn to_raw(&self) -> Result<RawMBR, MBRError> {
         let mut mbr = RawMBR::new();
 
+        mbr.bootstrap[DISK_SIGNATURE_OFFSET..DISK_SIGNATURE_OFFSET + 4]
+            .copy_from_slice(&u32_to_le(self.disk_signature));
+
         for i in 0..4 {
-            mbr.partition_entries[i] = self.partition_table[i].to_raw();
+            mbr.partition_entries[i] = self.partition_table[i]
+                .to_raw()
+                .map_err(|_| MBRError::PartitionOutOfRange(i + 1))?;
         }
 
-        mbr
+        Ok(mbr)
+    }
+
+    /// Writes the partition table and disk signature, preserving any existing boot code.
+    pub fn write(&self, image: &mut Image<impl WritableBlockDevice>) -> Result<(), MBRError> {
+        let mut mbr = self.to_raw()?;
+
+        let existing = image.read::<RawMBR>(0)?;
+        mbr.bootstrap[..DISK_SIGNATURE_OFFSET]
+            .copy_from_slice(&existing.bootstrap[..DISK_SIGNATURE_OFFSET]);
+
+        image.write(0, &mbr)?;
+
+        Ok(())
     }
 
-    pub fn write(&self, image: &mut Image) {
-        let mbr = self.to_raw();
-        image.write(0, mbr);
+    /// Returns true if sector 0 is the boot sector of a filesystem, such as a FAT volume boot
+    /// record, rather than an MBR. Both end in 0x55AA; a boot sector has a sane BIOS parameter
+    /// block and no plausible partition entries.
+    pub fn is_boot_sector(image: &Image<impl BlockDevice>) -> bool {
+        let Ok(raw) = image.read::<RawMBR>(0) else {
+            return false;
+        };
+        let bpb = &raw.bootstrap;
+
+        let has_jump = bpb[0] == 0xeb && bpb[2] == 0x90 || bpb[0] == 0xe9;
+        let is_exfat_or_ntfs = &bpb[3..11] == b"EXFAT   " || &bpb[3..11] == b"NTFS    ";
+
+        let bytes_per_sector = u16::from_le_bytes([bpb[0x0b], bpb[0x0c]]);
+        let sectors_per_cluster = bpb[0x0d];
+        let reserved_sectors = u16::from_le_bytes([bpb[0x0e], bpb[0x0f]]);
+        let nr_fats = bpb[0x10];
+        let has_fat_bpb = bytes_per_sector.is_power_of_two()
+            && (512..=4096).contains(&bytes_per_sector)
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && (1..=2).contains(&nr_fats);
+
+        if !(has_jump && (has_fat_bpb || is_exfat_or_ntfs)) {
+            return false;
+        }
+
+        // Boot code or messages in a boot sector overlap the partition table area.
+        let entries = raw.partition_entries;
+        let plausible = entries.iter().all(|e| e.status & 0x7f == 0)
+            && entries.iter().any(|e| {
+                let (start, count) = (e.first_sector_lba, e.nr_sectors);
+                e.ptype != 0 && start > 0 && count > 0
+            });
+
+        !plausible
     }
 
-    pub fn read(image: &Image) -> Option<Self> {
-        let raw = image.read::<RawMBR>(0);
+    pub fn read(image: &Image<impl BlockDevice>) -> Option<Self> {
+        let raw = image.read::<RawMBR>(0).ok()?;
 
         if raw.signature != [0x55, 0xaa] {
             return None;
         }
 
+        let mut disk_signature = [0; 4];
+        disk_signature
+            .copy_from_slice(&raw.bootstrap[DISK_SIGNATURE_OFFSET..DISK_SIGNATURE_OFFSET + 4]);
+
         Some(MBR {
+            disk_signature: u32::from_le_bytes(disk_signature),
             partition_table: [
                 PartitionEntry::from_raw(raw.partition_entries[0]),
                 PartitionEntry::from_raw(raw.partition_entries[1]),
@@ -260,10 +419,101 @@ impl Display for MBR {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for pte in &self.partition_table {
             if pte.ptype != PartitionType::Empty {
-                f.write_fmt(format_args!("{}", pte))?;
+                f.write_fmt(format_args!("{}\n", pte))?;
             }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::MemoryDevice;
+
+    fn image() -> Image<MemoryDevice> {
+        Image::new(MemoryDevice::new(vec![0; 8 * 1024 * 1024]))
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let mut mbr = MBR::with_ids(&IdSource::Seeded(String::from("test")));
+        mbr.set_entry(
+            0,
+            PartitionEntry::new(
+                EntryStatus::Bootable,
+                PartitionType::from_byte(0x0c),
+                2048,
+                4095,
+            ),
+        );
+        mbr.set_entry(
+            2,
+            PartitionEntry::new(
+                EntryStatus::NotBootable,
+                PartitionType::from_byte(0x83),
+                4096,
+                16383,
+            ),
+        );
+
+        let mut image = image();
+        mbr.write(&mut image).unwrap();
+
+        let read = MBR::read(&image).unwrap();
+        assert_eq!(read.disk_signature, mbr.disk_signature);
+
+        for (written, read) in mbr.partition_table.iter().zip(&read.partition_table) {
+            assert_eq!(read.status, written.status);
+            assert_eq!(read.ptype, written.ptype);
+            assert_eq!(read.first_sector_lba, written.first_sector_lba);
+            assert_eq!(read.nr_sectors, written.nr_sectors);
+            assert_eq!(
+                read.first_sector.to_bytes(),
+                written.first_sector.to_bytes()
+            );
+            assert_eq!(read.last_sector.to_bytes(), written.last_sector.to_bytes());
+        }
+    }
+
+    #[test]
+    fn write_preserves_boot_code() {
+        let mut image = image();
+        image.write_bytes(0, &[0xeb; 440]).unwrap();
+
+        MBR::with_ids(&IdSource::Random).write(&mut image).unwrap();
+
+        assert!(image.get_bytes(0, 440).unwrap().iter().all(|&b| b == 0xeb));
+        assert!(MBR::read(&image).is_some());
+    }
+
+    #[test]
+    fn boot_sector_without_filesystem_is_read_as_mbr() {
+        let mut image = image();
+        // A jump and a FAT BIOS parameter block, but no filesystem behind them.
+        let mut bpb = [0; 0x11];
+        bpb[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        bpb[0x0b..0x0d].copy_from_slice(&512u16.to_le_bytes());
+        bpb[0x0d] = 1;
+        bpb[0x0e] = 1;
+        bpb[0x10] = 2;
+        image.write_bytes(0, &bpb).unwrap();
+
+        MBR::with_ids(&IdSource::Random).write(&mut image).unwrap();
+
+        assert!(MBR::is_boot_sector(&image));
+        assert!(matches!(
+            crate::pt::read_partition_table(&image),
+            Some(crate::pt::PartitionTable::MBR(_))
+        ));
+    }
+
+    #[test]
+    fn protective_mbr_saturates() {
+        let mbr = MBR::new_protective(u64::from(u32::MAX) * 4);
+
+        let raw = mbr.to_raw().unwrap();
+        assert_eq!(raw.partition_entries[0].nr_sectors, u32::MAX);
+    }
+}
@@ -1,8 +1,18 @@
-use crate::image::Image;
+use crate::{
+    fs::probe::{probe_partition, FsInfo},
+    image::{BlockDevice, Image, KernelPartition},
+};
 
+pub mod backup;
 pub mod gpt;
+pub mod ids;
+pub mod json;
 pub mod mbr;
 pub mod raw;
+pub mod regions;
+pub mod sfdisk;
+
+const BLOCK_SIZE: u64 = 512;
 
 #[derive(Debug)]
 pub enum PartitionTableType {
@@ -11,23 +21,104 @@ pub enum PartitionTableType {
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum PartitionTable {
     MBR(mbr::MBR),
     GPT(gpt::GPT),
+    /// No partition table, with a filesystem covering the whole disk.
+    Superfloppy(FsInfo),
+}
+
+pub fn read_partition_table(image: &Image<impl BlockDevice>) -> Option<PartitionTable> {
+    read_partition_table_verbose(image).0
+}
+
+/// Reads the partition table like [`read_partition_table`], also returning why the GPT was
+/// ignored if the image has a GPT header that cannot be used, e.g. because a partition lies
+/// outside the usable area.
+pub fn read_partition_table_verbose(
+    image: &Image<impl BlockDevice>,
+) -> (Option<PartitionTable>, Option<gpt::GPTError>) {
+    let gpt_error = match gpt::GPT::read(image) {
+        Ok(gpt) => return (Some(PartitionTable::GPT(gpt)), None),
+        Err(e) => e,
+    };
+
+    let whole_disk = || probe_partition(image, 0, image.len() / BLOCK_SIZE);
+    let table = match mbr::MBR::read(image) {
+        // A boot sector without a filesystem behind it may still be a real MBR.
+        Some(mbr) if mbr::MBR::is_boot_sector(image) => match whole_disk() {
+            Some(fs) => Some(PartitionTable::Superfloppy(fs)),
+            None => Some(PartitionTable::MBR(mbr)),
+        },
+        Some(mbr) => Some(PartitionTable::MBR(mbr)),
+        None => whole_disk().map(PartitionTable::Superfloppy),
+    };
+
+    let has_gpt_header = image
+        .get_blocks(1, 1)
+        .is_ok_and(|b| b[..8] == raw::GPT_SIGNATURE);
+
+    (table, has_gpt_header.then_some(gpt_error))
 }
 
-pub fn read_partition_table(image: &Image) -> Option<PartitionTable> {
-    let mbr = mbr::MBR::read(image);
+impl PartitionTable {
+    /// Finds a partition by its number, starting at 1, or by its GPT partition name.
+    ///
+    /// Returns the first block and number of blocks of the partition.
+    pub fn find_partition(&self, selector: &str) -> Option<(u64, u64)> {
+        let number = selector.parse::<usize>().ok();
 
-    match mbr {
-        Some(mbr) => {
-            let gpt = gpt::GPT::read(image);
+        match self {
+            PartitionTable::GPT(gpt) => gpt
+                .partitions()
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| !p.is_empty())
+                .find(|(i, p)| number == Some(i + 1) || p.name() == selector)
+                .map(|(_, p)| (p.start(), p.nr_sectors())),
+            PartitionTable::MBR(mbr) => mbr
+                .partition_table
+                .iter()
+                .enumerate()
+                .filter(|(_, pte)| pte.ptype != mbr::PartitionType::Empty)
+                .find(|(i, _)| number == Some(i + 1))
+                .map(|(_, pte)| (pte.first_sector_lba, pte.nr_sectors)),
+            PartitionTable::Superfloppy(_) => None,
+        }
+    }
 
-            match gpt {
-                Some(gpt) => Some(PartitionTable::GPT(gpt)),
-                None => Some(PartitionTable::MBR(mbr)),
-            }
+    /// Returns the partitions as the kernel would register them, or `None` if the table has an
+    /// extended partition whose logical partitions only the kernel numbers.
+    pub fn kernel_partitions(&self) -> Option<Vec<KernelPartition>> {
+        match self {
+            PartitionTable::GPT(gpt) => Some(
+                gpt.partitions()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, p)| !p.is_empty())
+                    .map(|(i, p)| KernelPartition {
+                        number: i + 1,
+                        start: p.start(),
+                        nr_sectors: p.nr_sectors(),
+                    })
+                    .collect(),
+            ),
+            PartitionTable::MBR(mbr) => mbr
+                .partition_table
+                .iter()
+                .enumerate()
+                .filter(|(_, pte)| pte.ptype != mbr::PartitionType::Empty)
+                .map(|(i, pte)| match pte.ptype.to_byte() {
+                    0x05 | 0x0f | 0x85 => None,
+                    _ => Some(KernelPartition {
+                        number: i + 1,
+                        start: pte.first_sector_lba,
+                        nr_sectors: pte.nr_sectors,
+                    }),
+                })
+                .collect(),
+            PartitionTable::Superfloppy(_) => Some(Vec::new()),
         }
-        None => None,
     }
 }
@@ -1,11 +1,20 @@
+use crate::image::OnDisk;
+
 pub const GPT_PTYPE_EMPTY: &str = "00000000-0000-0000-0000-000000000000";
 pub const GPT_PTYPE_MBR: &str = "024DEE41-33E7-11D3-9D69-0008C781F39F";
 pub const GPT_PTYPE_EFI_SYSTEM: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
 pub const GPT_PTYPE_BIOS_BOOT: &str = "21686148-6449-6E6F-744E-656564454649";
 pub const GPT_PTYPE_LINUX_FS: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+pub const GPT_PTYPE_LINUX_SWAP: &str = "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F";
+pub const GPT_PTYPE_LINUX_LVM: &str = "E6D6D379-F507-44C2-A23C-238F2A3DF928";
+pub const GPT_PTYPE_LINUX_RAID: &str = "A19D880F-05FC-4D3B-A006-743F0F84911E";
+
+pub const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
+
+/// Largest LBA accepted from a GPT header, so that the byte offset just past it fits a `usize`.
+pub const GPT_MAX_LBA: u64 = (usize::MAX / 512 - 1) as u64;
 
 #[derive(Clone, Copy)]
-#[repr(C, packed)]
 pub struct RawGPTPartitionEntry {
     pub ptype: [u8; 16],
     pub ident: [u8; 16],
@@ -16,7 +25,6 @@ pub struct RawGPTPartitionEntry {
 }
 
 #[derive(Clone, Copy)]
-#[repr(C, packed)]
 pub struct RawGPTHeader {
     pub signature: [u8; 8],
     pub revision: u32,
@@ -34,12 +42,18 @@ pub struct RawGPTHeader {
     pub partition_entries_checksum: u32,
 }
 
+impl Default for RawGPTHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RawGPTHeader {
     pub fn new() -> Self {
         RawGPTHeader {
-            signature: [0x45, 0x46, 0x49, 0x20, 0x50, 0x41, 0x52, 0x54],
+            signature: GPT_SIGNATURE,
             revision: 0x00010000,
-            header_size: std::mem::size_of::<Self>() as u32,
+            header_size: Self::SIZE as u32,
             header_checksum: 0,
             reserved: 0,
             this_header_lba: 0,
@@ -49,29 +63,47 @@ impl RawGPTHeader {
             disk_guid: [0; 16],
             partition_entries_lba: 0,
             nr_partition_entries: 0,
-            partition_entry_size: std::mem::size_of::<RawGPTPartitionEntry>() as u32,
+            partition_entry_size: RawGPTPartitionEntry::SIZE as u32,
             partition_entries_checksum: 0,
         }
     }
 
-    pub fn compute_checksum(&self) -> u32 {
-        let mut crc = crc_any::CRC::crc32();
+    /// Returns true if the sizes and addresses in the header can be used without overflowing:
+    /// sizes the specification allows, an entry array past the protective MBR and the primary
+    /// header, and LBAs no larger than `GPT_MAX_LBA`.
+    pub fn is_valid(&self) -> bool {
+        (Self::SIZE as u32..=512).contains(&self.header_size)
+            && self.partition_entry_size >= RawGPTPartitionEntry::SIZE as u32
+            && self.partition_entry_size.is_power_of_two()
+            && self.partition_entries_lba >= 2
+            && self.first_usable_lba <= self.last_usable_lba
+            && [
+                self.this_header_lba,
+                self.other_header_lba,
+                self.last_usable_lba,
+                self.partition_entries_lba,
+            ]
+            .iter()
+            .all(|&lba| lba <= GPT_MAX_LBA)
+    }
 
-        let data = unsafe {
-            ::std::slice::from_raw_parts(
-                (self as *const _) as *const u8,
-                ::std::mem::size_of::<Self>(),
-            )
-        };
+    /// Size of the partition entry array in bytes.
+    pub fn entries_size(&self) -> usize {
+        self.nr_partition_entries as usize * self.partition_entry_size as usize
+    }
+
+    pub fn compute_checksum(&self) -> u32 {
+        let mut data = [0; Self::SIZE];
+        self.encode(&mut data);
 
-        crc.digest(data);
+        let mut crc = crc_any::CRC::crc32();
+        crc.digest(&data);
 
         crc.get_crc() as u32
     }
 }
 
 #[derive(Clone, Copy)]
-#[repr(C, packed)]
 pub struct RawMBRPartitionEntry {
     pub status: u8,
     pub first_sector_chs: [u8; 3],
@@ -81,6 +113,12 @@ pub struct RawMBRPartitionEntry {
     pub nr_sectors: u32,
 }
 
+impl Default for RawMBRPartitionEntry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RawMBRPartitionEntry {
     pub fn new() -> Self {
         RawMBRPartitionEntry {
@@ -95,13 +133,18 @@ impl RawMBRPartitionEntry {
 }
 
 #[derive(Clone, Copy)]
-#[repr(C, packed)]
 pub struct RawMBR {
     pub bootstrap: [u8; 0x01be],
     pub partition_entries: [RawMBRPartitionEntry; 4],
     pub signature: [u8; 2],
 }
 
+impl Default for RawMBR {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RawMBR {
     pub fn new() -> Self {
         RawMBR {
@@ -111,3 +154,137 @@ impl RawMBR {
         }
     }
 }
+
+/// Returns the `N` bytes at `offset` of `bytes`.
+fn array<const N: usize>(bytes: &[u8], offset: usize) -> [u8; N] {
+    bytes[offset..offset + N].try_into().unwrap()
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(array(bytes, offset))
+}
+
+fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(array(bytes, offset))
+}
+
+fn put(bytes: &mut [u8], offset: usize, data: &[u8]) {
+    bytes[offset..offset + data.len()].copy_from_slice(data);
+}
+
+impl OnDisk for RawGPTPartitionEntry {
+    const SIZE: usize = 128;
+
+    fn decode(bytes: &[u8]) -> Self {
+        RawGPTPartitionEntry {
+            ptype: array(bytes, 0),
+            ident: array(bytes, 16),
+            starting_lba: le_u64(bytes, 32),
+            ending_lba: le_u64(bytes, 40),
+            attributes: le_u64(bytes, 48),
+            name: array(bytes, 56),
+        }
+    }
+
+    fn encode(&self, bytes: &mut [u8]) {
+        put(bytes, 0, &self.ptype);
+        put(bytes, 16, &self.ident);
+        put(bytes, 32, &self.starting_lba.to_le_bytes());
+        put(bytes, 40, &self.ending_lba.to_le_bytes());
+        put(bytes, 48, &self.attributes.to_le_bytes());
+        put(bytes, 56, &self.name);
+    }
+}
+
+impl OnDisk for RawGPTHeader {
+    const SIZE: usize = 92;
+
+    fn decode(bytes: &[u8]) -> Self {
+        RawGPTHeader {
+            signature: array(bytes, 0),
+            revision: le_u32(bytes, 8),
+            header_size: le_u32(bytes, 12),
+            header_checksum: le_u32(bytes, 16),
+            reserved: le_u32(bytes, 20),
+            this_header_lba: le_u64(bytes, 24),
+            other_header_lba: le_u64(bytes, 32),
+            first_usable_lba: le_u64(bytes, 40),
+            last_usable_lba: le_u64(bytes, 48),
+            disk_guid: array(bytes, 56),
+            partition_entries_lba: le_u64(bytes, 72),
+            nr_partition_entries: le_u32(bytes, 80),
+            partition_entry_size: le_u32(bytes, 84),
+            partition_entries_checksum: le_u32(bytes, 88),
+        }
+    }
+
+    fn encode(&self, bytes: &mut [u8]) {
+        put(bytes, 0, &self.signature);
+        put(bytes, 8, &self.revision.to_le_bytes());
+        put(bytes, 12, &self.header_size.to_le_bytes());
+        put(bytes, 16, &self.header_checksum.to_le_bytes());
+        put(bytes, 20, &self.reserved.to_le_bytes());
+        put(bytes, 24, &self.this_header_lba.to_le_bytes());
+        put(bytes, 32, &self.other_header_lba.to_le_bytes());
+        put(bytes, 40, &self.first_usable_lba.to_le_bytes());
+        put(bytes, 48, &self.last_usable_lba.to_le_bytes());
+        put(bytes, 56, &self.disk_guid);
+        put(bytes, 72, &self.partition_entries_lba.to_le_bytes());
+        put(bytes, 80, &self.nr_partition_entries.to_le_bytes());
+        put(bytes, 84, &self.partition_entry_size.to_le_bytes());
+        put(bytes, 88, &self.partition_entries_checksum.to_le_bytes());
+    }
+}
+
+impl OnDisk for RawMBRPartitionEntry {
+    const SIZE: usize = 16;
+
+    fn decode(bytes: &[u8]) -> Self {
+        RawMBRPartitionEntry {
+            status: bytes[0],
+            first_sector_chs: array(bytes, 1),
+            ptype: bytes[4],
+            last_sector_chs: array(bytes, 5),
+            first_sector_lba: le_u32(bytes, 8),
+            nr_sectors: le_u32(bytes, 12),
+        }
+    }
+
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes[0] = self.status;
+        put(bytes, 1, &self.first_sector_chs);
+        bytes[4] = self.ptype;
+        put(bytes, 5, &self.last_sector_chs);
+        put(bytes, 8, &self.first_sector_lba.to_le_bytes());
+        put(bytes, 12, &self.nr_sectors.to_le_bytes());
+    }
+}
+
+/// Offset of the partition table within the MBR.
+const MBR_ENTRIES_OFFSET: usize = 0x1be;
+
+impl OnDisk for RawMBR {
+    const SIZE: usize = 512;
+
+    fn decode(bytes: &[u8]) -> Self {
+        let entry = |i: usize| {
+            RawMBRPartitionEntry::decode(
+                &bytes[MBR_ENTRIES_OFFSET + i * RawMBRPartitionEntry::SIZE..],
+            )
+        };
+
+        RawMBR {
+            bootstrap: array(bytes, 0),
+            partition_entries: [entry(0), entry(1), entry(2), entry(3)],
+            signature: array(bytes, 0x1fe),
+        }
+    }
+
+    fn encode(&self, bytes: &mut [u8]) {
+        put(bytes, 0, &self.bootstrap);
+        for (i, e) in self.partition_entries.iter().enumerate() {
+            e.encode(&mut bytes[MBR_ENTRIES_OFFSET + i * RawMBRPartitionEntry::SIZE..]);
+        }
+        put(bytes, 0x1fe, &self.signature);
+    }
+}
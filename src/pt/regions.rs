@@ -0,0 +1,229 @@
+use std::fmt::Display;
+
+use humansize::BINARY;
+
+use crate::image::{BlockDevice, Image};
+
+use super::{mbr::PartitionType, read_partition_table, PartitionTable};
+
+const BLOCK_SIZE: u64 = 512;
+
+/// A byte range of the image with a known purpose.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    /// Offset of the first byte.
+    pub start: u64,
+    /// Offset one past the last byte.
+    pub end: u64,
+}
+
+impl Region {
+    fn from_blocks(name: String, first_block: u64, nr_blocks: u64) -> Self {
+        Region {
+            name,
+            start: first_block * BLOCK_SIZE,
+            end: (first_block + nr_blocks) * BLOCK_SIZE,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        start < self.end && self.start < end
+    }
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{:#012x}-{:#012x} {:>10} {}",
+            self.start,
+            self.end - 1,
+            humansize::format_size(self.len(), BINARY),
+            self.name
+        ))
+    }
+}
+
+/// Returns the regions occupied by the partition table structures and the partitions, in order
+/// of their start offset.
+pub fn table_regions(image: &Image<impl BlockDevice>) -> Vec<Region> {
+    let nr_blocks = image.len() / BLOCK_SIZE;
+    let mut regions = Vec::new();
+
+    match read_partition_table(image) {
+        Some(PartitionTable::GPT(gpt)) => {
+            let layout = gpt.layout();
+            let nr_entry_blocks = gpt.nr_entry_blocks();
+
+            regions.push(Region::from_blocks(String::from("protective MBR"), 0, 1));
+            regions.push(Region::from_blocks(
+                String::from("primary GPT header"),
+                1,
+                1,
+            ));
+            regions.push(Region::from_blocks(
+                String::from("primary GPT entries"),
+                layout.entries_lba,
+                nr_entry_blocks,
+            ));
+            regions.push(Region::from_blocks(
+                String::from("backup GPT entries"),
+                nr_blocks - 1 - nr_entry_blocks,
+                nr_entry_blocks,
+            ));
+            regions.push(Region::from_blocks(
+                String::from("backup GPT header"),
+                nr_blocks - 1,
+                1,
+            ));
+
+            for (i, p) in gpt.partitions().iter().enumerate() {
+                if !p.is_empty() {
+                    regions.push(Region::from_blocks(
+                        format!("partition {}", i + 1),
+                        p.start(),
+                        p.nr_sectors(),
+                    ));
+                }
+            }
+        }
+        Some(PartitionTable::MBR(mbr)) => {
+            regions.push(Region::from_blocks(String::from("MBR"), 0, 1));
+
+            for (i, pte) in mbr.partition_table.iter().enumerate() {
+                if pte.ptype != PartitionType::Empty {
+                    regions.push(Region::from_blocks(
+                        format!("partition {}", i + 1),
+                        pte.first_sector_lba,
+                        pte.nr_sectors,
+                    ));
+                }
+            }
+        }
+        Some(PartitionTable::Superfloppy(fs)) => {
+            regions.push(Region {
+                name: format!("whole-disk {} filesystem", fs.fstype),
+                start: 0,
+                end: image.len(),
+            });
+        }
+        None => {}
+    }
+
+    regions.sort_by_key(|r| r.start);
+    regions
+}
+
+/// Amount of the image read at once while looking for raw data.
+const SCAN_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Returns the byte ranges that may hold raw data outside the partitions: the gap before the
+/// first partition, where boot loaders are embedded, and the space after the last usable LBA.
+/// Without a partition table this is the whole image.
+fn scan_ranges(image: &Image<impl BlockDevice>) -> Vec<(u64, u64)> {
+    let nr_blocks = image.len() / BLOCK_SIZE;
+
+    let ranges = match read_partition_table(image) {
+        Some(PartitionTable::GPT(gpt)) => {
+            let (first_usable, last_usable) = gpt.usable_range(nr_blocks);
+            let first_partition = gpt
+                .partitions()
+                .iter()
+                .filter(|p| !p.is_empty())
+                .map(|p| p.start())
+                .min()
+                .unwrap_or(first_usable);
+
+            vec![(0, first_partition), (last_usable + 1, nr_blocks)]
+        }
+        Some(PartitionTable::MBR(mbr)) => {
+            let first_partition = mbr
+                .partition_table
+                .iter()
+                .filter(|pte| pte.ptype != PartitionType::Empty)
+                .map(|pte| pte.first_sector_lba)
+                .min()
+                .unwrap_or(nr_blocks);
+
+            vec![(0, first_partition)]
+        }
+        Some(PartitionTable::Superfloppy(_)) => Vec::new(),
+        None => vec![(0, nr_blocks)],
+    };
+
+    ranges
+        .into_iter()
+        .map(|(start, end)| (start * BLOCK_SIZE, end.min(nr_blocks) * BLOCK_SIZE))
+        .filter(|(start, end)| start < end)
+        .collect()
+}
+
+/// Finds runs of non-zero blocks outside `known` and the partitions, such as bootloaders
+/// written at fixed offsets. Holes in the image are skipped without reading them.
+pub fn raw_data_regions(image: &Image<impl BlockDevice>, known: &[Region]) -> Vec<Region> {
+    let mut known: Vec<&Region> = known.iter().collect();
+    known.sort_by_key(|r| r.start);
+
+    // The parts of the scanned ranges not covered by a known region.
+    let mut gaps = Vec::new();
+    for (start, end) in scan_ranges(image) {
+        let mut pos = start;
+        for r in known.iter().filter(|r| r.overlaps(start, end)) {
+            if r.start > pos {
+                gaps.push((pos, r.start));
+            }
+            pos = pos.max(r.end.div_ceil(BLOCK_SIZE) * BLOCK_SIZE);
+        }
+        if pos < end {
+            gaps.push((pos, end));
+        }
+    }
+
+    let mut regions: Vec<Region> = Vec::new();
+    for (start, end) in gaps {
+        let extents = image
+            .data_extents(start, end - start)
+            .unwrap_or_else(|_| vec![(start, end)]);
+
+        for (data_start, data_end) in extents {
+            // Holes are reported in filesystem blocks, which need not be aligned to ours.
+            let mut offset = (data_start / BLOCK_SIZE * BLOCK_SIZE).max(start);
+            let data_end = (data_end.div_ceil(BLOCK_SIZE) * BLOCK_SIZE).min(end);
+
+            while offset < data_end {
+                let len = SCAN_CHUNK_SIZE.min(data_end - offset);
+                let Ok(chunk) = image.get_bytes(offset, len as usize) else {
+                    break;
+                };
+
+                for (i, block) in chunk.chunks(BLOCK_SIZE as usize).enumerate() {
+                    if block.iter().all(|&b| b == 0) {
+                        continue;
+                    }
+
+                    let block_start = offset + i as u64 * BLOCK_SIZE;
+                    match regions.last_mut() {
+                        Some(last) if last.end == block_start => last.end += BLOCK_SIZE,
+                        _ => regions.push(Region {
+                            name: String::from("raw data"),
+                            start: block_start,
+                            end: block_start + BLOCK_SIZE,
+                        }),
+                    }
+                }
+
+                offset += len;
+            }
+        }
+    }
+
+    regions
+}
@@ -0,0 +1,694 @@
+use std::fmt::Write;
+
+use nuuid::Uuid;
+use parse_size::Config;
+
+use super::{
+    gpt::{Layout, Partition, GPT},
+    ids::IdSource,
+    mbr::{EntryStatus, PartitionEntry, PartitionType, MBR},
+    raw::{
+        GPT_PTYPE_EFI_SYSTEM, GPT_PTYPE_LINUX_FS, GPT_PTYPE_LINUX_LVM, GPT_PTYPE_LINUX_RAID,
+        GPT_PTYPE_LINUX_SWAP,
+    },
+    PartitionTable,
+};
+
+const SECTOR_SIZE: usize = 512;
+
+/// Partitions placed without an explicit start are aligned to 1 MiB, as sfdisk does.
+const ALIGNMENT: u64 = 2048;
+
+/// GPT partition attribute bits that sfdisk refers to by name.
+const ATTRIBUTE_NAMES: [(u32, &str); 3] = [
+    (0, "RequiredPartition"),
+    (1, "NoBlockIOProtocol"),
+    (2, "LegacyBIOSBootable"),
+];
+
+/// sfdisk's shortcut partition types, as (alias, GPT type, MBR type).
+const TYPE_ALIASES: [(&str, &str, u8); 5] = [
+    ("L", GPT_PTYPE_LINUX_FS, 0x83),
+    ("S", GPT_PTYPE_LINUX_SWAP, 0x82),
+    ("U", GPT_PTYPE_EFI_SYSTEM, 0xef),
+    ("R", GPT_PTYPE_LINUX_RAID, 0xfd),
+    ("V", GPT_PTYPE_LINUX_LVM, 0x8e),
+];
+
+/// Resolves a GPT partition type given either as a GUID or as one of sfdisk's shortcuts.
+pub fn gpt_type(s: &str) -> Option<Uuid> {
+    match TYPE_ALIASES.iter().find(|(alias, _, _)| *alias == s) {
+        Some((_, guid, _)) => Uuid::parse(guid).ok(),
+        None => Uuid::parse(s).ok(),
+    }
+}
+
+/// Error while parsing or applying an sfdisk script.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ScriptError {
+    /// line {0}: {1}
+    ParseError(usize, String),
+    /// the script does not specify a partition table label
+    MissingLabel,
+    /// unsupported partition table label `{0}`
+    UnsupportedLabel(String),
+    /// invalid label-id `{0}`
+    InvalidLabelId(String),
+    /// invalid partition type `{0}`
+    InvalidType(String),
+    /// partition {0} does not fit within the usable area of the disk
+    OutOfRange(usize),
+    /// partition {0} overlaps partition {1}
+    Overlap(usize, usize),
+    /// partition number {0} is not available in this partition table
+    InvalidPartitionNumber(usize),
+    /// the GPT entry array does not fit on the disk, or has fewer than 128 entries
+    InvalidTableLength,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Label {
+    Dos,
+    Gpt,
+}
+
+/// A single partition line of an sfdisk script.
+#[derive(Debug, Default)]
+pub struct ScriptPartition {
+    /// Partition number taken from the device name, if one was given.
+    pub number: Option<usize>,
+    pub start: Option<u64>,
+    /// Size in sectors, or `None` to fill the remaining space.
+    pub size: Option<u64>,
+    pub ptype: Option<String>,
+    pub uuid: Option<Uuid>,
+    pub name: Option<String>,
+    pub attributes: u64,
+    pub bootable: bool,
+}
+
+/// A parsed sfdisk script.
+#[derive(Debug, Default)]
+pub struct Script {
+    pub label: Option<Label>,
+    pub label_id: Option<String>,
+    /// Name of the whole device, which partition names start with.
+    pub device: Option<String>,
+    pub first_lba: Option<u64>,
+    pub last_lba: Option<u64>,
+    /// Number of GPT partition entries.
+    pub table_length: Option<usize>,
+    /// LBA of the primary GPT partition entry array.
+    pub entries_lba: Option<u64>,
+    pub partitions: Vec<ScriptPartition>,
+}
+
+pub(crate) fn format_attributes(attributes: u64) -> String {
+    let mut parts: Vec<String> = ATTRIBUTE_NAMES
+        .iter()
+        .filter(|(bit, _)| attributes & (1 << bit) != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+
+    let guid_bits: Vec<String> = (48..64)
+        .filter(|bit| attributes & (1 << bit) != 0)
+        .map(|bit| bit.to_string())
+        .collect();
+
+    if !guid_bits.is_empty() {
+        parts.push(format!("GUID:{}", guid_bits.join(",")));
+    }
+
+    parts.join(" ")
+}
+
+fn parse_attributes(s: &str) -> Result<u64, String> {
+    let mut attributes = 0;
+
+    for word in s.split_whitespace() {
+        if let Some(bits) = word.strip_prefix("GUID:") {
+            for bit in bits.split(',') {
+                let bit: u32 = bit
+                    .parse()
+                    .map_err(|_| format!("invalid attribute bit `{}`", bit))?;
+                if !(48..64).contains(&bit) {
+                    return Err(format!("attribute bit {} is out of range", bit));
+                }
+                attributes |= 1 << bit;
+            }
+        } else {
+            let (bit, _) = ATTRIBUTE_NAMES
+                .iter()
+                .find(|(_, name)| *name == word)
+                .ok_or_else(|| format!("unknown attribute `{}`", word))?;
+            attributes |= 1 << bit;
+        }
+    }
+
+    Ok(attributes)
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn unquote(s: &str) -> String {
+    match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::new();
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => out.extend(chars.next()),
+                    c => out.push(c),
+                }
+            }
+            out
+        }
+        None => s.to_string(),
+    }
+}
+
+/// Splits `s` on `sep`, ignoring separators inside double quotes.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut field_start = 0;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            c if c == sep && !in_quotes => {
+                fields.push(&s[field_start..i]);
+                field_start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+
+    fields.push(&s[field_start..]);
+    fields
+}
+
+/// Parses a sector count, which is either a plain number of sectors or a size with a unit suffix.
+fn parse_sectors(s: &str) -> Result<u64, String> {
+    let bytes = Config::new()
+        .with_binary()
+        .with_default_factor(SECTOR_SIZE as u64)
+        .parse_size(s)
+        .map_err(|e| format!("invalid size `{}`: {}", s, e))?;
+
+    Ok(bytes.div_ceil(SECTOR_SIZE as u64))
+}
+
+/// Returns the name of partition `number` of `device`, separated by a `p` if the device name
+/// ends in a digit, as with `/dev/nvme0n1p1`.
+pub fn partition_node(device: &str, number: usize) -> String {
+    if device.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", device, number)
+    } else {
+        format!("{}{}", device, number)
+    }
+}
+
+/// Extracts the partition number from a partition name such as `disk.img3` or `disk2p3`,
+/// relative to the name of the whole device if the script gives it.
+fn partition_number(node: &str, device: Option<&str>) -> Option<usize> {
+    if let Some(number) = device.and_then(|device| node.strip_prefix(device)) {
+        return number.strip_prefix('p').unwrap_or(number).parse().ok();
+    }
+
+    let digits = node.len() - node.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+
+    node[node.len() - digits..].parse().ok()
+}
+
+fn parse_partition(line: &str, device: Option<&str>) -> Result<ScriptPartition, String> {
+    let mut p = ScriptPartition::default();
+
+    let spec = match split_unquoted(line, ':').as_slice() {
+        [spec] => *spec,
+        [node, spec] if !node.contains('=') => {
+            p.number = partition_number(node.trim(), device);
+            *spec
+        }
+        _ => return Err(String::from("malformed partition line")),
+    };
+
+    for field in split_unquoted(spec, ',') {
+        let field = field.trim();
+
+        match field.split_once('=') {
+            Some((key, value)) => {
+                let value = value.trim();
+                match key.trim() {
+                    "start" => p.start = Some(parse_sectors(value)?),
+                    "size" => {
+                        p.size = match value {
+                            "+" => None,
+                            v => Some(parse_sectors(v)?),
+                        }
+                    }
+                    "type" | "Id" => p.ptype = Some(unquote(value)),
+                    "uuid" => {
+                        p.uuid = Some(
+                            Uuid::parse(&unquote(value))
+                                .map_err(|_| format!("invalid uuid `{}`", value))?,
+                        )
+                    }
+                    "name" => p.name = Some(unquote(value)),
+                    "attrs" => p.attributes = parse_attributes(&unquote(value))?,
+                    key => return Err(format!("unknown field `{}`", key)),
+                }
+            }
+            None => match field {
+                "bootable" => p.bootable = true,
+                "" => {}
+                field => return Err(format!("unknown field `{}`", field)),
+            },
+        }
+    }
+
+    Ok(p)
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Self, ScriptError> {
+        let mut script = Script::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line_nr = i + 1;
+            let line = line.trim();
+            let err = |msg: String| ScriptError::ParseError(line_nr, msg);
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let header = line
+                .split_once(':')
+                .filter(|(key, _)| !key.contains('='))
+                .map(|(key, value)| (key.trim(), value.trim()));
+
+            match header {
+                Some(("label", value)) => {
+                    script.label = Some(match value {
+                        "dos" => Label::Dos,
+                        "gpt" => Label::Gpt,
+                        other => return Err(ScriptError::UnsupportedLabel(other.to_string())),
+                    })
+                }
+                Some(("label-id", value)) => script.label_id = Some(value.to_string()),
+                Some(("first-lba", value)) => {
+                    script.first_lba = Some(value.parse().map_err(|_| err(value.to_string()))?)
+                }
+                Some(("last-lba", value)) => {
+                    script.last_lba = Some(value.parse().map_err(|_| err(value.to_string()))?)
+                }
+                Some(("unit", value)) => {
+                    if value != "sectors" {
+                        return Err(err(format!("unsupported unit `{}`", value)));
+                    }
+                }
+                Some(("sector-size", value)) => {
+                    if value != SECTOR_SIZE.to_string() {
+                        return Err(err(format!("unsupported sector size `{}`", value)));
+                    }
+                }
+                Some(("table-length", value)) => {
+                    script.table_length = Some(value.parse().map_err(|_| err(value.to_string()))?)
+                }
+                Some(("entries-lba", value)) => {
+                    script.entries_lba = Some(value.parse().map_err(|_| err(value.to_string()))?)
+                }
+                Some(("device", value)) => script.device = Some(value.to_string()),
+                Some(("grain", _)) => {}
+                _ => script
+                    .partitions
+                    .push(parse_partition(line, script.device.as_deref()).map_err(err)?),
+            }
+        }
+
+        Ok(script)
+    }
+
+    /// Assigns partition numbers and resolves default starts and sizes within `usable`.
+    fn place(&self, usable: (u64, u64)) -> Result<Vec<(usize, u64, u64)>, ScriptError> {
+        let mut placed: Vec<(usize, u64, u64)> = Vec::new();
+        let mut next_number = 1;
+        let mut next_start = usable.0;
+
+        for p in &self.partitions {
+            let number = p.number.unwrap_or(next_number);
+            next_number = number + 1;
+
+            let start = p
+                .start
+                .unwrap_or_else(|| next_start.div_ceil(ALIGNMENT) * ALIGNMENT);
+            let end = match p.size {
+                Some(0) => return Err(ScriptError::OutOfRange(number)),
+                Some(size) => start + size - 1,
+                None => usable.1,
+            };
+
+            if start < usable.0 || end > usable.1 || start > end {
+                return Err(ScriptError::OutOfRange(number));
+            }
+
+            if let Some((other, _, _)) = placed.iter().find(|(_, s, e)| start <= *e && *s <= end) {
+                return Err(ScriptError::Overlap(number, *other));
+            }
+
+            placed.push((number, start, end));
+            next_start = end + 1;
+        }
+
+        Ok(placed)
+    }
+
+    fn build_gpt(
+        &self,
+        nr_blocks: u64,
+        ids: &IdSource,
+        mut layout: Layout,
+    ) -> Result<GPT, ScriptError> {
+        if let Some(nr_entries) = self.table_length {
+            layout.nr_entries = nr_entries;
+        }
+        if let Some(entries_lba) = self.entries_lba {
+            layout.entries_lba = entries_lba;
+        }
+
+        let mut gpt = GPT::with_layout(ids, layout);
+        if !gpt.fits(nr_blocks) {
+            return Err(ScriptError::InvalidTableLength);
+        }
+
+        if let Some(id) = &self.label_id {
+            gpt.set_disk_guid(
+                Uuid::parse(id).map_err(|_| ScriptError::InvalidLabelId(id.clone()))?,
+            );
+        }
+
+        let (first, last) = gpt.usable_range(nr_blocks);
+        let usable = (
+            self.first_lba.unwrap_or(first).max(first),
+            self.last_lba.unwrap_or(last).min(last),
+        );
+
+        for (p, (number, start, end)) in self.partitions.iter().zip(self.place(usable)?) {
+            if number == 0 || number > gpt.partitions().len() {
+                return Err(ScriptError::InvalidPartitionNumber(number));
+            }
+
+            let ptype = p.ptype.as_deref().unwrap_or("L");
+            let type_guid =
+                gpt_type(ptype).ok_or_else(|| ScriptError::InvalidType(ptype.into()))?;
+
+            let name = p.name.clone().unwrap_or_default();
+            let part_guid = p
+                .uuid
+                .unwrap_or_else(|| ids.partition_guid(number - 1, &name));
+
+            let mut partition = Partition::new(type_guid, name, start, end);
+            partition.set_part_guid(part_guid);
+            partition.set_attributes(p.attributes);
+
+            gpt.set_partition(number - 1, partition);
+        }
+
+        Ok(gpt)
+    }
+
+    fn build_mbr(&self, nr_blocks: u64, ids: &IdSource) -> Result<MBR, ScriptError> {
+        let mut mbr = MBR::with_ids(ids);
+
+        if let Some(id) = &self.label_id {
+            mbr.disk_signature = u32::from_str_radix(id.trim_start_matches("0x"), 16)
+                .map_err(|_| ScriptError::InvalidLabelId(id.clone()))?;
+        }
+
+        let usable = (
+            self.first_lba.unwrap_or(1).max(1),
+            self.last_lba.unwrap_or(nr_blocks - 1).min(nr_blocks - 1),
+        );
+
+        for (p, (number, start, end)) in self.partitions.iter().zip(self.place(usable)?) {
+            if number == 0 || number > mbr.partition_table.len() {
+                return Err(ScriptError::InvalidPartitionNumber(number));
+            }
+
+            let ptype = p.ptype.as_deref().unwrap_or("L");
+            let ptype = match TYPE_ALIASES.iter().find(|(alias, _, _)| *alias == ptype) {
+                Some((_, _, byte)) => *byte,
+                None => u8::from_str_radix(ptype.trim_start_matches("0x"), 16)
+                    .map_err(|_| ScriptError::InvalidType(ptype.into()))?,
+            };
+
+            let status = if p.bootable {
+                EntryStatus::Bootable
+            } else {
+                EntryStatus::NotBootable
+            };
+
+            mbr.set_entry(
+                number - 1,
+                PartitionEntry::new(status, PartitionType::from_byte(ptype), start, end),
+            );
+        }
+
+        Ok(mbr)
+    }
+
+    /// Builds the partition table described by this script for an image of `nr_blocks`, taking
+    /// any identifiers the script leaves unspecified from `ids`, and placing a GPT entry array
+    /// according to `layout` unless the script sets its length.
+    pub fn build(
+        &self,
+        nr_blocks: u64,
+        ids: &IdSource,
+        layout: Layout,
+    ) -> Result<PartitionTable, ScriptError> {
+        match self.label {
+            Some(Label::Gpt) => Ok(PartitionTable::GPT(self.build_gpt(nr_blocks, ids, layout)?)),
+            Some(Label::Dos) => Ok(PartitionTable::MBR(self.build_mbr(nr_blocks, ids)?)),
+            None => Err(ScriptError::MissingLabel),
+        }
+    }
+}
+
+/// Renders a partition table as an sfdisk script, as produced by `sfdisk --dump`.
+pub fn dump(pt: &PartitionTable, device: &str, nr_blocks: u64) -> String {
+    let mut out = String::new();
+
+    match pt {
+        PartitionTable::GPT(gpt) => {
+            let (first, last) = gpt.usable_range(nr_blocks);
+
+            writeln!(out, "label: gpt").unwrap();
+            writeln!(
+                out,
+                "label-id: {}",
+                gpt.disk_guid().to_string().to_uppercase()
+            )
+            .unwrap();
+            writeln!(out, "device: {}", device).unwrap();
+            writeln!(out, "unit: sectors").unwrap();
+            writeln!(out, "first-lba: {}", first).unwrap();
+            writeln!(out, "last-lba: {}", last).unwrap();
+            if gpt.layout().nr_entries != Layout::default().nr_entries {
+                writeln!(out, "table-length: {}", gpt.layout().nr_entries).unwrap();
+            }
+            if gpt.layout().entries_lba != Layout::default().entries_lba {
+                writeln!(out, "entries-lba: {}", gpt.layout().entries_lba).unwrap();
+            }
+            writeln!(out, "sector-size: {}", SECTOR_SIZE).unwrap();
+            writeln!(out).unwrap();
+
+            for (i, p) in gpt.partitions().iter().enumerate() {
+                if p.is_empty() {
+                    continue;
+                }
+
+                write!(
+                    out,
+                    "{} : start={:>12}, size={:>12}, type={}, uuid={}",
+                    partition_node(device, i + 1),
+                    p.start(),
+                    p.nr_sectors(),
+                    p.type_guid().to_string().to_uppercase(),
+                    p.part_guid().to_string().to_uppercase()
+                )
+                .unwrap();
+                if !p.name().is_empty() {
+                    write!(out, ", name={}", quote(p.name())).unwrap();
+                }
+                if p.attributes() != 0 {
+                    write!(out, ", attrs={}", quote(&format_attributes(p.attributes()))).unwrap();
+                }
+                writeln!(out).unwrap();
+            }
+        }
+        PartitionTable::MBR(mbr) => {
+            writeln!(out, "label: dos").unwrap();
+            writeln!(out, "label-id: 0x{:08x}", mbr.disk_signature).unwrap();
+            writeln!(out, "device: {}", device).unwrap();
+            writeln!(out, "unit: sectors").unwrap();
+            writeln!(out, "sector-size: {}", SECTOR_SIZE).unwrap();
+            writeln!(out).unwrap();
+
+            for (i, pte) in mbr.partition_table.iter().enumerate() {
+                if pte.ptype == PartitionType::Empty {
+                    continue;
+                }
+
+                write!(
+                    out,
+                    "{} : start={:>12}, size={:>12}, type={:x}",
+                    partition_node(device, i + 1),
+                    pte.first_sector_lba,
+                    pte.nr_sectors,
+                    pte.ptype.to_byte()
+                )
+                .unwrap();
+                if pte.status == EntryStatus::Bootable {
+                    write!(out, ", bootable").unwrap();
+                }
+                writeln!(out).unwrap();
+            }
+        }
+        // There is no table to describe.
+        PartitionTable::Superfloppy(_) => {}
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2 GiB disk.
+    const NR_BLOCKS: u64 = 4 * 1024 * 1024;
+
+    fn build(script: &str) -> Result<PartitionTable, ScriptError> {
+        Script::parse(script)?.build(
+            NR_BLOCKS,
+            &IdSource::Seeded(String::from("test")),
+            Layout::default(),
+        )
+    }
+
+    /// Dumps the table built from `script`, and checks that loading the dump gives it back.
+    fn round_trip(script: &str) -> String {
+        let dumped = dump(&build(script).unwrap(), "disk.img", NR_BLOCKS);
+        let reloaded = dump(&build(&dumped).unwrap(), "disk.img", NR_BLOCKS);
+        assert_eq!(reloaded, dumped);
+
+        dumped
+    }
+
+    fn partition_lines(dumped: &str) -> Vec<&str> {
+        dumped
+            .lines()
+            .filter(|line| line.starts_with("disk.img"))
+            .collect()
+    }
+
+    #[test]
+    fn gpt_script_round_trip() {
+        let dumped = round_trip(
+            r#"label: gpt
+label-id: 5D7333B4-77B3-417C-A1A5-001BB35B7961
+device: disk.img
+
+disk.img1 : size=100M, type=U, name="EFI \"system\", boot", attrs="RequiredPartition GUID:60,63"
+disk.img3 : start=411648, size=1G, uuid=0FC63DAF-8483-4772-8E79-3D69D8477DE4
+size=+, type=S
+"#,
+        );
+
+        assert!(dumped.starts_with(
+            "label: gpt\nlabel-id: 5D7333B4-77B3-417C-A1A5-001BB35B7961\ndevice: disk.img\n"
+        ));
+        assert!(dumped.contains(&format!("last-lba: {}\n", NR_BLOCKS - 34)));
+
+        let lines = partition_lines(&dumped);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("disk.img1 : start=        2048, size=      204800, "));
+        assert!(lines[0].contains(&format!("type={}", GPT_PTYPE_EFI_SYSTEM)));
+        assert!(lines[0]
+            .ends_with(r#", name="EFI \"system\", boot", attrs="RequiredPartition GUID:60,63""#));
+        assert!(lines[1].starts_with("disk.img3 : start=      411648, size=     2097152, "));
+        assert!(lines[1].contains("uuid=0FC63DAF-8483-4772-8E79-3D69D8477DE4"));
+        // Without a start, the partition follows the previous one, aligned to 1 MiB.
+        let start = (411648 + 2097152u64).next_multiple_of(ALIGNMENT);
+        assert!(lines[2].starts_with(&format!(
+            "disk.img4 : start={:>12}, size={:>12}, type={}",
+            start,
+            NR_BLOCKS - 33 - start,
+            GPT_PTYPE_LINUX_SWAP
+        )));
+    }
+
+    #[test]
+    fn dos_script_round_trip() {
+        let dumped = round_trip(
+            "label: dos
+label-id: 0x12345678
+
+size=50M, type=ef, bootable
+start=204800, size=100M, type=S
+size=+
+",
+        );
+
+        assert!(dumped.starts_with("label: dos\nlabel-id: 0x12345678\n"));
+        assert_eq!(
+            partition_lines(&dumped),
+            [
+                "disk.img1 : start=        2048, size=      102400, type=ef, bootable",
+                "disk.img2 : start=      204800, size=      204800, type=82",
+                &format!(
+                    "disk.img3 : start=      409600, size={:>12}, type=83",
+                    NR_BLOCKS - 409600
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_partitions_are_refused() {
+        let result = build(
+            "label: gpt
+start=2048, size=100M
+start=4096, size=10M
+",
+        );
+
+        assert!(matches!(result, Err(ScriptError::Overlap(2, 1))));
+    }
+
+    #[test]
+    fn partitions_outside_the_disk_are_refused() {
+        for (script, number) in [
+            ("label: gpt\nsize=4G\n", 1),
+            ("label: gpt\nstart=2048, size=1M\nstart=10, size=1M\n", 2),
+            (
+                &format!("label: gpt\nstart={}, size=1M\n", NR_BLOCKS - 100),
+                1,
+            ),
+            ("label: dos\nsize=10M\nsize=0\n", 2),
+            (&format!("label: dos\nstart={}, size=2\n", NR_BLOCKS - 1), 1),
+        ] {
+            assert!(
+                matches!(build(script), Err(ScriptError::OutOfRange(n)) if n == number),
+                "{}",
+                script
+            );
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Runs arbitrary bytes through the read paths as a disk image, shared by the malformed sample
+//! test and the `parse_image` fuzz target.
+
+use fisic::{
+    fs::{
+        files::find_files, probe::probe_partition, signatures::find_filesystem_signatures,
+        size::filesystem_size,
+    },
+    image::{BlockDevice, Image, MemoryDevice},
+    pt::{
+        backup::TableBackup,
+        json::JsonListing,
+        read_partition_table,
+        regions::{raw_data_regions, table_regions},
+        sfdisk, PartitionTable,
+    },
+};
+
+const BLOCK_SIZE: u64 = 512;
+
+/// Runs the read-only paths on `image`, which must not panic whatever it holds.
+pub fn exercise_image(image: &Image<impl BlockDevice>) {
+    let nr_blocks = image.len() / BLOCK_SIZE;
+
+    let regions = table_regions(image);
+    raw_data_regions(image, &regions);
+    find_filesystem_signatures(image, 0, image.len());
+    probe_partition(image, 0, nr_blocks);
+    find_files(
+        image,
+        0,
+        image.len(),
+        &[&["etc", "fstab"], &["*", "*", "*"]],
+    );
+
+    let pt = read_partition_table(image);
+    if let Some(pt) = &pt {
+        sfdisk::dump(pt, "sample", nr_blocks);
+    }
+    serde_json::to_string(&JsonListing::new(pt, "sample", image)).unwrap();
+}
+
+pub fn exercise(data: &[u8]) {
+    let image = Image::new(MemoryDevice::new(data.to_vec()));
+    exercise_image(&image);
+    filesystem_size(data);
+
+    // Rewriting a table read from the image must stay within it too.
+    let mut copy = Image::new(MemoryDevice::new(data.to_vec()));
+    match read_partition_table(&copy) {
+        Some(PartitionTable::GPT(mut gpt)) => {
+            gpt.sort_partitions();
+            let _ = gpt.write_tables(&mut copy);
+        }
+        Some(PartitionTable::MBR(mut mbr)) => {
+            mbr.sort_entries();
+            let _ = mbr.write(&mut copy);
+        }
+        _ => {}
+    }
+
+    if let Ok(backup) = TableBackup::read(&image) {
+        let bytes = backup.to_bytes();
+        let _ = TableBackup::from_bytes(&bytes).map(|b| b.restore(&mut copy, true));
+    }
+
+    // The sample itself, taken as a backup file.
+    let _ = TableBackup::from_bytes(data).map(|b| b.restore(&mut copy, true));
+}
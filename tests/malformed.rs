@@ -0,0 +1,121 @@
+//! Runs every sample in `tests/corpus` through the read paths, which must reject malformed
+//! images with errors rather than panic. The same samples seed the `parse_image` fuzz target.
+
+use std::{fs, path::PathBuf};
+
+use fisic::{
+    image::{Image, ImageError, MemoryDevice, Qcow2Device},
+    pt::{
+        gpt::{GPTError, GPT},
+        read_partition_table_verbose,
+    },
+};
+
+mod common;
+
+/// Checks how reading the GPT of sample `name` turned out, for the samples with a GPT header.
+fn gpt_as_expected(name: &str, result: &Result<GPT, GPTError>) -> Option<bool> {
+    Some(match name {
+        // Only the primary header is read, so a bad backup does not hide the table.
+        "gpt-valid.img" | "gpt-backup-lba-beyond-image.img" => result.is_ok(),
+        // The header is cut short, so there is no GPT to speak of.
+        "gpt-truncated-header.img" => matches!(result, Err(GPTError::NoSignature)),
+        // The entries overwrite the protective MBR.
+        "gpt-entries-lba-zero.img" => matches!(result, Err(GPTError::NoProtectiveMBR)),
+        "gpt-partition-past-usable.img" | "gpt-partition-reversed.img" => {
+            matches!(result, Err(GPTError::InvalidPartition(1)))
+        }
+        "backup-entries-count-max.img"
+        | "gpt-entries-count-max.img"
+        | "gpt-truncated-entries.img" => matches!(result, Err(GPTError::EntriesOutOfRange)),
+        "backup-entry-size-zero.img"
+        | "gpt-backup-lba-max.img"
+        | "gpt-entries-lba-max.img"
+        | "gpt-entry-size-odd.img"
+        | "gpt-entry-size-zero.img"
+        | "gpt-header-size-max.img"
+        | "gpt-tiny-image.img"
+        | "gpt-usable-range-max.img"
+        | "gpt-usable-range-reversed.img" => matches!(result, Err(GPTError::InvalidHeader)),
+        _ => return None,
+    })
+}
+
+fn samples() -> Vec<PathBuf> {
+    let mut samples: Vec<_> = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/corpus"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    samples.sort();
+
+    assert!(!samples.is_empty());
+    samples
+}
+
+#[test]
+fn malformed_samples() {
+    for path in samples() {
+        let data = fs::read(&path).unwrap();
+        let result = std::panic::catch_unwind(|| common::exercise(&data));
+
+        assert!(result.is_ok(), "{} panicked", path.display());
+    }
+}
+
+#[test]
+fn malformed_gpts_are_refused() {
+    for path in samples() {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let image = Image::new(MemoryDevice::new(fs::read(&path).unwrap()));
+        let result = GPT::read(&image);
+
+        let Some(as_expected) = gpt_as_expected(name, &result) else {
+            assert!(!name.starts_with("gpt-"), "{}: no expectation", name);
+            continue;
+        };
+        assert!(as_expected, "{}: unexpected {:?}", name, result);
+
+        // A header that is present but unusable is reported rather than silently ignored.
+        let reported = read_partition_table_verbose(&image).1;
+        match result {
+            Ok(_) | Err(GPTError::NoSignature) => assert!(reported.is_none(), "{}", name),
+            Err(_) => assert!(reported.is_some(), "{}", name),
+        }
+    }
+}
+
+#[test]
+fn corrupt_qcow2_samples() {
+    for path in samples() {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        if !name.starts_with("qcow2-") {
+            continue;
+        }
+
+        let result = std::panic::catch_unwind(|| {
+            let image = Image::new(Qcow2Device::open_read_only(&path).ok()?);
+            common::exercise_image(&image);
+
+            Some(image.get_bytes(0, image.len() as usize).map(|_| ()))
+        });
+        let Ok(read) = result else {
+            panic!("{} panicked", name);
+        };
+
+        // Headers that cannot be trusted are refused when opening, anything else when the
+        // corrupt part is read.
+        let refused_on_open = matches!(
+            name,
+            "qcow2-l1-beyond-file.img" | "qcow2-size-max.img" | "qcow2-truncated-header.img"
+        );
+        match read {
+            None => assert!(refused_on_open, "{}: refused on open", name),
+            Some(read) => assert!(
+                !refused_on_open && matches!(read, Err(ImageError::Io(_))),
+                "{}: unexpected {:?}",
+                name,
+                read
+            ),
+        }
+    }
+}